@@ -0,0 +1,5 @@
+// mbedTLS APIs used to generate and check the certificate of the settings server
+#include "mbedtls/bignum.h"
+#include "mbedtls/ecp.h"
+#include "mbedtls/pk.h"
+#include "mbedtls/x509_crt.h"
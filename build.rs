@@ -1,5 +1,9 @@
 // Necessary because of this issue: https://github.com/rust-lang/cargo/issues/9641
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Host builds of the library do not link against ESP-IDF
+    if std::env::var("CARGO_CFG_TARGET_OS")? != "espidf" {
+        return Ok(());
+    }
     embuild::build::CfgArgs::output_propagated("ESP_IDF")?;
     embuild::build::LinkArgs::output_propagated("ESP_IDF")?;
     Ok(())
@@ -1,6 +1,7 @@
+use core::ops::RangeInclusive;
 use embedded_graphics::{
     prelude::*,
-    primitives::{Arc, PrimitiveStyle},
+    primitives::{Arc, Line, PrimitiveStyle, Rectangle},
 };
 
 pub struct Loader<C: PixelColor> {
@@ -40,3 +41,111 @@ impl<C: PixelColor> Drawable for Loader<C> {
         self.arc.into_styled(self.style).draw(target)
     }
 }
+
+pub struct ProgressBar<C: PixelColor> {
+    bounds: Rectangle,
+    color: C,
+    progress: f32,
+}
+
+impl<C: PixelColor> ProgressBar<C> {
+    pub fn new(bounds: Rectangle, color: C) -> Self {
+        Self {
+            bounds,
+            color,
+            progress: 0.0,
+        }
+    }
+
+    /// Sets the filled fraction of the bar, clamped to `0.0..=1.0`.
+    pub fn set_progress(&mut self, progress: f32) {
+        self.progress = progress.clamp(0.0, 1.0);
+    }
+}
+
+impl<C: PixelColor> Drawable for ProgressBar<C> {
+    type Color = C;
+    type Output = ();
+
+    fn draw<D>(&self, target: &mut D) -> Result<Self::Output, D::Error>
+    where
+        D: DrawTarget<Color = Self::Color>,
+    {
+        self.bounds
+            .into_styled(PrimitiveStyle::with_stroke(self.color, 1))
+            .draw(target)?;
+
+        let inner = self.bounds.offset(-2);
+        let width = (inner.size.width as f32 * self.progress) as u32;
+        Rectangle::new(inner.top_left, Size::new(width, inner.size.height))
+            .into_styled(PrimitiveStyle::with_fill(self.color))
+            .draw(target)
+    }
+}
+
+/// Line chart of a series, one pixel per value with the newest one at the
+/// right edge. Missing values leave a gap.
+pub struct Sparkline<C: PixelColor> {
+    bounds: Rectangle,
+    color: C,
+    range: RangeInclusive<i32>,
+    values: Vec<Option<i32>>,
+}
+
+impl<C: PixelColor> Sparkline<C> {
+    pub fn new(bounds: Rectangle, color: C, range: RangeInclusive<i32>) -> Self {
+        Self {
+            bounds,
+            color,
+            range,
+            values: Vec::new(),
+        }
+    }
+
+    /// Sets the series, only the newest values that fit the width are kept.
+    pub fn set_values(&mut self, values: impl IntoIterator<Item = Option<i32>>) {
+        self.values = values.into_iter().collect();
+        let width = self.bounds.size.width as usize;
+        if self.values.len() > width {
+            self.values.drain(..self.values.len() - width);
+        }
+    }
+
+    fn point(&self, index: usize, value: i32) -> Point {
+        let (min, max) = (*self.range.start(), *self.range.end());
+        let height = self.bounds.size.height as i32 - 1;
+        let y = (value.clamp(min, max) - min) * height / (max - min).max(1);
+        let right = self.bounds.top_left.x + self.bounds.size.width as i32 - 1;
+        let bottom = self.bounds.top_left.y + height;
+        Point::new(right - (self.values.len() - 1 - index) as i32, bottom - y)
+    }
+}
+
+impl<C: PixelColor> Drawable for Sparkline<C> {
+    type Color = C;
+    type Output = ();
+
+    fn draw<D>(&self, target: &mut D) -> Result<Self::Output, D::Error>
+    where
+        D: DrawTarget<Color = Self::Color>,
+    {
+        let style = PrimitiveStyle::with_stroke(self.color, 1);
+        for (index, value) in self.values.iter().enumerate() {
+            let Some(value) = *value else {
+                continue;
+            };
+            let point = self.point(index, value);
+            match index
+                .checked_sub(1)
+                .and_then(|previous| self.values[previous])
+            {
+                Some(previous) => Line::new(self.point(index - 1, previous), point)
+                    .into_styled(style)
+                    .draw(target)?,
+                // Isolated values show up as a dot
+                None => Pixel(point, self.color).draw(target)?,
+            }
+        }
+        Ok(())
+    }
+}
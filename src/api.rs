@@ -0,0 +1,235 @@
+//! Data transfer objects of the versioned REST API (`/api/v1`)
+//!
+//! The settings resources are shared with the console and live in
+//! [`soft_ap::api::settings`], merging them into the settings is done here.
+
+pub use soft_ap::api::settings::{
+    Address, ApSettings, ClientSettings, FromInfo, IpSettings, Mac, RoamingSettings,
+};
+
+use crate::{
+    convert,
+    syslog::SyslogConfig,
+    wifi::{self, default_ap_config, InvalidSettings, Roaming, WifiInfo},
+};
+use core::ffi::CStr;
+use embedded_svc::ipv4;
+use esp_idf_svc::ota::EspOta;
+use esp_idf_sys::{self as sys, EspError};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+pub const PREFIX: &str = "/api/v1";
+
+/// OpenAPI description of the endpoints below
+pub const OPENAPI: &str = include_str!("../api/openapi.json");
+
+/// A part of the WiFi settings that can be read and replaced on its own
+pub trait Resource: FromInfo + Serialize + DeserializeOwned {
+    /// Merges the resource into `info`, leaving all other settings untouched.
+    fn apply(self, info: WifiInfo) -> Result<WifiInfo, EspError>;
+}
+
+impl Resource for ClientSettings {
+    fn apply(self, info: WifiInfo) -> Result<WifiInfo, EspError> {
+        let password = self.password.unwrap_or_else(|| {
+            info.sta_config
+                .as_ref()
+                .map(|config| config.password.clone())
+                .unwrap_or_default()
+        });
+        let sta_config = if self.ssid.is_empty() {
+            None
+        } else {
+            Some(wifi::client_config(
+                self.ssid,
+                password,
+                self.bssid.map(|Mac(mac)| mac),
+                self.hidden,
+                self.auth_method,
+            )?)
+        };
+        Ok(WifiInfo {
+            sta_hidden: self.hidden && sta_config.is_some(),
+            sta_config,
+            ..info
+        })
+    }
+}
+
+impl Resource for ApSettings {
+    fn apply(self, info: WifiInfo) -> Result<WifiInfo, EspError> {
+        let password = self.password.unwrap_or_else(|| {
+            info.ap_config
+                .clone()
+                .unwrap_or_else(default_ap_config)
+                .password
+        });
+        let ap_config = (!self.ssid.is_empty())
+            .then(|| wifi::ap_config(self.ssid, password, self.hidden, self.channel));
+        Ok(WifiInfo {
+            ap_config,
+            ap_mode: self.mode.into(),
+            country: self.country,
+            ..info
+        })
+    }
+}
+
+impl Resource for IpSettings {
+    fn apply(self, info: WifiInfo) -> Result<WifiInfo, EspError> {
+        let ip_info = ipv4::IpInfo {
+            ip: self.ip.into(),
+            subnet: ipv4::Subnet {
+                gateway: self.gateway.into(),
+                mask: ipv4::Mask(self.prefix_len),
+            },
+            dns: self.dns.map(Into::into),
+            secondary_dns: self.secondary_dns.map(Into::into),
+        };
+        Ok(WifiInfo { ip_info, ..info })
+    }
+}
+
+impl Resource for RoamingSettings {
+    fn apply(self, info: WifiInfo) -> Result<WifiInfo, EspError> {
+        let roaming = Roaming {
+            strongest: self.strongest,
+            monitor: self.monitor,
+            threshold: self.threshold,
+        };
+        Ok(WifiInfo { roaming, ..info })
+    }
+}
+
+/// All settings of a device in one document, used to copy them to other
+/// units
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfigFile {
+    pub version: u32,
+    pub client: ClientSettings,
+    pub ap: ApSettings,
+    pub ip: IpSettings,
+    #[serde(default)]
+    pub syslog: SyslogConfig,
+    /// Left out by older firmware, the roaming settings are kept then
+    #[serde(default)]
+    pub roaming: Option<RoamingSettings>,
+}
+
+impl ConfigFile {
+    /// Version of the file format written by this firmware
+    pub const VERSION: u32 = 1;
+
+    /// Collects the current settings, the passwords only if `secrets` is set.
+    pub fn export(info: &WifiInfo, syslog: SyslogConfig, secrets: bool) -> Self {
+        let mut client = ClientSettings::from_info(info);
+        let mut ap = ApSettings::from_info(info);
+        if secrets {
+            client.password = info
+                .sta_config
+                .as_ref()
+                .map(|config| config.password.clone());
+            ap.password = Some(
+                info.ap_config
+                    .clone()
+                    .unwrap_or_else(default_ap_config)
+                    .password,
+            );
+        }
+        Self {
+            version: Self::VERSION,
+            client,
+            ap,
+            ip: IpSettings::from_info(info),
+            syslog,
+            roaming: Some(RoamingSettings::from_info(info)),
+        }
+    }
+
+    /// Merges the file into `info`. Passwords left out of the file, e.g. in
+    /// a redacted export, are kept.
+    pub fn apply(self, info: WifiInfo) -> Result<(WifiInfo, SyslogConfig), EspError> {
+        let info = self.client.apply(info)?;
+        let info = self.ap.apply(info)?;
+        let info = self.ip.apply(info)?;
+        let info = match self.roaming {
+            Some(roaming) => roaming.apply(info)?,
+            None => info,
+        };
+        Ok((info, self.syslog))
+    }
+
+    pub fn validate(&self) -> Result<(), InvalidSettings> {
+        if self.version == 0 || self.version > Self::VERSION {
+            return Err(InvalidSettings {
+                field: "version",
+                message: "unsupported file version",
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Read-only facts about the device and its firmware
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceInfo {
+    pub firmware_version: &'static str,
+    pub idf_version: String,
+    pub partition: String,
+    pub mac: String,
+    pub cores: u8,
+    pub https: bool,
+    pub uptime_secs: u64,
+}
+
+impl DeviceInfo {
+    pub fn current(https: bool) -> Result<Self, EspError> {
+        let mut mac = [0u8; 6];
+        sys::esp!(unsafe {
+            sys::esp_read_mac(mac.as_mut_ptr(), sys::esp_mac_type_t_ESP_MAC_WIFI_STA)
+        })?;
+        let mut chip_info = sys::esp_chip_info_t::default();
+        unsafe { sys::esp_chip_info(&mut chip_info) };
+
+        Ok(Self {
+            firmware_version: env!("CARGO_PKG_VERSION"),
+            idf_version: unsafe { CStr::from_ptr(sys::esp_get_idf_version()) }
+                .to_string_lossy()
+                .into_owned(),
+            partition: EspOta::new()?.get_running_slot()?.label.to_string(),
+            mac: convert::format_mac(&mac),
+            cores: chip_info.cores,
+            https,
+            uptime_secs: (unsafe { sys::esp_timer_get_time() } / 1_000_000) as u64,
+        })
+    }
+}
+
+/// Body of every error response of the API
+#[derive(Debug, Serialize)]
+pub struct ErrorBody<'a> {
+    pub error: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<&'a str>,
+}
+
+/// Applies a JSON merge patch (RFC 7396) to `target`.
+pub fn merge_patch(target: &mut serde_json::Value, patch: serde_json::Value) {
+    let serde_json::Value::Object(patch) = patch else {
+        *target = patch;
+        return;
+    };
+    if !target.is_object() {
+        *target = serde_json::Value::Object(Default::default());
+    }
+    let serde_json::Value::Object(target) = target else {
+        unreachable!()
+    };
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(&key);
+        } else {
+            merge_patch(target.entry(key).or_insert(serde_json::Value::Null), value);
+        }
+    }
+}
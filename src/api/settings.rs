@@ -0,0 +1,228 @@
+//! Parts of the WiFi settings as the REST API and the console exchange them
+//!
+//! These are deliberately independent of the portal templates and of the
+//! persisted [`WifiInfo`], so either can change without breaking API clients.
+
+use crate::{
+    convert,
+    wifi::{
+        ap_mode::ApMode,
+        country::Country,
+        info::{default_ap_config, WifiInfo},
+        roaming::Roaming,
+        security::ClientAuth,
+    },
+};
+use embedded_svc::ipv4;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::net::Ipv4Addr;
+
+/// Reads a part of the WiFi settings on its own
+pub trait FromInfo {
+    fn from_info(info: &WifiInfo) -> Self;
+}
+
+/// Connection to an existing network
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientSettings {
+    /// An empty SSID disables the client
+    pub ssid: heapless::String<32>,
+    /// Only sent in configuration exports with secrets, the stored password
+    /// is kept if it is left out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<heapless::String<64>>,
+    #[serde(default, skip_deserializing)]
+    pub password_set: bool,
+    /// The network does not broadcast its name
+    #[serde(default)]
+    pub hidden: bool,
+    #[serde(default)]
+    pub auth_method: ClientAuth,
+    /// Only connect through this access point
+    #[serde(default)]
+    pub bssid: Option<Mac>,
+}
+
+impl FromInfo for ClientSettings {
+    fn from_info(info: &WifiInfo) -> Self {
+        let auth_method = info.sta_config.as_ref().map_or(ClientAuth::Auto, |config| {
+            ClientAuth::from_method(config.auth_method)
+        });
+        let config = info.sta_config.clone().unwrap_or_default();
+        Self {
+            ssid: config.ssid,
+            password: None,
+            password_set: !config.password.is_empty(),
+            hidden: info.sta_hidden,
+            auth_method,
+            bssid: config.bssid.map(Mac),
+        }
+    }
+}
+
+/// The access point opened by the device itself
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApSettings {
+    /// An empty SSID restores the default access point
+    pub ssid: heapless::String<32>,
+    /// Only sent in configuration exports with secrets, the stored password
+    /// is kept if it is left out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<heapless::String<64>>,
+    #[serde(default, skip_deserializing)]
+    pub password_set: bool,
+    pub hidden: bool,
+    pub channel: u8,
+    pub mode: AccessPointMode,
+    /// Regulatory domain of the device, it limits the allowed channels
+    #[serde(default)]
+    pub country: Country,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessPointMode {
+    NoConnOnBoot,
+    Always,
+    Never,
+}
+
+impl From<ApMode> for AccessPointMode {
+    fn from(mode: ApMode) -> Self {
+        match mode {
+            ApMode::NoConnOnBoot => Self::NoConnOnBoot,
+            ApMode::Always => Self::Always,
+            ApMode::Never => Self::Never,
+        }
+    }
+}
+
+impl From<AccessPointMode> for ApMode {
+    fn from(mode: AccessPointMode) -> Self {
+        match mode {
+            AccessPointMode::NoConnOnBoot => Self::NoConnOnBoot,
+            AccessPointMode::Always => Self::Always,
+            AccessPointMode::Never => Self::Never,
+        }
+    }
+}
+
+impl FromInfo for ApSettings {
+    fn from_info(info: &WifiInfo) -> Self {
+        let config = info.ap_config.clone().unwrap_or_else(default_ap_config);
+        Self {
+            ssid: config.ssid,
+            password: None,
+            password_set: !config.password.is_empty(),
+            hidden: config.ssid_hidden,
+            channel: config.channel,
+            mode: info.ap_mode.into(),
+            country: info.country,
+        }
+    }
+}
+
+/// Static address of the client interface, `0.0.0.0` leaves it to DHCP
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IpSettings {
+    pub ip: Address,
+    pub gateway: Address,
+    pub prefix_len: u8,
+    #[serde(default)]
+    pub dns: Option<Address>,
+    #[serde(default)]
+    pub secondary_dns: Option<Address>,
+}
+
+/// An IPv4 address in dotted notation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Address(pub Ipv4Addr);
+
+impl From<ipv4::Ipv4Addr> for Address {
+    fn from(address: ipv4::Ipv4Addr) -> Self {
+        Self(address.octets().into())
+    }
+}
+
+impl From<Address> for ipv4::Ipv4Addr {
+    fn from(Address(address): Address) -> Self {
+        address.octets().into()
+    }
+}
+
+impl Serialize for Address {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map(Self)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// A MAC address like `AA:BB:CC:DD:EE:FF`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mac(pub [u8; 6]);
+
+impl Serialize for Mac {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        convert::serialize_mac(&self.0, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Mac {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        convert::parse_mac(&String::deserialize(deserializer)?)
+            .map(Self)
+            .ok_or_else(|| {
+                serde::de::Error::custom("expected a MAC address like AA:BB:CC:DD:EE:FF")
+            })
+    }
+}
+
+impl FromInfo for IpSettings {
+    fn from_info(info: &WifiInfo) -> Self {
+        let ip_info = &info.ip_info;
+        Self {
+            ip: ip_info.ip.into(),
+            gateway: ip_info.subnet.gateway.into(),
+            prefix_len: ip_info.subnet.mask.0,
+            dns: ip_info.dns.map(Address::from),
+            secondary_dns: ip_info.secondary_dns.map(Address::from),
+        }
+    }
+}
+
+/// Choice of the access point in networks with several ones, e.g. mesh
+/// systems
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoamingSettings {
+    /// Join the access point with the strongest signal instead of the first
+    /// one found
+    pub strongest: bool,
+    /// Move to a better access point while the signal is weaker than
+    /// `threshold`
+    pub monitor: bool,
+    /// Signal strength in dBm
+    pub threshold: i8,
+}
+
+impl FromInfo for RoamingSettings {
+    fn from_info(info: &WifiInfo) -> Self {
+        let Roaming {
+            strongest,
+            monitor,
+            threshold,
+        } = info.roaming;
+        Self {
+            strongest,
+            monitor,
+            threshold,
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use core::time::Duration;
+use embedded_svc::storage::RawStorage;
+use esp_idf_svc::nvs;
+use esp_idf_sys as sys;
+use hmac::Hmac;
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, Mutex};
+
+/// The only user of the settings portal
+pub const USERNAME: &str = "admin";
+/// Minimum length of the admin password
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+const ROUNDS: u32 = 4096;
+
+/// [`Auth`] shared between the HTTP handlers and the main loop
+pub type SharedAuth = Arc<Mutex<Auth<nvs::NvsDefault>>>;
+
+/// Outcome of an authentication attempt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Granted,
+    Denied,
+    /// Too many failed attempts, retry after the given time
+    LockedOut(Duration),
+    /// No admin password has been set yet
+    NoPassword,
+}
+
+/// Checks HTTP Basic credentials against the admin password, which is stored
+/// as salted PBKDF2-SHA256 hash in NVS.
+pub struct Auth<P: nvs::NvsPartitionId> {
+    nvs: nvs::EspNvs<P>,
+    failures: u32,
+    locked_until: Duration,
+    /// Digest of the last accepted `Authorization` header, so that the key
+    /// derivation does not have to run for every request
+    accepted: Option<[u8; HASH_LEN]>,
+}
+
+impl<P: nvs::NvsPartitionId> Auth<P> {
+    const PASSWORD_KEY: &str = "password";
+    /// Failed attempts before further attempts are rejected for a while.
+    ///
+    /// They are counted for all clients together, so someone guessing locks
+    /// the admin out as well, for at most [`Self::MAX_LOCKOUT`]. A limit per
+    /// address would not bound the guessing: clients of the own access point
+    /// pick any address they like. The serial console and the factory reset
+    /// keep working during a lockout.
+    const MAX_FAILURES: u32 = 5;
+    const LOCKOUT: Duration = Duration::from_secs(30);
+    const MAX_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+
+    pub fn new(nvs_partition: nvs::EspNvsPartition<P>) -> Result<Self, sys::EspError> {
+        let nvs = nvs::EspNvs::new(nvs_partition, "auth", true)?;
+        Ok(Self {
+            nvs,
+            failures: 0,
+            locked_until: Duration::ZERO,
+            accepted: None,
+        })
+    }
+
+    pub fn has_password(&self) -> Result<bool, sys::EspError> {
+        Ok(self.nvs.len(Self::PASSWORD_KEY)?.is_some())
+    }
+
+    /// Stores a new admin password, or removes it if `None`.
+    pub fn set_password(&mut self, password: Option<&str>) -> Result<(), sys::EspError> {
+        self.accepted = None;
+        let Some(password) = password else {
+            self.nvs.remove(Self::PASSWORD_KEY)?;
+            return Ok(());
+        };
+
+        let mut stored = [0; SALT_LEN + HASH_LEN];
+        let (salt, hash) = stored.split_at_mut(SALT_LEN);
+        unsafe { sys::esp_fill_random(salt.as_mut_ptr().cast(), salt.len() as _) };
+        hash.copy_from_slice(&derive(password, salt));
+        self.nvs.set_raw(Self::PASSWORD_KEY, &stored)?;
+        Ok(())
+    }
+
+    /// Checks the value of an `Authorization` header.
+    pub fn authenticate(&mut self, authorization: Option<&str>) -> Result<Access, sys::EspError> {
+        let mut buf = [0; SALT_LEN + HASH_LEN];
+        let Some(stored) = self.nvs.get_raw(Self::PASSWORD_KEY, &mut buf)? else {
+            return Ok(Access::NoPassword);
+        };
+        // Browsers only send credentials after being challenged once, so a
+        // missing header does not count as failed attempt
+        let Some(authorization) = authorization else {
+            return Ok(Access::Denied);
+        };
+
+        let now = uptime();
+        if now < self.locked_until {
+            return Ok(Access::LockedOut(self.locked_until - now));
+        }
+
+        let digest: [u8; HASH_LEN] = Sha256::digest(authorization).into();
+        if self.accepted == Some(digest) {
+            return Ok(Access::Granted);
+        }
+
+        let (salt, hash) = stored.split_at(SALT_LEN);
+        let granted = basic_password(authorization).map_or(false, |password| {
+            constant_time_eq(&derive(&password, salt), hash)
+        });
+        if granted {
+            self.failures = 0;
+            self.accepted = Some(digest);
+            Ok(Access::Granted)
+        } else {
+            self.failures += 1;
+            if self.failures >= Self::MAX_FAILURES {
+                let doublings = (self.failures - Self::MAX_FAILURES).min(5);
+                let lockout = Self::LOCKOUT * 2u32.pow(doublings);
+                self.locked_until = now + lockout.min(Self::MAX_LOCKOUT);
+            }
+            Ok(Access::Denied)
+        }
+    }
+}
+
+/// Returns the password of HTTP Basic credentials for [`USERNAME`].
+fn basic_password(authorization: &str) -> Option<String> {
+    let encoded = authorization.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(BASE64.decode(encoded.trim()).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    (username == USERNAME).then(|| password.to_owned())
+}
+
+fn derive(password: &str, salt: &[u8]) -> [u8; HASH_LEN] {
+    let mut hash = [0; HASH_LEN];
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(password.as_bytes(), salt, ROUNDS, &mut hash);
+    hash
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn uptime() -> Duration {
+    Duration::from_micros(unsafe { sys::esp_timer_get_time() } as u64)
+}
@@ -0,0 +1,116 @@
+use crate::wifi::{ApMode, WifiInfo};
+use core::{fmt, time::Duration};
+use embedded_svc::storage::RawStorage;
+use esp_idf_svc::nvs;
+use esp_idf_sys as sys;
+use std::sync::{Arc, Mutex};
+
+/// Uptime after which a boot counts as successful
+pub const STABLE_AFTER: Duration = Duration::from_secs(30);
+
+/// Counts consecutive boots that crashed before reaching a stable state, so
+/// that a bad stored configuration can not lock the device in a reboot loop.
+/// Power cycles and restarts on purpose, e.g. after an update, start the count
+/// over.
+pub struct BootCounter<P: nvs::NvsPartitionId> {
+    nvs: nvs::EspNvs<P>,
+}
+
+impl<P: nvs::NvsPartitionId> BootCounter<P> {
+    const ATTEMPTS_KEY: &str = "attempts";
+    /// Number of failed boots after which the stored configuration is skipped
+    const MAX_ATTEMPTS: u8 = 3;
+
+    pub fn new(nvs_partition: nvs::EspNvsPartition<P>) -> Result<Self, sys::EspError> {
+        let nvs = nvs::EspNvs::new(nvs_partition, "boot", true)?;
+        Ok(Self { nvs })
+    }
+
+    /// Records a new boot attempt and returns the recovery reason if too many
+    /// attempts in a row failed.
+    pub fn begin(&mut self) -> Result<Option<Recovery>, sys::EspError> {
+        let mut buf = [0u8; 1];
+        let previous = if crashed() {
+            self.nvs
+                .get_raw(Self::ATTEMPTS_KEY, &mut buf)?
+                .map_or(0, |attempts| attempts[0])
+        } else {
+            0
+        };
+        let attempts = previous.saturating_add(1);
+        self.nvs.set_raw(Self::ATTEMPTS_KEY, &[attempts])?;
+
+        Ok((attempts > Self::MAX_ATTEMPTS).then(|| Recovery {
+            failed_boots: attempts - 1,
+            reset_reason: reset_reason(),
+        }))
+    }
+
+    /// Marks the current boot as successful, resetting the counter.
+    pub fn mark_successful(&mut self) -> Result<(), sys::EspError> {
+        self.nvs.set_raw(Self::ATTEMPTS_KEY, &[0])?;
+        Ok(())
+    }
+}
+
+/// [`Recovery`] shared between the handlers and the main loop, which clears it
+/// once new settings are committed
+pub type SharedRecovery = Arc<Mutex<Option<Recovery>>>;
+
+/// Why the device started in recovery mode
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct Recovery {
+    pub failed_boots: u8,
+    pub reset_reason: &'static str,
+}
+
+impl Recovery {
+    /// The configuration used instead of the stored one: the default access
+    /// point with the settings portal.
+    pub fn wifi_info() -> WifiInfo {
+        WifiInfo {
+            ap_mode: ApMode::Always,
+            ..Default::default()
+        }
+    }
+}
+
+impl fmt::Display for Recovery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} failed boots, last reset: {}",
+            self.failed_boots, self.reset_reason
+        )
+    }
+}
+
+/// Whether the last reset was caused by a panic, a watchdog or a brownout.
+/// An error returned from `main` ends in a panic as well.
+fn crashed() -> bool {
+    matches!(
+        unsafe { sys::esp_reset_reason() },
+        sys::esp_reset_reason_t_ESP_RST_PANIC
+            | sys::esp_reset_reason_t_ESP_RST_INT_WDT
+            | sys::esp_reset_reason_t_ESP_RST_TASK_WDT
+            | sys::esp_reset_reason_t_ESP_RST_WDT
+            | sys::esp_reset_reason_t_ESP_RST_BROWNOUT
+    )
+}
+
+/// Returns the reason for the last reset of the chip.
+pub fn reset_reason() -> &'static str {
+    match unsafe { sys::esp_reset_reason() } {
+        sys::esp_reset_reason_t_ESP_RST_POWERON => "power on",
+        sys::esp_reset_reason_t_ESP_RST_EXT => "external pin",
+        sys::esp_reset_reason_t_ESP_RST_SW => "software",
+        sys::esp_reset_reason_t_ESP_RST_PANIC => "panic",
+        sys::esp_reset_reason_t_ESP_RST_INT_WDT => "interrupt watchdog",
+        sys::esp_reset_reason_t_ESP_RST_TASK_WDT => "task watchdog",
+        sys::esp_reset_reason_t_ESP_RST_WDT => "watchdog",
+        sys::esp_reset_reason_t_ESP_RST_DEEPSLEEP => "deep sleep",
+        sys::esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+        sys::esp_reset_reason_t_ESP_RST_SDIO => "sdio",
+        _ => "unknown",
+    }
+}
@@ -0,0 +1,177 @@
+use core::time::Duration;
+
+/// A classified button gesture
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Press {
+    Single,
+    Double,
+    Long,
+}
+
+/// Debounces a raw button level and turns it into [`Press`] gestures.
+///
+/// The button is fed with the raw level and a monotonic timestamp on every
+/// tick, so the classification does not depend on any hardware and can be
+/// driven by synthetic input.
+#[derive(Default)]
+pub struct Button {
+    raw: bool,
+    raw_since: Duration,
+    pressed: bool,
+    pressed_at: Option<Duration>,
+    released_at: Option<Duration>,
+    suppressed: bool,
+}
+
+impl Button {
+    const DEBOUNCE: Duration = Duration::from_millis(30);
+    const DOUBLE_WINDOW: Duration = Duration::from_millis(300);
+    const LONG: Duration = Duration::from_millis(800);
+
+    /// Returns for how long the debounced button has been held down.
+    pub fn held_for(&self, now: Duration) -> Option<Duration> {
+        self.pressed_at
+            .map(|pressed_at| now.saturating_sub(pressed_at))
+    }
+
+    /// Swallows the gesture of the current press, e.g. because the caller
+    /// already acted on it while the button was being held.
+    pub fn suppress(&mut self) {
+        self.suppressed = self.pressed;
+    }
+
+    /// Feeds the current raw level (`true` = pressed) and returns a gesture
+    /// once it is recognized.
+    pub fn update(&mut self, raw: bool, now: Duration) -> Option<Press> {
+        if raw != self.raw {
+            self.raw = raw;
+            self.raw_since = now;
+        }
+
+        if self.raw != self.pressed && now.saturating_sub(self.raw_since) >= Self::DEBOUNCE {
+            self.pressed = self.raw;
+            if self.pressed {
+                self.pressed_at = Some(now);
+            } else if let Some(pressed_at) = self.pressed_at.take() {
+                if core::mem::take(&mut self.suppressed) {
+                    self.released_at = None;
+                } else if now.saturating_sub(pressed_at) >= Self::LONG {
+                    self.released_at = None;
+                    return Some(Press::Long);
+                } else if self.released_at.take().is_some() {
+                    return Some(Press::Double);
+                } else {
+                    self.released_at = Some(now);
+                }
+            }
+        }
+
+        match self.released_at {
+            Some(released_at)
+                if !self.pressed && now.saturating_sub(released_at) >= Self::DOUBLE_WINDOW =>
+            {
+                self.released_at = None;
+                Some(Press::Single)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Ticks `button` every 10 ms until `until`, with the raw level switching
+    /// at the given milliseconds, and returns the gestures with their time.
+    fn run(button: &mut Button, levels: &[(u64, bool)], until: u64) -> Vec<(u64, Press)> {
+        (0..=until)
+            .step_by(10)
+            .filter_map(|ms| {
+                let level = levels.iter().rev().find(|(at, _)| *at <= ms);
+                let raw = matches!(level, Some((_, true)));
+                button
+                    .update(raw, Duration::from_millis(ms))
+                    .map(|press| (ms, press))
+            })
+            .collect()
+    }
+
+    fn press(from: u64, to: u64) -> [(u64, bool); 2] {
+        [(from, true), (to, false)]
+    }
+
+    #[test]
+    fn bounce() {
+        let glitches = [(100, true), (110, false), (120, true), (130, false)];
+        assert_eq!(run(&mut Button::default(), &glitches, 1000), []);
+
+        let bouncy = [
+            (100, true),
+            (110, false),
+            (120, true),
+            (300, false),
+            (310, true),
+            (320, false),
+        ];
+        assert_eq!(
+            run(&mut Button::default(), &bouncy, 1000),
+            [(650, Press::Single)]
+        );
+    }
+
+    #[test]
+    fn single() {
+        // Reported once no second press follows within the double window
+        assert_eq!(
+            run(&mut Button::default(), &press(100, 300), 1000),
+            [(630, Press::Single)]
+        );
+        let [a, b] = press(100, 200);
+        let [c, d] = press(600, 700);
+        assert_eq!(
+            run(&mut Button::default(), &[a, b, c, d], 1500),
+            [(530, Press::Single), (1030, Press::Single)]
+        );
+    }
+
+    #[test]
+    fn double() {
+        let [a, b] = press(100, 200);
+        let [c, d] = press(300, 400);
+        assert_eq!(
+            run(&mut Button::default(), &[a, b, c, d], 1500),
+            [(430, Press::Double)]
+        );
+    }
+
+    #[test]
+    fn long() {
+        assert_eq!(
+            run(&mut Button::default(), &press(100, 900), 1500),
+            [(930, Press::Long)]
+        );
+        // Just below the threshold
+        assert_eq!(
+            run(&mut Button::default(), &press(100, 890), 1500),
+            [(1220, Press::Single)]
+        );
+    }
+
+    #[test]
+    fn hold() {
+        let mut button = Button::default();
+        assert_eq!(run(&mut button, &[(100, true)], 2100), []);
+        assert_eq!(
+            button.held_for(Duration::from_millis(2100)),
+            Some(Duration::from_millis(1970))
+        );
+
+        // The caller acted on the hold, the release is no gesture
+        button.suppress();
+        assert_eq!(button.update(false, Duration::from_millis(2110)), None);
+        assert_eq!(button.update(false, Duration::from_millis(2140)), None);
+        assert_eq!(button.held_for(Duration::from_millis(2140)), None);
+        assert_eq!(button.update(false, Duration::from_millis(3000)), None);
+    }
+}
@@ -0,0 +1,256 @@
+//! Line based shell on the serial console, for provisioning and diagnostics
+//! when the portal can not be reached
+//!
+//! Changes go through the same validation and [`CMD_QUEUE`] as the ones made
+//! over HTTP, so they are applied on trial as well.
+
+use crate::{
+    api::{ApSettings, ClientSettings, FromInfo, IpSettings, Resource, RoamingSettings},
+    boot::SharedRecovery,
+    diagnostics::Status,
+    fleet,
+    wifi::{self, storage::WifiStorage, WifiInfo},
+    wps, Command, CMD_QUEUE,
+};
+use core::{ffi::CStr, time::Duration};
+use esp_idf_svc::nvs::NvsDefault;
+use esp_idf_sys as sys;
+use soft_ap::console::command::{dispatch, parse, Action, ConsoleCommand, HELP};
+use std::{
+    io::{ErrorKind, Read, Write},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+/// Longer lines are discarded
+const MAX_LINE_LEN: usize = 256;
+
+/// Starts the thread that reads commands from the console.
+pub fn spawn(
+    wifi_storage: Arc<Mutex<WifiStorage<NvsDefault>>>,
+    recovery: SharedRecovery,
+) -> std::io::Result<()> {
+    thread::Builder::new()
+        .name("console".into())
+        .stack_size(8 * 1024)
+        .spawn(move || run(&wifi_storage, &recovery))?;
+    Ok(())
+}
+
+fn run(wifi_storage: &Mutex<WifiStorage<NvsDefault>>, recovery: &SharedRecovery) {
+    let mut line = Vec::new();
+    let mut buf = [0; 64];
+    let mut overflow = false;
+    loop {
+        // The console is not blocking unless a UART driver is installed
+        let len = match std::io::stdin().read(&mut buf) {
+            Ok(len) => len,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => 0,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => {
+                log::error!("reading the console failed, shell stopped: {err}");
+                return;
+            }
+        };
+        if len == 0 {
+            thread::sleep(Duration::from_millis(50));
+            continue;
+        }
+
+        let mut stdout = std::io::stdout();
+        for byte in &buf[..len] {
+            match byte {
+                b'\r' | b'\n' => {
+                    let _ = stdout.write_all(b"\n");
+                    if overflow {
+                        println!("line too long");
+                    } else {
+                        handle_line(&String::from_utf8_lossy(&line), wifi_storage, recovery);
+                    }
+                    line.clear();
+                    overflow = false;
+                }
+                // Backspace and delete
+                0x08 | 0x7f => {
+                    if line.pop().is_some() {
+                        let _ = stdout.write_all(b"\x08 \x08");
+                    }
+                }
+                byte => {
+                    if line.len() < MAX_LINE_LEN {
+                        line.push(*byte);
+                        // Terminals do not echo locally
+                        let _ = stdout.write_all(&[*byte]);
+                    } else {
+                        overflow = true;
+                    }
+                }
+            }
+        }
+        let _ = stdout.flush();
+    }
+}
+
+fn handle_line(
+    line: &str,
+    wifi_storage: &Mutex<WifiStorage<NvsDefault>>,
+    recovery: &SharedRecovery,
+) {
+    match parse(line) {
+        Ok(Some(command)) => {
+            if let Err(err) = execute(command, wifi_storage, recovery) {
+                println!("error: {err}");
+            }
+        }
+        Ok(None) => {}
+        Err(message) => println!("{message}"),
+    }
+}
+
+fn execute(
+    command: ConsoleCommand,
+    wifi_storage: &Mutex<WifiStorage<NvsDefault>>,
+    recovery: &SharedRecovery,
+) -> anyhow::Result<()> {
+    let info = wifi_storage.lock().unwrap().get_info()?;
+    let action = match dispatch(command, &info) {
+        Ok(action) => action,
+        Err(message) => {
+            println!("{message}");
+            return Ok(());
+        }
+    };
+    match action {
+        Action::Help => println!("{HELP}"),
+        Action::Status => {
+            let recovery = *recovery.lock().unwrap();
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&Status::current(recovery)?)?
+            );
+        }
+        Action::ShowWifi => {
+            println!(
+                "client: {}",
+                serde_json::to_string(&ClientSettings::from_info(&info))?
+            );
+            println!(
+                "ap: {}",
+                serde_json::to_string(&ApSettings::from_info(&info))?
+            );
+            println!(
+                "ip: {}",
+                serde_json::to_string(&IpSettings::from_info(&info))?
+            );
+            println!(
+                "roaming: {}",
+                serde_json::to_string(&RoamingSettings::from_info(&info))?
+            );
+        }
+        Action::ScanWifi => {
+            let mut aps = wifi::scan_aps()?;
+            aps.sort_by_key(|ap| core::cmp::Reverse(ap.signal_strength));
+            println!("RSSI  CH  SSID");
+            for ap in aps {
+                println!("{:>4}  {:>2}  {}", ap.signal_strength, ap.channel, ap.ssid);
+            }
+        }
+        Action::StartWps => {
+            enqueue(Command::StartWps)?;
+            println!(
+                "press the WPS button of the router within {:?}",
+                wps::TIMEOUT
+            );
+        }
+        Action::ConfirmWifi => enqueue(Command::ConfirmWifi)?,
+        Action::Client(settings) => submit(settings.apply(info)?)?,
+        Action::Ap(settings) => submit(settings.apply(info)?)?,
+        Action::Ip(settings) => submit(settings.apply(info)?)?,
+        Action::Roaming(settings) => submit(settings.apply(info)?)?,
+        Action::FleetStatus => {
+            println!("{}", serde_json::to_string_pretty(&fleet::status())?);
+        }
+        Action::FleetPush => match fleet::push(info.sta_config.as_ref()) {
+            Ok(()) => println!(
+                "offering the client settings to nearby devices for {:?}",
+                fleet::PUSH_DURATION
+            ),
+            Err(err) => println!("rejected, {err}"),
+        },
+        Action::FleetKey(passphrase) => {
+            let key = match passphrase.as_deref().map(fleet::key_from_passphrase) {
+                None => None,
+                Some(Ok(key)) => Some(key),
+                Some(Err(invalid)) => {
+                    println!("rejected, {invalid}");
+                    return Ok(());
+                }
+            };
+            wifi_storage.lock().unwrap().set_fleet_key(key.as_ref())?;
+            fleet::set_key(key);
+        }
+        Action::NvsDump => nvs_dump(),
+        Action::Reboot => enqueue(Command::Reboot)?,
+        Action::FactoryReset => enqueue(Command::FactoryReset)?,
+    }
+    Ok(())
+}
+
+/// Validates the settings and hands them to the main loop.
+fn submit(info: WifiInfo) -> anyhow::Result<()> {
+    if let Err(invalid) = info.validate() {
+        println!("rejected, {invalid}");
+        return Ok(());
+    }
+    enqueue(Command::UpdateWifi(info))?;
+    println!("applying, use `wifi confirm` to keep the settings if the device can not connect");
+    Ok(())
+}
+
+fn enqueue(command: Command) -> anyhow::Result<()> {
+    CMD_QUEUE
+        .enqueue(command)
+        .map_err(|_| anyhow::anyhow!("busy, try again"))
+}
+
+/// Lists the keys of the default NVS partition. Values are left out, they
+/// include passwords and the TLS key.
+fn nvs_dump() {
+    let mut iterator = unsafe {
+        sys::nvs_entry_find(
+            b"nvs\0".as_ptr().cast(),
+            core::ptr::null(),
+            sys::nvs_type_t_NVS_TYPE_ANY,
+        )
+    };
+    while !iterator.is_null() {
+        let mut info = sys::nvs_entry_info_t::default();
+        unsafe { sys::nvs_entry_info(iterator, &mut info) };
+        let namespace = unsafe { CStr::from_ptr(info.namespace_name.as_ptr()) };
+        let key = unsafe { CStr::from_ptr(info.key.as_ptr()) };
+        println!(
+            "{:<16} {:<16} {}",
+            namespace.to_string_lossy(),
+            key.to_string_lossy(),
+            nvs_type_name(info.type_)
+        );
+        // Frees the iterator once the end is reached
+        iterator = unsafe { sys::nvs_entry_next(iterator) };
+    }
+}
+
+fn nvs_type_name(nvs_type: sys::nvs_type_t) -> &'static str {
+    match nvs_type {
+        sys::nvs_type_t_NVS_TYPE_U8 => "u8",
+        sys::nvs_type_t_NVS_TYPE_I8 => "i8",
+        sys::nvs_type_t_NVS_TYPE_U16 => "u16",
+        sys::nvs_type_t_NVS_TYPE_I16 => "i16",
+        sys::nvs_type_t_NVS_TYPE_U32 => "u32",
+        sys::nvs_type_t_NVS_TYPE_I32 => "i32",
+        sys::nvs_type_t_NVS_TYPE_U64 => "u64",
+        sys::nvs_type_t_NVS_TYPE_I64 => "i64",
+        sys::nvs_type_t_NVS_TYPE_STR => "string",
+        sys::nvs_type_t_NVS_TYPE_BLOB => "blob",
+        _ => "unknown",
+    }
+}
@@ -0,0 +1,640 @@
+//! Commands of the serial console, their syntax and what they do
+
+use crate::{
+    api::settings::{
+        Address, ApSettings, ClientSettings, FromInfo, IpSettings, Mac, RoamingSettings,
+    },
+    convert,
+    wifi::{ap_mode::ApMode, country::Country, info::WifiInfo, security::ClientAuth},
+};
+use std::net::Ipv4Addr;
+
+pub const HELP: &str = "\
+commands:
+  help                                  show this text
+  status                                health of the device
+  wifi show                             current settings
+  wifi set <ssid> [<password>]          connect to a network, quote values with spaces
+  wifi scan                             list networks in range
+  wifi wps                              connect by pressing the WPS button of the router
+  wifi confirm                          keep settings that are on trial
+  wifi bssid <mac|any>                  only connect through one access point of the network
+  wifi roaming <off|strongest|monitor> [<dbm>]
+                                        join the strongest access point, and move to a
+                                        better one below the signal threshold
+  ap mode <no_conn_on_boot|always|never>
+  ap country <code>                     regulatory domain, e.g. DE, US, JP or 01 (world)
+  ip static <ip>/<prefix> <gateway> [<dns>]
+  ip dhcp
+  fleet status                          followers of the last push
+  fleet push                            send the client settings to nearby devices
+  fleet key <passphrase|clear>          set or remove the shared fleet key
+  nvs dump                              list stored keys (without values)
+  reset [factory]                       reboot, or wipe the settings and reboot";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConsoleCommand {
+    Help,
+    Status,
+    WifiShow,
+    WifiSet {
+        ssid: heapless::String<32>,
+        password: heapless::String<64>,
+    },
+    WifiScan,
+    WifiWps,
+    WifiConfirm,
+    /// `None` connects through any access point of the network
+    WifiBssid(Option<[u8; 6]>),
+    WifiRoaming {
+        strongest: bool,
+        monitor: bool,
+        /// The stored threshold is kept if it is left out
+        threshold: Option<i8>,
+    },
+    ApMode(ApMode),
+    ApCountry(Country),
+    IpStatic {
+        ip: Ipv4Addr,
+        prefix_len: u8,
+        gateway: Ipv4Addr,
+        dns: Option<Ipv4Addr>,
+    },
+    IpDhcp,
+    FleetStatus,
+    FleetPush,
+    /// Passphrase of the fleet key, `None` removes it
+    FleetKey(Option<String>),
+    NvsDump,
+    Reset {
+        factory: bool,
+    },
+}
+
+/// Parses a line typed on the console, `Ok(None)` for an empty line.
+pub fn parse(line: &str) -> Result<Option<ConsoleCommand>, &'static str> {
+    let words = split_words(line)?;
+    let words: Vec<&str> = words.iter().map(String::as_str).collect();
+    let command = match words.as_slice() {
+        [] => return Ok(None),
+        ["help"] => ConsoleCommand::Help,
+        ["status"] => ConsoleCommand::Status,
+        ["wifi", "show"] => ConsoleCommand::WifiShow,
+        ["wifi", "set", ssid, password @ ..] if password.len() <= 1 => ConsoleCommand::WifiSet {
+            ssid: bounded(ssid, "SSID is too long")?,
+            password: bounded(password.first().unwrap_or(&""), "password is too long")?,
+        },
+        ["wifi", "scan"] => ConsoleCommand::WifiScan,
+        ["wifi", "wps"] => ConsoleCommand::WifiWps,
+        ["wifi", "confirm"] => ConsoleCommand::WifiConfirm,
+        ["wifi", "bssid", "any"] => ConsoleCommand::WifiBssid(None),
+        ["wifi", "bssid", mac] => {
+            ConsoleCommand::WifiBssid(Some(convert::parse_mac(mac).ok_or("invalid MAC address")?))
+        }
+        ["wifi", "roaming", "off"] => ConsoleCommand::WifiRoaming {
+            strongest: false,
+            monitor: false,
+            threshold: None,
+        },
+        ["wifi", "roaming", "strongest"] => ConsoleCommand::WifiRoaming {
+            strongest: true,
+            monitor: false,
+            threshold: None,
+        },
+        ["wifi", "roaming", "monitor", threshold @ ..] if threshold.len() <= 1 => {
+            ConsoleCommand::WifiRoaming {
+                strongest: true,
+                monitor: true,
+                threshold: threshold
+                    .first()
+                    .map(|threshold| threshold.parse())
+                    .transpose()
+                    .map_err(|_| "invalid threshold")?,
+            }
+        }
+        ["ap", "mode", mode] => ConsoleCommand::ApMode(match *mode {
+            "no_conn_on_boot" => ApMode::NoConnOnBoot,
+            "always" => ApMode::Always,
+            "never" => ApMode::Never,
+            _ => return Err("mode must be no_conn_on_boot, always or never"),
+        }),
+        ["ap", "country", code] => {
+            ConsoleCommand::ApCountry(Country::from_code(code).ok_or("unsupported country")?)
+        }
+        ["ip", "static", address, gateway, dns @ ..] if dns.len() <= 1 => {
+            let (ip, prefix_len) = address
+                .split_once('/')
+                .ok_or("address must be given as <ip>/<prefix>")?;
+            ConsoleCommand::IpStatic {
+                ip: ip.parse().map_err(|_| "invalid IP address")?,
+                prefix_len: prefix_len
+                    .parse()
+                    .ok()
+                    .filter(|len| *len <= 32)
+                    .ok_or("prefix must be between 0 and 32")?,
+                gateway: gateway.parse().map_err(|_| "invalid gateway")?,
+                dns: dns
+                    .first()
+                    .map(|dns| dns.parse())
+                    .transpose()
+                    .map_err(|_| "invalid DNS server")?,
+            }
+        }
+        ["ip", "dhcp"] => ConsoleCommand::IpDhcp,
+        ["fleet", "status"] => ConsoleCommand::FleetStatus,
+        ["fleet", "push"] => ConsoleCommand::FleetPush,
+        ["fleet", "key", "clear"] => ConsoleCommand::FleetKey(None),
+        ["fleet", "key", passphrase] => ConsoleCommand::FleetKey(Some((*passphrase).to_owned())),
+        ["nvs", "dump"] => ConsoleCommand::NvsDump,
+        ["reset"] => ConsoleCommand::Reset { factory: false },
+        ["reset", "factory"] => ConsoleCommand::Reset { factory: true },
+        _ => return Err("unknown command, try `help`"),
+    };
+    Ok(Some(command))
+}
+
+/// What the firmware does for a command, see [`dispatch`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Help,
+    Status,
+    ShowWifi,
+    ScanWifi,
+    StartWps,
+    ConfirmWifi,
+    /// Settings merged into the stored ones and applied on trial, like the
+    /// ones changed through the REST API
+    Client(ClientSettings),
+    Ap(ApSettings),
+    Ip(IpSettings),
+    Roaming(RoamingSettings),
+    FleetStatus,
+    FleetPush,
+    /// Passphrase of the fleet key, `None` removes it
+    FleetKey(Option<String>),
+    NvsDump,
+    Reboot,
+    FactoryReset,
+}
+
+/// Decides what `command` does with the stored settings `info`. Errors are
+/// meant for the user.
+pub fn dispatch(command: ConsoleCommand, info: &WifiInfo) -> Result<Action, &'static str> {
+    Ok(match command {
+        ConsoleCommand::Help => Action::Help,
+        ConsoleCommand::Status => Action::Status,
+        ConsoleCommand::WifiShow => Action::ShowWifi,
+        ConsoleCommand::WifiSet { ssid, password } => Action::Client(ClientSettings {
+            ssid,
+            password: Some(password),
+            password_set: false,
+            hidden: false,
+            auth_method: ClientAuth::Auto,
+            bssid: None,
+        }),
+        ConsoleCommand::WifiScan => Action::ScanWifi,
+        ConsoleCommand::WifiWps => Action::StartWps,
+        ConsoleCommand::WifiConfirm => Action::ConfirmWifi,
+        ConsoleCommand::WifiBssid(bssid) => {
+            if info.sta_config.is_none() {
+                return Err("no network configured, use `wifi set` first");
+            }
+            Action::Client(ClientSettings {
+                bssid: bssid.map(Mac),
+                ..ClientSettings::from_info(info)
+            })
+        }
+        ConsoleCommand::WifiRoaming {
+            strongest,
+            monitor,
+            threshold,
+        } => Action::Roaming(RoamingSettings {
+            strongest,
+            monitor,
+            threshold: threshold.unwrap_or(info.roaming.threshold),
+        }),
+        ConsoleCommand::ApMode(mode) => Action::Ap(ApSettings {
+            mode: mode.into(),
+            ..ApSettings::from_info(info)
+        }),
+        ConsoleCommand::ApCountry(country) => Action::Ap(ApSettings {
+            country,
+            ..ApSettings::from_info(info)
+        }),
+        ConsoleCommand::IpStatic {
+            ip,
+            prefix_len,
+            gateway,
+            dns,
+        } => Action::Ip(IpSettings {
+            ip: Address(ip),
+            gateway: Address(gateway),
+            prefix_len,
+            dns: dns.map(Address),
+            secondary_dns: None,
+        }),
+        ConsoleCommand::IpDhcp => Action::Ip(IpSettings {
+            ip: Address(Ipv4Addr::UNSPECIFIED),
+            gateway: Address(Ipv4Addr::UNSPECIFIED),
+            prefix_len: 24,
+            dns: None,
+            secondary_dns: None,
+        }),
+        ConsoleCommand::FleetStatus => Action::FleetStatus,
+        ConsoleCommand::FleetPush => Action::FleetPush,
+        ConsoleCommand::FleetKey(passphrase) => Action::FleetKey(passphrase),
+        ConsoleCommand::NvsDump => Action::NvsDump,
+        ConsoleCommand::Reset { factory: false } => Action::Reboot,
+        ConsoleCommand::Reset { factory: true } => Action::FactoryReset,
+    })
+}
+
+fn bounded<const N: usize>(
+    value: &str,
+    message: &'static str,
+) -> Result<heapless::String<N>, &'static str> {
+    let mut bounded = heapless::String::new();
+    bounded.push_str(value).map_err(|_| message)?;
+    Ok(bounded)
+}
+
+/// Splits at whitespace, double quotes group words and `\` escapes the next
+/// character.
+fn split_words(line: &str) -> Result<Vec<String>, &'static str> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quoted = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                word.get_or_insert_with(String::new);
+            }
+            '\\' => {
+                let escaped = chars.next().ok_or("line ends with `\\`")?;
+                word.get_or_insert_with(String::new).push(escaped);
+            }
+            c if c.is_whitespace() && !quoted => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quoted {
+        return Err("missing closing quote");
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::settings::AccessPointMode;
+    use embedded_svc::wifi::{AuthMethod, ClientConfiguration};
+
+    fn command(line: &str) -> ConsoleCommand {
+        parse(line).unwrap().unwrap()
+    }
+
+    fn ip(value: &str) -> Ipv4Addr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn words() {
+        assert_eq!(
+            split_words("  wifi\tshow  "),
+            Ok(vec!["wifi".into(), "show".into()])
+        );
+        assert_eq!(
+            split_words(r#"set "My Home" pass\ word"#),
+            Ok(vec!["set".into(), "My Home".into(), "pass word".into()])
+        );
+        assert_eq!(
+            split_words(r#"a"b c"d \"e\\ """#),
+            Ok(vec!["ab cd".into(), "\"e\\".into(), "".into()])
+        );
+        assert_eq!(split_words(r#"set "My Home"#), Err("missing closing quote"));
+        assert_eq!(split_words("set home\\"), Err("line ends with `\\`"));
+    }
+
+    #[test]
+    fn simple_commands() {
+        assert_eq!(parse(""), Ok(None));
+        assert_eq!(parse("   "), Ok(None));
+        for (line, expected) in [
+            ("help", ConsoleCommand::Help),
+            ("status", ConsoleCommand::Status),
+            ("wifi show", ConsoleCommand::WifiShow),
+            ("wifi scan", ConsoleCommand::WifiScan),
+            ("wifi wps", ConsoleCommand::WifiWps),
+            ("wifi confirm", ConsoleCommand::WifiConfirm),
+            ("ip dhcp", ConsoleCommand::IpDhcp),
+            ("fleet status", ConsoleCommand::FleetStatus),
+            ("fleet push", ConsoleCommand::FleetPush),
+            ("nvs dump", ConsoleCommand::NvsDump),
+            ("reset", ConsoleCommand::Reset { factory: false }),
+            ("reset factory", ConsoleCommand::Reset { factory: true }),
+        ] {
+            assert_eq!(command(line), expected, "{line}");
+        }
+        for line in ["wifi", "status now", "reset hard", "\"help\" x"] {
+            assert_eq!(parse(line), Err("unknown command, try `help`"), "{line}");
+        }
+    }
+
+    #[test]
+    fn wifi_set() {
+        assert_eq!(
+            command(r#"wifi set "My Home" "correct horse""#),
+            ConsoleCommand::WifiSet {
+                ssid: "My Home".into(),
+                password: "correct horse".into(),
+            }
+        );
+        assert_eq!(
+            command("wifi set Cafe"),
+            ConsoleCommand::WifiSet {
+                ssid: "Cafe".into(),
+                password: "".into(),
+            }
+        );
+        assert_eq!(
+            parse("wifi set My Home password"),
+            Err("unknown command, try `help`")
+        );
+        assert_eq!(
+            parse(&format!("wifi set {}", "s".repeat(33))),
+            Err("SSID is too long")
+        );
+        assert_eq!(
+            parse(&format!("wifi set home {}", "p".repeat(65))),
+            Err("password is too long")
+        );
+    }
+
+    #[test]
+    fn wifi_bssid() {
+        assert_eq!(command("wifi bssid any"), ConsoleCommand::WifiBssid(None));
+        assert_eq!(
+            command("wifi bssid 00:1a:2b:c3:d4:ff"),
+            ConsoleCommand::WifiBssid(Some([0x00, 0x1a, 0x2b, 0xc3, 0xd4, 0xff]))
+        );
+        assert_eq!(parse("wifi bssid 00:1a:2b"), Err("invalid MAC address"));
+    }
+
+    #[test]
+    fn wifi_roaming() {
+        assert_eq!(
+            command("wifi roaming off"),
+            ConsoleCommand::WifiRoaming {
+                strongest: false,
+                monitor: false,
+                threshold: None,
+            }
+        );
+        assert_eq!(
+            command("wifi roaming strongest"),
+            ConsoleCommand::WifiRoaming {
+                strongest: true,
+                monitor: false,
+                threshold: None,
+            }
+        );
+        assert_eq!(
+            command("wifi roaming monitor"),
+            ConsoleCommand::WifiRoaming {
+                strongest: true,
+                monitor: true,
+                threshold: None,
+            }
+        );
+        assert_eq!(
+            command("wifi roaming monitor -70"),
+            ConsoleCommand::WifiRoaming {
+                strongest: true,
+                monitor: true,
+                threshold: Some(-70),
+            }
+        );
+        assert_eq!(parse("wifi roaming monitor weak"), Err("invalid threshold"));
+    }
+
+    #[test]
+    fn ap() {
+        for (mode, expected) in [
+            ("no_conn_on_boot", ApMode::NoConnOnBoot),
+            ("always", ApMode::Always),
+            ("never", ApMode::Never),
+        ] {
+            assert_eq!(
+                command(&format!("ap mode {mode}")),
+                ConsoleCommand::ApMode(expected)
+            );
+        }
+        assert_eq!(
+            parse("ap mode sometimes"),
+            Err("mode must be no_conn_on_boot, always or never")
+        );
+        assert_eq!(
+            command("ap country de"),
+            ConsoleCommand::ApCountry(Country::from_code("DE").unwrap())
+        );
+        assert_eq!(
+            command("ap country 01"),
+            ConsoleCommand::ApCountry(Country::WORLD)
+        );
+        assert_eq!(parse("ap country XX"), Err("unsupported country"));
+    }
+
+    #[test]
+    fn ip_static() {
+        assert_eq!(
+            command("ip static 192.168.1.20/24 192.168.1.1"),
+            ConsoleCommand::IpStatic {
+                ip: ip("192.168.1.20"),
+                prefix_len: 24,
+                gateway: ip("192.168.1.1"),
+                dns: None,
+            }
+        );
+        assert_eq!(
+            command("ip static 10.0.0.2/8 10.0.0.1 1.1.1.1"),
+            ConsoleCommand::IpStatic {
+                ip: ip("10.0.0.2"),
+                prefix_len: 8,
+                gateway: ip("10.0.0.1"),
+                dns: Some(ip("1.1.1.1")),
+            }
+        );
+        for (line, error) in [
+            (
+                "ip static 192.168.1.20 192.168.1.1",
+                "address must be given as <ip>/<prefix>",
+            ),
+            (
+                "ip static 192.168.1.20/33 192.168.1.1",
+                "prefix must be between 0 and 32",
+            ),
+            (
+                "ip static 192.168.1.20/x 192.168.1.1",
+                "prefix must be between 0 and 32",
+            ),
+            ("ip static 192.168.1/24 192.168.1.1", "invalid IP address"),
+            ("ip static 192.168.1.20/24 router", "invalid gateway"),
+            (
+                "ip static 192.168.1.20/24 192.168.1.1 dns",
+                "invalid DNS server",
+            ),
+            (
+                "ip static 192.168.1.20/24 192.168.1.1 1.1.1.1 8.8.8.8",
+                "unknown command, try `help`",
+            ),
+        ] {
+            assert_eq!(parse(line), Err(error), "{line}");
+        }
+    }
+
+    #[test]
+    fn fleet_key() {
+        assert_eq!(command("fleet key clear"), ConsoleCommand::FleetKey(None));
+        assert_eq!(
+            command(r#"fleet key "clear as day""#),
+            ConsoleCommand::FleetKey(Some("clear as day".into()))
+        );
+        assert_eq!(
+            command("fleet key secret"),
+            ConsoleCommand::FleetKey(Some("secret".into()))
+        );
+    }
+
+    /// Stored settings with a configured network
+    fn connected() -> WifiInfo {
+        WifiInfo {
+            sta_config: Some(ClientConfiguration {
+                ssid: "Home".into(),
+                password: "correct horse".into(),
+                auth_method: AuthMethod::WPA2Personal,
+                channel: Some(6),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn action(line: &str, info: &WifiInfo) -> Result<Action, &'static str> {
+        dispatch(command(line), info)
+    }
+
+    #[test]
+    fn dispatch_wifi_set() {
+        assert_eq!(
+            action(r#"wifi set Cafe "fresh beans""#, &connected()),
+            Ok(Action::Client(ClientSettings {
+                ssid: "Cafe".into(),
+                password: Some("fresh beans".into()),
+                password_set: false,
+                hidden: false,
+                auth_method: ClientAuth::Auto,
+                bssid: None,
+            }))
+        );
+    }
+
+    #[test]
+    fn dispatch_wifi_bssid() {
+        assert_eq!(
+            action("wifi bssid 00:1a:2b:c3:d4:ff", &WifiInfo::default()),
+            Err("no network configured, use `wifi set` first")
+        );
+        // Everything else of the stored network is kept
+        assert_eq!(
+            action("wifi bssid 00:1a:2b:c3:d4:ff", &connected()),
+            Ok(Action::Client(ClientSettings {
+                ssid: "Home".into(),
+                password: None,
+                password_set: true,
+                hidden: false,
+                auth_method: ClientAuth::Wpa2,
+                bssid: Some(Mac([0x00, 0x1a, 0x2b, 0xc3, 0xd4, 0xff])),
+            }))
+        );
+    }
+
+    #[test]
+    fn dispatch_wifi_roaming() {
+        let mut info = connected();
+        info.roaming.threshold = -80;
+        assert_eq!(
+            action("wifi roaming monitor", &info),
+            Ok(Action::Roaming(RoamingSettings {
+                strongest: true,
+                monitor: true,
+                threshold: -80,
+            }))
+        );
+        assert_eq!(
+            action("wifi roaming monitor -65", &info),
+            Ok(Action::Roaming(RoamingSettings {
+                strongest: true,
+                monitor: true,
+                threshold: -65,
+            }))
+        );
+    }
+
+    #[test]
+    fn dispatch_ap() {
+        let stored = ApSettings::from_info(&WifiInfo::default());
+        assert_eq!(
+            action("ap mode always", &WifiInfo::default()),
+            Ok(Action::Ap(ApSettings {
+                mode: AccessPointMode::Always,
+                ..stored.clone()
+            }))
+        );
+        assert_eq!(
+            action("ap country JP", &WifiInfo::default()),
+            Ok(Action::Ap(ApSettings {
+                country: Country::from_code("JP").unwrap(),
+                ..stored
+            }))
+        );
+    }
+
+    #[test]
+    fn dispatch_ip() {
+        assert_eq!(
+            action(
+                "ip static 192.168.1.20/24 192.168.1.1 1.1.1.1",
+                &connected()
+            ),
+            Ok(Action::Ip(IpSettings {
+                ip: Address(ip("192.168.1.20")),
+                gateway: Address(ip("192.168.1.1")),
+                prefix_len: 24,
+                dns: Some(Address(ip("1.1.1.1"))),
+                secondary_dns: None,
+            }))
+        );
+        assert_eq!(
+            action("ip dhcp", &connected()),
+            Ok(Action::Ip(IpSettings {
+                ip: Address(Ipv4Addr::UNSPECIFIED),
+                gateway: Address(Ipv4Addr::UNSPECIFIED),
+                prefix_len: 24,
+                dns: None,
+                secondary_dns: None,
+            }))
+        );
+    }
+
+    #[test]
+    fn dispatch_reset() {
+        assert_eq!(action("reset", &connected()), Ok(Action::Reboot));
+        assert_eq!(
+            action("reset factory", &connected()),
+            Ok(Action::FactoryReset)
+        );
+    }
+}
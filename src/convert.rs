@@ -1,9 +1,41 @@
+//! Conversions between the types of ESP-IDF, of `embedded-svc` and of the
+//! API. The ones of ESP-IDF types are only built for the device.
+
+#[cfg(target_os = "espidf")]
 use embedded_svc::{ipv4, wifi};
+#[cfg(target_os = "espidf")]
 use enumset::EnumSet;
+#[cfg(target_os = "espidf")]
 use esp_idf_sys as sys;
+use serde::Serializer;
 
 pub struct Newtype<T>(pub T);
 
+/// Formats a MAC address like `AA:BB:CC:DD:EE:FF`
+pub fn format_mac(mac: &[u8; 6]) -> String {
+    let [a, b, c, d, e, f] = mac;
+    format!("{a:02X}:{b:02X}:{c:02X}:{d:02X}:{e:02X}:{f:02X}")
+}
+
+/// Parses a MAC address like `AA:BB:CC:DD:EE:FF`, in either case
+pub fn parse_mac(value: &str) -> Option<[u8; 6]> {
+    let mut mac = [0; 6];
+    let mut parts = value.split(':');
+    for byte in &mut mac {
+        let part = parts
+            .next()
+            .filter(|part| part.len() == 2 && part.bytes().all(|b| b.is_ascii_hexdigit()))?;
+        *byte = u8::from_str_radix(part, 16).ok()?;
+    }
+    parts.next().is_none().then_some(mac)
+}
+
+/// Serializes a MAC address like [`format_mac`], for `#[serde(serialize_with)]`
+pub fn serialize_mac<S: Serializer>(mac: &[u8; 6], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format_mac(mac))
+}
+
+#[cfg(target_os = "espidf")]
 impl From<Newtype<sys::wifi_ap_record_t>> for wifi::AccessPointInfo {
     fn from(Newtype(record): Newtype<sys::wifi_ap_record_t>) -> Self {
         wifi::AccessPointInfo {
@@ -29,6 +61,7 @@ impl From<Newtype<sys::wifi_ap_record_t>> for wifi::AccessPointInfo {
     }
 }
 
+#[cfg(target_os = "espidf")]
 impl From<Newtype<sys::wifi_auth_mode_t>> for wifi::AuthMethod {
     fn from(Newtype(mode): Newtype<sys::wifi_auth_mode_t>) -> Self {
         match mode {
@@ -46,18 +79,52 @@ impl From<Newtype<sys::wifi_auth_mode_t>> for wifi::AuthMethod {
     }
 }
 
+#[cfg(target_os = "espidf")]
 impl From<ipv4::IpInfo> for Newtype<sys::esp_netif_ip_info_t> {
     fn from(value: ipv4::IpInfo) -> Self {
+        let netmask = u32::MAX
+            .checked_shr(value.subnet.mask.0.into())
+            .unwrap_or(0);
         Self(sys::esp_netif_ip_info_t {
-            ip: sys::esp_ip4_addr_t {
-                addr: value.ip.into(),
-            },
+            ip: Newtype::from(value.ip).0,
             netmask: sys::esp_ip4_addr_t {
-                addr: !(u32::MAX >> u32::from(value.subnet.mask.0)),
-            },
-            gw: sys::esp_ip4_addr_t {
-                addr: value.subnet.gateway.into(),
+                addr: u32::to_be(!netmask),
             },
+            gw: Newtype::from(value.subnet.gateway).0,
         })
     }
 }
+
+#[cfg(target_os = "espidf")]
+impl From<ipv4::Ipv4Addr> for Newtype<sys::esp_ip4_addr_t> {
+    fn from(value: ipv4::Ipv4Addr) -> Self {
+        // lwIP keeps addresses in network byte order
+        Self(sys::esp_ip4_addr_t {
+            addr: u32::to_be(value.into()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mac() {
+        let mac = [0x00, 0x1a, 0x2B, 0xc3, 0xD4, 0xff];
+        assert_eq!(format_mac(&mac), "00:1A:2B:C3:D4:FF");
+        assert_eq!(parse_mac("00:1A:2B:C3:D4:FF"), Some(mac));
+        assert_eq!(parse_mac("00:1a:2b:c3:d4:ff"), Some(mac));
+        for invalid in [
+            "",
+            "00:1A:2B:C3:D4",
+            "00:1A:2B:C3:D4:FF:00",
+            "0:1A:2B:C3:D4:FF",
+            "+0:1A:2B:C3:D4:FF",
+            "00-1A-2B-C3-D4-FF",
+            "00:1A:2B:C3:D4:FG",
+        ] {
+            assert_eq!(parse_mac(invalid), None, "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use crate::{
+    api::Address,
+    boot::{self, Recovery},
+    convert, wifi,
+};
+use core::{
+    ffi::c_void,
+    sync::atomic::{AtomicU32, AtomicU8, Ordering},
+};
+use esp_idf_sys as sys;
+use std::net::Ipv4Addr;
+
+/// Reason of the last station disconnect as reported by the driver, 0 if the
+/// station has not been disconnected since boot
+static LAST_DISCONNECT: AtomicU8 = AtomicU8::new(0);
+/// Station disconnects since boot
+static DISCONNECTS: AtomicU32 = AtomicU32::new(0);
+
+/// Snapshot of the health of the device
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Status {
+    pub firmware_version: &'static str,
+    pub uptime_secs: u64,
+    pub reset_reason: &'static str,
+    pub recovery: Option<Recovery>,
+    pub free_heap: u32,
+    pub min_free_heap: u32,
+    pub wifi_mode: &'static str,
+    /// Regulatory domain the radio is operated in
+    pub country: String,
+    pub station: Option<StationStatus>,
+    pub ip: Option<IpStatus>,
+    pub ap_clients: Vec<ApClient>,
+    pub last_disconnect: Option<Disconnect>,
+}
+
+/// The network the station is connected to
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct StationStatus {
+    pub ssid: String,
+    pub bssid: String,
+    pub channel: u8,
+    pub rssi: i8,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct IpStatus {
+    pub ip: Address,
+    pub gateway: Address,
+    pub netmask: Address,
+    pub dns: Option<Address>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ApClient {
+    pub mac: String,
+    pub ip: Option<Address>,
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct Disconnect {
+    pub code: u8,
+    pub reason: &'static str,
+}
+
+impl Status {
+    pub fn current(recovery: Option<Recovery>) -> Result<Self, sys::EspError> {
+        let station = station_status();
+        Ok(Self {
+            firmware_version: env!("CARGO_PKG_VERSION"),
+            uptime_secs: (unsafe { sys::esp_timer_get_time() } / 1_000_000) as u64,
+            reset_reason: boot::reset_reason(),
+            recovery,
+            free_heap: unsafe { sys::esp_get_free_heap_size() },
+            min_free_heap: unsafe { sys::esp_get_minimum_free_heap_size() },
+            wifi_mode: wifi_mode()?,
+            country: wifi::current_country()?,
+            ip: station.as_ref().and_then(|_| ip_status()),
+            station,
+            ap_clients: ap_clients()?,
+            last_disconnect: last_disconnect(),
+        })
+    }
+}
+
+/// Starts recording why the station lost its connection. The typed events of
+/// `esp-idf-svc` do not carry the reason, hence the raw handler.
+pub fn track_disconnects() -> Result<(), sys::EspError> {
+    sys::esp!(unsafe {
+        sys::esp_event_handler_register(
+            sys::WIFI_EVENT,
+            sys::wifi_event_t_WIFI_EVENT_STA_DISCONNECTED as _,
+            Some(on_disconnect),
+            core::ptr::null_mut(),
+        )
+    })
+}
+
+unsafe extern "C" fn on_disconnect(
+    _: *mut c_void,
+    _: sys::esp_event_base_t,
+    _: i32,
+    event_data: *mut c_void,
+) {
+    let event = &*(event_data as *const sys::wifi_event_sta_disconnected_t);
+    LAST_DISCONNECT.store(event.reason, Ordering::Relaxed);
+    DISCONNECTS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn disconnect_count() -> u32 {
+    DISCONNECTS.load(Ordering::Relaxed)
+}
+
+pub fn last_disconnect() -> Option<Disconnect> {
+    let code = LAST_DISCONNECT.load(Ordering::Relaxed);
+    (code != 0).then(|| Disconnect {
+        code,
+        reason: disconnect_reason(code),
+    })
+}
+
+fn disconnect_reason(code: u8) -> &'static str {
+    match code {
+        2 => "authentication expired",
+        3 => "deauthenticated by the AP",
+        4 => "association expired",
+        5 => "too many stations on the AP",
+        6 | 7 => "not authenticated",
+        8 => "left the network",
+        14 => "message integrity failure",
+        15 => "4-way handshake timeout",
+        16 => "group key update timeout",
+        23 => "802.1X authentication failed",
+        200 => "beacon timeout",
+        201 => "network not found",
+        202 => "authentication failed",
+        203 => "association failed",
+        204 => "handshake timeout",
+        205 => "connection failed",
+        _ => "unspecified",
+    }
+}
+
+fn wifi_mode() -> Result<&'static str, sys::EspError> {
+    let mut mode = sys::wifi_mode_t_WIFI_MODE_NULL;
+    sys::esp!(unsafe { sys::esp_wifi_get_mode(&mut mode) })?;
+    Ok(match mode {
+        sys::wifi_mode_t_WIFI_MODE_STA => "station",
+        sys::wifi_mode_t_WIFI_MODE_AP => "access point",
+        sys::wifi_mode_t_WIFI_MODE_APSTA => "station and access point",
+        _ => "off",
+    })
+}
+
+fn station_status() -> Option<StationStatus> {
+    let mut record = sys::wifi_ap_record_t::default();
+    // Fails if the station is not connected
+    sys::esp!(unsafe { sys::esp_wifi_sta_get_ap_info(&mut record) }).ok()?;
+    let len = record
+        .ssid
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(record.ssid.len());
+    Some(StationStatus {
+        ssid: String::from_utf8_lossy(&record.ssid[..len]).into_owned(),
+        bssid: convert::format_mac(&record.bssid),
+        channel: record.primary,
+        rssi: record.rssi,
+    })
+}
+
+fn ip_status() -> Option<IpStatus> {
+    let netif = unsafe { sys::esp_netif_get_handle_from_ifkey(b"WIFI_STA_DEF\0".as_ptr().cast()) };
+    if netif.is_null() {
+        return None;
+    }
+    let mut ip_info = sys::esp_netif_ip_info_t::default();
+    sys::esp!(unsafe { sys::esp_netif_get_ip_info(netif, &mut ip_info) }).ok()?;
+    let mut dns = sys::esp_netif_dns_info_t::default();
+    let dns = sys::esp!(unsafe {
+        sys::esp_netif_get_dns_info(
+            netif,
+            sys::esp_netif_dns_type_t_ESP_NETIF_DNS_MAIN,
+            &mut dns,
+        )
+    })
+    .ok()
+    .map(|_| address(unsafe { dns.ip.u_addr.ip4.addr }))
+    .filter(|dns| !dns.0.is_unspecified());
+
+    Some(IpStatus {
+        ip: address(ip_info.ip.addr),
+        gateway: address(ip_info.gw.addr),
+        netmask: address(ip_info.netmask.addr),
+        dns,
+    })
+}
+
+fn ap_clients() -> Result<Vec<ApClient>, sys::EspError> {
+    let mut stations = sys::wifi_sta_list_t::default();
+    if sys::esp!(unsafe { sys::esp_wifi_ap_get_sta_list(&mut stations) }).is_err() {
+        // Not running as an access point
+        return Ok(Vec::new());
+    }
+    let mut with_ip = sys::esp_netif_sta_list_t::default();
+    sys::esp!(unsafe { sys::esp_netif_get_sta_list(&stations, &mut with_ip) })?;
+    Ok(with_ip.sta[..with_ip.num as usize]
+        .iter()
+        .map(|station| ApClient {
+            mac: convert::format_mac(&station.mac),
+            // Clients that have not received a DHCP lease yet
+            ip: Some(address(station.ip.addr)).filter(|ip| !ip.0.is_unspecified()),
+        })
+        .collect())
+}
+
+/// lwIP keeps addresses in network byte order
+fn address(addr: u32) -> Address {
+    Address(Ipv4Addr::from(addr.to_le_bytes()))
+}
@@ -0,0 +1,276 @@
+//! Push channel for the portal and fleet tooling (`/api/events`)
+//!
+//! Events are broadcast as JSON text frames to every WebSocket client that
+//! sent the [`CsrfToken`] as its first message. Clients that do not send it
+//! within [`AUTH_TIMEOUT`] are disconnected. Each client has a bounded queue
+//! that is drained by a dedicated sender thread, so a slow client only loses
+//! its own oldest events and never blocks the publisher.
+
+use crate::{auth, fleet::PeerState, http::CsrfToken};
+use core::time::Duration;
+use embedded_svc::ws::{FrameType, Receiver, Sender};
+use esp_idf_svc::http::server::ws::{EspHttpWsConnection, EspHttpWsDetachedSender};
+use esp_idf_sys::{self as sys, EspError};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+};
+
+/// Events kept per client before the oldest ones are dropped
+const QUEUE_LEN: usize = 32;
+/// Authorized clients served at the same time
+const MAX_CLIENTS: usize = 4;
+/// Time a new client has to send the CSRF token
+const AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+static HUB: Mutex<Vec<Client>> = Mutex::new(Vec::new());
+static WAKE: Condvar = Condvar::new();
+/// Number of authorized clients, lets [`publish`] skip all work while there
+/// are none
+static LISTENERS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// A WiFi or IP event of the driver
+    Network {
+        event: String,
+    },
+    ScanDone {
+        networks: usize,
+    },
+    /// Progress of a settings change
+    Apply {
+        state: ApplyState,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    /// Progress of a WPS connection
+    Wps {
+        state: WpsState,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    /// A follower answered the settings pushed over ESP-NOW
+    Fleet {
+        mac: String,
+        state: PeerState,
+    },
+    /// The station moves to a stronger access point of its network
+    Roaming {
+        from: String,
+        to: String,
+        rssi: i8,
+    },
+    Log {
+        level: &'static str,
+        target: String,
+        message: String,
+    },
+    /// The client was too slow and missed `count` events
+    Dropped {
+        count: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApplyState {
+    Trying,
+    Committed,
+    Reverted,
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WpsState {
+    Started,
+    Succeeded,
+    Failed,
+}
+
+struct Client {
+    session: i32,
+    authorized: bool,
+    /// Uptime when the client connected
+    connected: Duration,
+    /// Taken by the sender thread while it is sending
+    sender: Option<EspHttpWsDetachedSender>,
+    queue: VecDeque<Arc<str>>,
+    dropped: usize,
+}
+
+/// Broadcasts `event` to all connected clients.
+pub fn publish(event: Event) {
+    if LISTENERS.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    let Ok(json) = serde_json::to_string(&event) else {
+        return;
+    };
+    let json: Arc<str> = json.into();
+    let mut clients = HUB.lock().unwrap();
+    for client in clients.iter_mut().filter(|client| client.authorized) {
+        if client.queue.len() == QUEUE_LEN {
+            client.queue.pop_front();
+            client.dropped += 1;
+        }
+        client.queue.push_back(json.clone());
+    }
+    WAKE.notify_one();
+}
+
+/// WebSocket handler of `/api/events`
+pub fn handle_ws(ws: &mut EspHttpWsConnection, csrf: &CsrfToken) -> Result<(), EspError> {
+    let session = ws.session();
+    if ws.is_new() {
+        if LISTENERS.load(Ordering::Relaxed) >= MAX_CLIENTS {
+            ws.send(FrameType::Close, &[])?;
+            return Ok(());
+        }
+        let sender = ws.create_detached_sender()?;
+        HUB.lock().unwrap().push(Client {
+            session,
+            authorized: false,
+            connected: uptime(),
+            sender: Some(sender),
+            queue: VecDeque::new(),
+            dropped: 0,
+        });
+        // The sender thread disconnects the client if it does not authorize
+        // in time
+        WAKE.notify_one();
+    } else if ws.is_closed() {
+        remove(session);
+    } else {
+        let (_, len) = ws.recv(&mut [])?;
+        let mut buf = [0; 64];
+        if len > buf.len() {
+            remove(session);
+            ws.send(FrameType::Close, &[])?;
+            return Ok(());
+        }
+        let (frame_type, len) = ws.recv(&mut buf[..len])?;
+        if !matches!(frame_type, FrameType::Text(_)) {
+            return Ok(());
+        }
+        // The first message has to be the CSRF token, which is only handed
+        // out to authenticated users
+        let valid = auth::constant_time_eq(&buf[..len], csrf.as_str().as_bytes());
+        let mut clients = HUB.lock().unwrap();
+        let Some(client) = clients
+            .iter_mut()
+            .find(|client| client.session == session && !client.authorized)
+        else {
+            return Ok(());
+        };
+        // Other clients might have authorized in the meantime
+        if valid && LISTENERS.load(Ordering::Relaxed) < MAX_CLIENTS {
+            client.authorized = true;
+            LISTENERS.fetch_add(1, Ordering::Relaxed);
+        } else {
+            drop(clients);
+            remove(session);
+            ws.send(FrameType::Close, &[])?;
+        }
+    }
+    Ok(())
+}
+
+fn remove(session: i32) {
+    let mut clients = HUB.lock().unwrap();
+    if let Some(index) = clients.iter().position(|client| client.session == session) {
+        if clients.swap_remove(index).authorized {
+            LISTENERS.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Starts the thread that delivers queued events to the clients.
+pub fn spawn_sender() -> std::io::Result<()> {
+    thread::Builder::new()
+        .name("events".into())
+        .stack_size(6 * 1024)
+        .spawn(run_sender)?;
+    Ok(())
+}
+
+fn run_sender() {
+    loop {
+        // Take the sender and the pending events of one client at a time, so
+        // the hub stays unlocked while sending
+        let (session, mut sender, dropped, queue) = {
+            let mut clients = HUB.lock().unwrap();
+            let index = loop {
+                disconnect_expired(&mut clients);
+                if let Some(index) = clients.iter().position(|client| {
+                    client.sender.is_some() && (!client.queue.is_empty() || client.dropped > 0)
+                }) {
+                    break index;
+                }
+                let next_expiry = clients
+                    .iter()
+                    .filter(|client| !client.authorized)
+                    .map(|client| client.connected + AUTH_TIMEOUT)
+                    .min();
+                clients = match next_expiry {
+                    Some(expiry) => {
+                        let timeout = expiry.saturating_sub(uptime());
+                        WAKE.wait_timeout(clients, timeout).unwrap().0
+                    }
+                    None => WAKE.wait(clients).unwrap(),
+                };
+            };
+            let client = &mut clients[index];
+            (
+                client.session,
+                client.sender.take().unwrap(),
+                core::mem::take(&mut client.dropped),
+                core::mem::take(&mut client.queue),
+            )
+        };
+
+        let dropped = (dropped > 0)
+            .then(|| serde_json::to_string(&Event::Dropped { count: dropped }).ok())
+            .flatten();
+        let result = dropped
+            .iter()
+            .map(String::as_str)
+            .chain(queue.iter().map(|json| &**json))
+            .try_for_each(|json| sender.send(FrameType::Text(false), json.as_bytes()));
+
+        let reachable = result.is_ok() && !sender.is_closed();
+        let mut clients = HUB.lock().unwrap();
+        // The client might have disconnected in the meantime
+        if let Some(index) = clients.iter().position(|client| client.session == session) {
+            if reachable {
+                clients[index].sender = Some(sender);
+            } else if clients.swap_remove(index).authorized {
+                LISTENERS.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Closes the connections of the clients that did not send the CSRF token
+/// within [`AUTH_TIMEOUT`].
+fn disconnect_expired(clients: &mut Vec<Client>) {
+    let now = uptime();
+    clients.retain_mut(|client| {
+        let expired = !client.authorized && now >= client.connected + AUTH_TIMEOUT;
+        if expired {
+            if let Some(sender) = &mut client.sender {
+                let _ = sender.send(FrameType::Close, &[]);
+            }
+        }
+        !expired
+    });
+}
+
+fn uptime() -> Duration {
+    Duration::from_micros(unsafe { sys::esp_timer_get_time() } as u64)
+}
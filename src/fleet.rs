@@ -0,0 +1,443 @@
+//! Pushes the station settings of a leader device to nearby unprovisioned
+//! devices over ESP-NOW
+//!
+//! Followers broadcast a hello with a fresh challenge, hopping through the
+//! channels since they do not know the one of the leader. A leader answers
+//! every hello with its station settings and waits for the acknowledgement.
+//! Both sides need the same [`FleetKey`], see [`frame`] for the message
+//! format.
+
+pub use soft_ap::fleet::frame;
+
+use self::frame::{Challenge, FleetKey, Frame, Message, MAX_FRAME_LEN};
+use crate::{
+    api::ClientSettings,
+    convert::{format_mac, serialize_mac},
+    events::{self, Event},
+    wifi::{self, ClientAuth, InvalidSettings},
+    Command, CMD_QUEUE,
+};
+use core::{ffi::c_int, time::Duration};
+use embedded_svc::wifi::ClientConfiguration;
+use esp_idf_sys::{self as sys, EspError};
+use log::{debug, info, warn};
+use serde::Serialize;
+use std::{
+    sync::{Condvar, Mutex},
+    thread,
+};
+
+/// Shorter passphrases are too easy to guess, see [`key_from_passphrase`]
+pub const MIN_PASSPHRASE_LEN: usize = 8;
+/// How long a leader answers hellos after a push was started
+pub const PUSH_DURATION: Duration = Duration::from_secs(60);
+const HELLO_INTERVAL: Duration = Duration::from_secs(1);
+const BROADCAST: [u8; 6] = [0xff; 6];
+/// Received frames waiting to be handled, more are dropped
+const INBOX_LEN: usize = 8;
+/// Followers a leader keeps track of, ESP-NOW itself allows 20 peers
+const MAX_PEERS: usize = 16;
+const MAX_CHANNEL: u8 = 13;
+
+static STATE: Mutex<State> = Mutex::new(State {
+    key: None,
+    role: Role::Off,
+    last_peers: Vec::new(),
+});
+static INBOX: Mutex<heapless::Deque<([u8; 6], Frame), INBOX_LEN>> =
+    Mutex::new(heapless::Deque::new());
+static WAKE: Condvar = Condvar::new();
+
+struct State {
+    key: Option<FleetKey>,
+    role: Role,
+    /// Followers of the last finished push
+    last_peers: Vec<Peer>,
+}
+
+enum Role {
+    Off,
+    Follower {
+        challenge: Challenge,
+        /// Channel the next hello is sent on
+        channel: u8,
+    },
+    Leader {
+        until: Duration,
+        ssid: heapless::String<32>,
+        password: heapless::String<64>,
+        peers: Vec<Peer>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Peer {
+    #[serde(serialize_with = "serialize_mac")]
+    pub mac: [u8; 6],
+    #[serde(skip)]
+    challenge: Challenge,
+    pub state: PeerState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerState {
+    /// The settings were sent, but not acknowledged yet
+    Sent,
+    Configured,
+    Rejected,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FleetStatus {
+    pub role: &'static str,
+    pub key_set: bool,
+    /// Followers of the running or last push
+    pub peers: Vec<Peer>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushError {
+    NoKey,
+    NoClientNetwork,
+}
+
+impl core::fmt::Display for PushError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            PushError::NoKey => "no fleet key set",
+            PushError::NoClientNetwork => "the leader needs a client network to push",
+        })
+    }
+}
+
+/// Derives the fleet key from a passphrase entered in the portal or on the
+/// console.
+pub fn key_from_passphrase(passphrase: &str) -> Result<FleetKey, InvalidSettings> {
+    if passphrase.chars().count() < MIN_PASSPHRASE_LEN {
+        return Err(InvalidSettings {
+            field: "key",
+            message: "must have at least 8 characters",
+        });
+    }
+    Ok(FleetKey::derive(passphrase))
+}
+
+/// Starts ESP-NOW and the thread handling the fleet messages. WiFi has to be
+/// started before.
+pub fn init(key: Option<FleetKey>) -> anyhow::Result<()> {
+    STATE.lock().unwrap().key = key;
+    sys::esp!(unsafe { sys::esp_now_init() })?;
+    sys::esp!(unsafe { sys::esp_now_register_recv_cb(Some(on_recv)) })?;
+    add_peer(BROADCAST)?;
+    thread::Builder::new()
+        .name("fleet".into())
+        .stack_size(6 * 1024)
+        .spawn(run)?;
+    Ok(())
+}
+
+/// Replaces the fleet key, `None` stops all fleet activity.
+pub fn set_key(key: Option<FleetKey>) {
+    let mut state = STATE.lock().unwrap();
+    if key.is_none() {
+        state.role = Role::Off;
+    }
+    state.key = key;
+}
+
+/// Waits for settings from a leader, as long as a key is set.
+pub fn follow() {
+    let mut state = STATE.lock().unwrap();
+    if state.key.is_some() && matches!(state.role, Role::Off) {
+        info!("waiting for settings from a fleet leader");
+        state.role = Role::Follower {
+            challenge: random(),
+            channel: 1,
+        };
+    }
+}
+
+/// Offers the station settings to followers for [`PUSH_DURATION`].
+pub fn push(sta_config: Option<&ClientConfiguration>) -> Result<(), PushError> {
+    let mut state = STATE.lock().unwrap();
+    if state.key.is_none() {
+        return Err(PushError::NoKey);
+    }
+    let Some(sta_config) = sta_config.filter(|config| !config.ssid.is_empty()) else {
+        return Err(PushError::NoClientNetwork);
+    };
+    info!("pushing the settings of {} to the fleet", sta_config.ssid);
+    state.role = Role::Leader {
+        until: uptime() + PUSH_DURATION,
+        ssid: sta_config.ssid.clone(),
+        password: sta_config.password.clone(),
+        peers: Vec::new(),
+    };
+    Ok(())
+}
+
+/// Stops following, e.g. because the station was set up otherwise.
+pub fn stop_following() {
+    let mut state = STATE.lock().unwrap();
+    if matches!(state.role, Role::Follower { .. }) {
+        state.role = Role::Off;
+    }
+}
+
+pub fn status() -> FleetStatus {
+    let state = STATE.lock().unwrap();
+    let (role, peers) = match &state.role {
+        Role::Off => ("off", state.last_peers.clone()),
+        Role::Follower { .. } => ("follower", Vec::new()),
+        Role::Leader { peers, .. } => ("leader", peers.clone()),
+    };
+    FleetStatus {
+        role,
+        key_set: state.key.is_some(),
+        peers,
+    }
+}
+
+unsafe extern "C" fn on_recv(mac: *const u8, data: *const u8, len: c_int) {
+    // Runs in the WiFi task, so only queue the frame
+    let Ok(len) = usize::try_from(len) else {
+        return;
+    };
+    if mac.is_null() || data.is_null() || len > MAX_FRAME_LEN {
+        return;
+    }
+    let mac: [u8; 6] = core::slice::from_raw_parts(mac, 6).try_into().unwrap();
+    let mut frame = Frame::new();
+    frame
+        .extend_from_slice(core::slice::from_raw_parts(data, len))
+        .unwrap();
+    let mut inbox = INBOX.lock().unwrap();
+    if inbox.push_back((mac, frame)).is_ok() {
+        WAKE.notify_one();
+    }
+}
+
+fn run() {
+    let mut next_tick = uptime();
+    loop {
+        let received = {
+            let mut inbox = INBOX.lock().unwrap();
+            if inbox.is_empty() {
+                inbox = WAKE.wait_timeout(inbox, HELLO_INTERVAL).unwrap().0;
+            }
+            inbox.pop_front()
+        };
+        if let Some((mac, frame)) = received {
+            handle_frame(mac, &frame);
+        }
+        let now = uptime();
+        if now >= next_tick {
+            tick(now);
+            next_tick = now + HELLO_INTERVAL;
+        }
+    }
+}
+
+fn handle_frame(mac: [u8; 6], frame: &[u8]) {
+    let mut state = STATE.lock().unwrap();
+    let State {
+        key: Some(key),
+        role,
+        ..
+    } = &mut *state
+    else {
+        return;
+    };
+    let message = match frame::open(frame, key) {
+        Ok(message) => message,
+        Err(err) => {
+            debug!("ignoring ESP-NOW frame: {err}");
+            return;
+        }
+    };
+
+    if let Role::Follower { challenge, .. } = role {
+        if !message.answers(challenge) {
+            // Hellos of other followers, or settings for an earlier hello
+            return;
+        }
+    }
+
+    let mut configured = false;
+    match (&mut *role, message) {
+        (
+            Role::Leader {
+                ssid,
+                password,
+                peers,
+                ..
+            },
+            Message::Hello { challenge },
+        ) => {
+            let index = match peers.iter().position(|peer| peer.mac == mac) {
+                Some(index) => index,
+                None if peers.len() < MAX_PEERS => {
+                    peers.push(Peer {
+                        mac,
+                        challenge,
+                        state: PeerState::Sent,
+                    });
+                    peers.len() - 1
+                }
+                None => return,
+            };
+            let peer = &mut peers[index];
+            if peer.challenge == challenge && peer.state != PeerState::Sent {
+                // Already answered
+                return;
+            }
+            peer.challenge = challenge;
+            peer.state = PeerState::Sent;
+            let config = Message::Config {
+                challenge,
+                ssid: ssid.clone(),
+                password: password.clone(),
+            };
+            send(mac, &frame::seal(&config, key, random()));
+        }
+        (
+            Role::Leader { peers, .. },
+            Message::Ack {
+                challenge,
+                accepted,
+            },
+        ) => {
+            // Only the answer to the challenge of the settings sent last
+            let Some(peer) = peers.iter_mut().find(|peer| {
+                peer.mac == mac && peer.challenge == challenge && peer.state == PeerState::Sent
+            }) else {
+                return;
+            };
+            peer.state = if accepted {
+                PeerState::Configured
+            } else {
+                PeerState::Rejected
+            };
+            info!("fleet follower {} {:?}", format_mac(&mac), peer.state);
+            events::publish(Event::Fleet {
+                mac: format_mac(&mac),
+                state: peer.state,
+            });
+        }
+        (
+            Role::Follower { challenge, .. },
+            Message::Config {
+                challenge: answered,
+                ssid,
+                password,
+            },
+        ) => {
+            info!("received settings for {ssid} from a fleet leader");
+            let settings = ClientSettings {
+                ssid,
+                password: Some(password),
+                password_set: false,
+                hidden: false,
+                auth_method: ClientAuth::Auto,
+                bssid: None,
+            };
+            let accepted = CMD_QUEUE.enqueue(Command::UpdateClient(settings)).is_ok();
+            let ack = Message::Ack {
+                challenge: answered,
+                accepted,
+            };
+            send(mac, &frame::seal(&ack, key, random()));
+            // The same settings are never taken twice
+            *challenge = random();
+            configured = accepted;
+        }
+        _ => {}
+    }
+    if configured {
+        // Stay on the channel while the settings are tried, the main loop
+        // follows again if they get reverted
+        *role = Role::Off;
+    }
+}
+
+fn tick(now: Duration) {
+    let mut state = STATE.lock().unwrap();
+    let State {
+        key,
+        role,
+        last_peers,
+    } = &mut *state;
+    match role {
+        Role::Leader { until, peers, .. } if now >= *until => {
+            let configured = peers
+                .iter()
+                .filter(|peer| peer.state == PeerState::Configured)
+                .count();
+            info!(
+                "fleet push finished, {configured} of {} followers configured",
+                peers.len()
+            );
+            *last_peers = core::mem::take(peers);
+            *role = Role::Off;
+        }
+        Role::Follower { challenge, channel } => {
+            let Some(key) = key else {
+                return;
+            };
+            // Hopping moves the access point as well, so only while nobody
+            // uses it
+            if wifi::ap_client_count() == 0 {
+                let _ = sys::esp!(unsafe {
+                    sys::esp_wifi_set_channel(
+                        *channel,
+                        sys::wifi_second_chan_t_WIFI_SECOND_CHAN_NONE,
+                    )
+                });
+                *channel = *channel % MAX_CHANNEL + 1;
+            }
+            let hello = Message::Hello {
+                challenge: *challenge,
+            };
+            send(BROADCAST, &frame::seal(&hello, key, random()));
+        }
+        _ => {}
+    }
+}
+
+fn send(mac: [u8; 6], frame: &[u8]) {
+    if let Err(err) = add_peer(mac) {
+        warn!("adding ESP-NOW peer failed: {err}");
+        return;
+    }
+    if let Err(err) =
+        sys::esp!(unsafe { sys::esp_now_send(mac.as_ptr(), frame.as_ptr(), frame.len()) })
+    {
+        warn!("sending over ESP-NOW failed: {err}");
+    }
+}
+
+fn add_peer(mac: [u8; 6]) -> Result<(), EspError> {
+    if unsafe { sys::esp_now_is_peer_exist(mac.as_ptr()) } {
+        return Ok(());
+    }
+    let peer = sys::esp_now_peer_info_t {
+        peer_addr: mac,
+        // The current channel, frames are encrypted by the fleet key
+        channel: 0,
+        ifidx: sys::wifi_interface_t_WIFI_IF_STA,
+        encrypt: false,
+        ..Default::default()
+    };
+    sys::esp!(unsafe { sys::esp_now_add_peer(&peer) })
+}
+
+fn random<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    unsafe { sys::esp_fill_random(bytes.as_mut_ptr().cast(), N as _) };
+    bytes
+}
+
+fn uptime() -> Duration {
+    Duration::from_micros(unsafe { sys::esp_timer_get_time() } as u64)
+}
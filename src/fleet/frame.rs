@@ -0,0 +1,407 @@
+//! Wire format of the fleet configuration messages
+//!
+//! ```text
+//! magic "SAF" | version | kind | nonce (12) | ciphertext | tag (16)
+//! ```
+//!
+//! Every message is encrypted with ChaCha20-Poly1305 under the fleet key, the
+//! first five bytes are authenticated as associated data. Replays are
+//! prevented by challenges: a configuration is only accepted if it echoes the
+//! random challenge of the follower's current hello, and an acknowledgement
+//! only if it echoes the challenge the leader answered.
+
+use chacha20poly1305::{aead::AeadInPlace, ChaCha20Poly1305, Key, KeyInit, Nonce, Tag};
+use core::fmt;
+use hmac::Hmac;
+use sha2::Sha256;
+
+/// Maximum payload of an ESP-NOW frame
+pub const MAX_FRAME_LEN: usize = 250;
+pub const NONCE_LEN: usize = 12;
+
+const MAGIC: &[u8; 3] = b"SAF";
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 2;
+const TAG_LEN: usize = 16;
+const SALT: &[u8] = b"soft-ap fleet";
+const ROUNDS: u32 = 4096;
+
+pub type Challenge = [u8; 16];
+pub type Frame = heapless::Vec<u8, MAX_FRAME_LEN>;
+
+/// Key shared by all devices of a fleet
+#[derive(Clone, PartialEq, Eq)]
+pub struct FleetKey([u8; 32]);
+
+impl FleetKey {
+    /// Derives the key from a passphrase, so it can be typed on every device.
+    pub fn derive(passphrase: &str) -> Self {
+        let mut key = [0; 32];
+        pbkdf2::pbkdf2::<Hmac<Sha256>>(passphrase.as_bytes(), SALT, ROUNDS, &mut key);
+        Self(key)
+    }
+
+    pub fn from_bytes(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for FleetKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("FleetKey(..)")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// Broadcast by unprovisioned devices
+    Hello { challenge: Challenge },
+    /// Station settings sent by the leader in reply to a hello
+    Config {
+        challenge: Challenge,
+        ssid: heapless::String<32>,
+        password: heapless::String<64>,
+    },
+    /// Whether the follower took the settings
+    Ack {
+        challenge: Challenge,
+        accepted: bool,
+    },
+}
+
+impl Message {
+    pub fn challenge(&self) -> &Challenge {
+        match self {
+            Message::Hello { challenge }
+            | Message::Config { challenge, .. }
+            | Message::Ack { challenge, .. } => challenge,
+        }
+    }
+
+    /// Whether the message answers one that carried `challenge`. Answers to
+    /// any other challenge are replays or late, a hello answers nothing.
+    pub fn answers(&self, challenge: &Challenge) -> bool {
+        !matches!(self, Message::Hello { .. }) && self.challenge() == challenge
+    }
+
+    fn kind(&self) -> u8 {
+        match self {
+            Message::Hello { .. } => 1,
+            Message::Config { .. } => 2,
+            Message::Ack { .. } => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// Not a fleet message, or one of another version
+    Foreign,
+    /// Sent with another key, or tampered with
+    Authentication,
+    Malformed,
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FrameError::Foreign => "not a fleet message",
+            FrameError::Authentication => "authentication failed",
+            FrameError::Malformed => "malformed message",
+        })
+    }
+}
+
+/// Encrypts `message`. `nonce` has to be random, it must never be used twice
+/// with the same key.
+pub fn seal(message: &Message, key: &FleetKey, nonce: [u8; NONCE_LEN]) -> Frame {
+    let mut frame = Frame::new();
+    // All messages fit, the longest one is 147 bytes
+    frame.extend_from_slice(MAGIC).unwrap();
+    frame.extend_from_slice(&[VERSION, message.kind()]).unwrap();
+    frame.extend_from_slice(&nonce).unwrap();
+
+    let start = frame.len();
+    frame.extend_from_slice(message.challenge()).unwrap();
+    match message {
+        Message::Hello { .. } => {}
+        Message::Config { ssid, password, .. } => {
+            for value in [ssid.as_str(), password.as_str()] {
+                frame.push(value.len() as u8).unwrap();
+                frame.extend_from_slice(value.as_bytes()).unwrap();
+            }
+        }
+        Message::Ack { accepted, .. } => frame.push(u8::from(*accepted)).unwrap(),
+    }
+
+    let (header, payload) = frame.split_at_mut(start);
+    let tag = cipher(key)
+        .encrypt_in_place_detached(
+            Nonce::from_slice(&header[HEADER_LEN..]),
+            &header[..HEADER_LEN],
+            payload,
+        )
+        .expect("payload is short");
+    frame.extend_from_slice(&tag).unwrap();
+    frame
+}
+
+/// Decrypts and parses a received frame.
+pub fn open(frame: &[u8], key: &FleetKey) -> Result<Message, FrameError> {
+    if frame.len() < HEADER_LEN || &frame[..MAGIC.len()] != MAGIC || frame[MAGIC.len()] != VERSION {
+        return Err(FrameError::Foreign);
+    }
+    if frame.len() < HEADER_LEN + NONCE_LEN + TAG_LEN {
+        return Err(FrameError::Malformed);
+    }
+    let kind = frame[MAGIC.len() + 1];
+    let (header, rest) = frame.split_at(HEADER_LEN);
+    let (nonce, rest) = rest.split_at(NONCE_LEN);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+
+    let mut payload = heapless::Vec::<u8, MAX_FRAME_LEN>::new();
+    payload.extend_from_slice(ciphertext).unwrap();
+    cipher(key)
+        .decrypt_in_place_detached(
+            Nonce::from_slice(nonce),
+            header,
+            &mut payload,
+            Tag::from_slice(tag),
+        )
+        .map_err(|_| FrameError::Authentication)?;
+
+    let mut reader = Reader(&payload);
+    let challenge = reader
+        .take(16)?
+        .try_into()
+        .map_err(|_| FrameError::Malformed)?;
+    let message = match kind {
+        1 => Message::Hello { challenge },
+        2 => Message::Config {
+            challenge,
+            ssid: reader.string()?,
+            password: reader.string()?,
+        },
+        3 => Message::Ack {
+            challenge,
+            accepted: match reader.take(1)? {
+                [0] => false,
+                [1] => true,
+                _ => return Err(FrameError::Malformed),
+            },
+        },
+        _ => return Err(FrameError::Malformed),
+    };
+    if !reader.0.is_empty() {
+        return Err(FrameError::Malformed);
+    }
+    Ok(message)
+}
+
+fn cipher(key: &FleetKey) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new(Key::from_slice(key.as_bytes()))
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], FrameError> {
+        if self.0.len() < len {
+            return Err(FrameError::Malformed);
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    /// A string prefixed with its length
+    fn string<const N: usize>(&mut self) -> Result<heapless::String<N>, FrameError> {
+        let len = self.take(1)?[0];
+        let value =
+            core::str::from_utf8(self.take(len.into())?).map_err(|_| FrameError::Malformed)?;
+        let mut string = heapless::String::new();
+        string.push_str(value).map_err(|_| FrameError::Malformed)?;
+        Ok(string)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHALLENGE: Challenge = [7; 16];
+
+    fn key() -> FleetKey {
+        FleetKey::from_bytes([1; 32])
+    }
+
+    fn messages() -> [Message; 3] {
+        [
+            Message::Hello {
+                challenge: CHALLENGE,
+            },
+            Message::Config {
+                challenge: CHALLENGE,
+                ssid: "Home network".into(),
+                password: "correct horse".into(),
+            },
+            Message::Ack {
+                challenge: CHALLENGE,
+                accepted: true,
+            },
+        ]
+    }
+
+    /// Seals `payload` as is, to get malformed messages past the
+    /// authentication
+    fn seal_payload(kind: u8, payload: &[u8]) -> Vec<u8> {
+        let nonce = [3; NONCE_LEN];
+        let mut frame = MAGIC.to_vec();
+        frame.extend([VERSION, kind]);
+        frame.extend(nonce);
+        let mut payload = payload.to_vec();
+        let tag = cipher(&key())
+            .encrypt_in_place_detached(
+                Nonce::from_slice(&nonce),
+                &frame[..HEADER_LEN],
+                &mut payload,
+            )
+            .unwrap();
+        frame.extend(payload);
+        frame.extend(tag);
+        frame
+    }
+
+    #[test]
+    fn round_trip() {
+        for message in messages() {
+            let frame = seal(&message, &key(), [3; NONCE_LEN]);
+            assert_eq!(open(&frame, &key()), Ok(message));
+        }
+    }
+
+    #[test]
+    fn wrong_key() {
+        let other = FleetKey::from_bytes([2; 32]);
+        for message in messages() {
+            let frame = seal(&message, &key(), [3; NONCE_LEN]);
+            assert_eq!(open(&frame, &other), Err(FrameError::Authentication));
+        }
+    }
+
+    #[test]
+    fn tampered() {
+        for message in messages() {
+            let frame = seal(&message, &key(), [3; NONCE_LEN]);
+            // The kind, the nonce, the ciphertext and the tag
+            for index in MAGIC.len() + 1..frame.len() {
+                let mut tampered = frame.clone();
+                tampered[index] ^= 0x01;
+                assert_eq!(
+                    open(&tampered, &key()),
+                    Err(FrameError::Authentication),
+                    "byte {index} of {message:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn foreign() {
+        let frame = seal(&messages()[0], &key(), [3; NONCE_LEN]);
+        for index in 0..=MAGIC.len() {
+            let mut foreign = frame.clone();
+            foreign[index] ^= 0x01;
+            assert_eq!(open(&foreign, &key()), Err(FrameError::Foreign));
+        }
+        assert_eq!(open(b"", &key()), Err(FrameError::Foreign));
+        assert_eq!(open(b"SAF", &key()), Err(FrameError::Foreign));
+    }
+
+    #[test]
+    fn truncated() {
+        let frame = seal(&messages()[0], &key(), [3; NONCE_LEN]);
+        for len in HEADER_LEN..HEADER_LEN + NONCE_LEN + TAG_LEN {
+            assert_eq!(open(&frame[..len], &key()), Err(FrameError::Malformed));
+        }
+
+        let mut config = CHALLENGE.to_vec();
+        config.extend(b"\x04Home");
+        assert_eq!(
+            open(&seal_payload(1, &CHALLENGE[..15]), &key()),
+            Err(FrameError::Malformed)
+        );
+        assert_eq!(
+            open(&seal_payload(2, &config), &key()),
+            Err(FrameError::Malformed)
+        );
+        config.extend(b"\x08secret");
+        assert_eq!(
+            open(&seal_payload(2, &config), &key()),
+            Err(FrameError::Malformed)
+        );
+        assert_eq!(
+            open(&seal_payload(3, &CHALLENGE), &key()),
+            Err(FrameError::Malformed)
+        );
+    }
+
+    #[test]
+    fn trailing_byte() {
+        let mut hello = CHALLENGE.to_vec();
+        hello.push(0);
+        assert_eq!(
+            open(&seal_payload(1, &hello), &key()),
+            Err(FrameError::Malformed)
+        );
+
+        let mut config = CHALLENGE.to_vec();
+        config.extend(b"\x04Home\x06secret!");
+        assert_eq!(
+            open(&seal_payload(2, &config), &key()),
+            Err(FrameError::Malformed)
+        );
+
+        let mut ack = CHALLENGE.to_vec();
+        ack.extend([1, 0]);
+        assert_eq!(
+            open(&seal_payload(3, &ack), &key()),
+            Err(FrameError::Malformed)
+        );
+    }
+
+    #[test]
+    fn invalid_values() {
+        let mut ack = CHALLENGE.to_vec();
+        ack.push(2);
+        assert_eq!(
+            open(&seal_payload(3, &ack), &key()),
+            Err(FrameError::Malformed)
+        );
+        assert_eq!(
+            open(&seal_payload(4, &CHALLENGE), &key()),
+            Err(FrameError::Malformed)
+        );
+    }
+
+    #[test]
+    fn stale_challenge() {
+        let stale = Message::Config {
+            challenge: [8; 16],
+            ssid: "Home network".into(),
+            password: "correct horse".into(),
+        };
+        let message = open(&seal(&stale, &key(), [3; NONCE_LEN]), &key()).unwrap();
+        assert!(!message.answers(&CHALLENGE));
+        assert!(message.answers(&[8; 16]));
+
+        let [hello, config, ack] = messages();
+        assert!(!hello.answers(&CHALLENGE));
+        assert!(config.answers(&CHALLENGE));
+        assert!(ack.answers(&CHALLENGE));
+    }
+}
@@ -1,44 +1,665 @@
 use crate::{
-    template::WifiSettingsTemplate,
-    wifi::{self, storage::WifiStorage},
+    api::{self, Resource},
+    auth::{self, Access, SharedAuth},
+    boot::Recovery,
+    convert,
+    diagnostics::Status,
+    fleet, logs,
+    ota::{self, OtaError},
+    syslog::{self, SyslogConfig},
+    template::{
+        ConfigTemplate, LogsTemplate, OtaTemplate, SetupTemplate, TlsTemplate, WifiSettingsTemplate,
+    },
+    tls::{self, Identity, SharedTls},
+    wifi::{self, storage::WifiStorage, InvalidSettings, ScanOptions, WifiInfo},
     Command, CMD_QUEUE,
 };
 use askama::Template as _;
 use embedded_svc::{
     http::{
         server::{HandlerError, Request},
-        Headers,
+        Headers, Method, Query,
     },
     io::{Read, Write},
 };
-use esp_idf_svc::http::server::EspHttpConnection;
+use esp_idf_svc::{http::server::EspHttpConnection, nvs::NvsPartitionId, ota::EspOta};
+use esp_idf_sys as sys;
+use log::{info, warn, LevelFilter};
+use soft_ap::link;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+/// Per-boot token that has to accompany every mutating request, so that other
+/// sites can not make the browser of a logged in user change the settings.
+#[derive(Debug, Clone)]
+pub struct CsrfToken(Arc<str>);
+
+impl CsrfToken {
+    pub const HEADER: &str = "X-CSRF-Token";
+
+    pub fn generate() -> Self {
+        let mut bytes = [0u8; 16];
+        unsafe { sys::esp_fill_random(bytes.as_mut_ptr().cast(), bytes.len() as _) };
+        let hex: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+        Self(hex.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Wraps `handler` so that it only runs for requests carrying the admin
+/// credentials (HTTP Basic auth).
+pub fn authenticated<H>(
+    auth: &SharedAuth,
+    handler: H,
+) -> impl Fn(Request<&mut EspHttpConnection>) -> Result<(), HandlerError> + Send + 'static
+where
+    H: Fn(Request<&mut EspHttpConnection>) -> Result<(), HandlerError> + Send + 'static,
+{
+    let auth = auth.clone();
+    move |request: Request<&mut EspHttpConnection>| {
+        let access = auth
+            .lock()
+            .unwrap()
+            .authenticate(request.header("Authorization"))?;
+        match access {
+            Access::Granted => handler(request),
+            Access::NoPassword if request.method() == Method::Get => {
+                request.into_response(302, None, &[("Location", "/setup")])?;
+                Ok(())
+            }
+            Access::NoPassword => error_response(request, 403, "set an admin password first"),
+            Access::Denied => error_response_with(
+                request,
+                401,
+                &[(
+                    "WWW-Authenticate",
+                    "Basic realm=\"ESP32\", charset=\"UTF-8\"",
+                )],
+                None,
+                "authentication required",
+            ),
+            Access::LockedOut(retry_after) => {
+                warn!("rejecting login attempt, too many failures");
+                let retry_after = (retry_after.as_secs() + 1).to_string();
+                error_response_with(
+                    request,
+                    429,
+                    &[("Retry-After", &retry_after)],
+                    None,
+                    "too many failed login attempts",
+                )
+            }
+        }
+    }
+}
+
+/// Wraps a mutating `handler` so that it only runs for same-origin requests
+/// that carry the [`CsrfToken`] and a body of the given `content_type`.
+pub fn csrf_protected<H>(
+    csrf: &CsrfToken,
+    content_type: &'static str,
+    handler: H,
+) -> impl Fn(Request<&mut EspHttpConnection>) -> Result<(), HandlerError> + Send + 'static
+where
+    H: Fn(Request<&mut EspHttpConnection>) -> Result<(), HandlerError> + Send + 'static,
+{
+    let csrf = csrf.clone();
+    move |request: Request<&mut EspHttpConnection>| {
+        if !is_same_origin(&request) {
+            warn!("rejecting cross-origin request to {}", request.uri());
+            return error_response(request, 403, "cross-origin requests are not allowed");
+        }
+        let token_valid = request.header(CsrfToken::HEADER).map_or(false, |token| {
+            auth::constant_time_eq(token.as_bytes(), csrf.as_str().as_bytes())
+        });
+        if !token_valid {
+            warn!(
+                "rejecting request to {} without valid CSRF token",
+                request.uri()
+            );
+            return error_response(request, 403, "missing or invalid CSRF token");
+        }
+        let media_type = request
+            .content_type()
+            .and_then(|value| value.split(';').next())
+            .map(str::trim);
+        if !media_type.map_or(false, |media_type| {
+            media_type.eq_ignore_ascii_case(content_type)
+        }) {
+            return error_response(
+                request,
+                415,
+                &format!("Content-Type must be {content_type}"),
+            );
+        }
+        handler(request)
+    }
+}
+
+/// Checks that `Origin` (or `Referer`) of a request, if present, refers to
+/// the host the request was sent to. Non-browser clients send neither, and
+/// are still covered by the [`CsrfToken`] check.
+fn is_same_origin(request: &Request<&mut EspHttpConnection>) -> bool {
+    let Some(host) = request.header("Host") else {
+        return false;
+    };
+    match request
+        .header("Origin")
+        .or_else(|| request.header("Referer"))
+    {
+        Some(source) => source
+            .split_once("://")
+            .and_then(|(_, rest)| rest.split('/').next())
+            .map_or(false, |authority| authority.eq_ignore_ascii_case(host)),
+        None => true,
+    }
+}
+
+pub fn csrf_handler(
+    request: Request<&mut EspHttpConnection>,
+    csrf: &CsrfToken,
+) -> Result<(), HandlerError> {
+    #[derive(serde::Serialize)]
+    struct CsrfApi<'a> {
+        token: &'a str,
+    }
+
+    let json = serde_json::to_vec(&CsrfApi {
+        token: csrf.as_str(),
+    })?;
+    let mut response =
+        request.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?;
+    response.write_all(&json)?;
+    Ok(())
+}
+
+pub fn setup_page_handler(
+    request: Request<&mut EspHttpConnection>,
+    auth: &SharedAuth,
+    csrf: &CsrfToken,
+) -> Result<(), HandlerError> {
+    if auth.lock().unwrap().has_password()? {
+        request.into_response(302, None, &[("Location", "/")])?;
+        return Ok(());
+    }
+    let template = SetupTemplate {
+        username: auth::USERNAME,
+        min_password_len: auth::MIN_PASSWORD_LEN,
+        csrf_token: csrf.as_str().to_owned(),
+    };
+    let mut response = request.into_ok_response()?;
+    response.write_all(template.render()?.as_bytes())?;
+    Ok(())
+}
+
+/// Sets the initial admin password. Changing it later requires a factory
+/// reset.
+pub fn setup_handler(
+    mut request: Request<&mut EspHttpConnection>,
+    auth: &SharedAuth,
+) -> Result<(), HandlerError> {
+    #[derive(serde::Deserialize)]
+    struct SetupApi {
+        password: String,
+    }
+
+    let SetupApi { password } = match serde_json::from_slice(&read_body(&mut request)?) {
+        Ok(setup) => setup,
+        Err(err) => {
+            return error_response(request, 400, &format!("invalid request body: {err}"));
+        }
+    };
+    let mut auth = auth.lock().unwrap();
+    if auth.has_password()? {
+        return error_response(request, 403, "admin password is already set");
+    }
+    if password.chars().count() < auth::MIN_PASSWORD_LEN {
+        return error_response(request, 400, "admin password is too short");
+    }
+    auth.set_password(Some(&password))?;
+    info!("admin password set");
+    request.into_ok_response()?;
+    Ok(())
+}
 
 pub fn root_handler<T>(
     request: Request<&mut EspHttpConnection>,
-    wifi_storage: &WifiStorage<T>,
+    wifi_storage: &Mutex<WifiStorage<T>>,
+    csrf: &CsrfToken,
 ) -> Result<(), HandlerError>
 where
-    T: esp_idf_svc::nvs::NvsPartitionId,
+    T: NvsPartitionId,
 {
-    let info = wifi_storage.get_info()?;
+    let (info, syslog) = {
+        let wifi_storage = wifi_storage.lock().unwrap();
+        (wifi_storage.get_info()?, wifi_storage.get_syslog()?)
+    };
+    let template = WifiSettingsTemplate {
+        syslog,
+        csrf_token: csrf.as_str().to_owned(),
+        ..WifiSettingsTemplate::from(info)
+    };
     let mut response = request.into_ok_response()?;
-    response.write_all(WifiSettingsTemplate::from(info).render()?.as_bytes())?;
+    response.write_all(template.render()?.as_bytes())?;
     Ok(())
 }
 
-pub fn post_handler(mut request: Request<&mut EspHttpConnection>) -> Result<(), HandlerError> {
-    let len = request.content_len().unwrap_or_default();
-    let mut buf = vec![0; len as usize];
-    request.read_exact(&mut buf)?;
-    let settings: WifiSettingsTemplate = serde_json::from_slice(&buf)?;
-    CMD_QUEUE.enqueue(Command::UpdateWifi(settings))?;
+pub fn post_handler<T>(
+    mut request: Request<&mut EspHttpConnection>,
+    wifi_storage: &Mutex<WifiStorage<T>>,
+) -> Result<(), HandlerError>
+where
+    T: NvsPartitionId,
+{
+    let settings: WifiSettingsTemplate = serde_json::from_slice(&read_body(&mut request)?)?;
+    let syslog_config = settings.syslog.clone();
+    let current = wifi_storage.lock().unwrap().get_info()?;
+    let info = settings.into_info(&current)?;
+    if let Err(invalid) = submit_settings(wifi_storage, info, syslog_config)? {
+        return invalid_settings_response(request, invalid);
+    }
     Ok(())
 }
 
-pub fn scan_handler(request: Request<&mut EspHttpConnection>) -> Result<(), HandlerError> {
+/// Validates the settings, stores the syslog configuration right away and
+/// hands the WiFi settings to the main loop, which applies them on trial.
+fn submit_settings<T>(
+    wifi_storage: &Mutex<WifiStorage<T>>,
+    info: WifiInfo,
+    syslog_config: SyslogConfig,
+) -> Result<Result<(), InvalidSettings>, HandlerError>
+where
+    T: NvsPartitionId,
+{
+    if let Err(invalid) = info.validate().and_then(|_| syslog_config.validate()) {
+        return Ok(Err(invalid));
+    }
+    let mut wifi_storage = wifi_storage.lock().unwrap();
+    if wifi_storage.get_syslog()? != syslog_config {
+        wifi_storage.set_syslog(&syslog_config)?;
+        syslog::configure(syslog_config);
+    }
+    drop(wifi_storage);
+    CMD_QUEUE.enqueue(Command::UpdateWifi(info))?;
+    Ok(Ok(()))
+}
+
+pub fn api_get_handler<R, T>(
+    request: Request<&mut EspHttpConnection>,
+    wifi_storage: &Mutex<WifiStorage<T>>,
+) -> Result<(), HandlerError>
+where
+    R: Resource,
+    T: NvsPartitionId,
+{
+    let info = wifi_storage.lock().unwrap().get_info()?;
+    json_response(request, 200, &R::from_info(&info))
+}
+
+/// `PUT` replaces the resource, `PATCH` applies a JSON merge patch to it. The
+/// result goes through the same trial period as changes made in the portal,
+/// hence `202 Accepted`.
+pub fn api_update_handler<R, T>(
+    mut request: Request<&mut EspHttpConnection>,
+    wifi_storage: &Mutex<WifiStorage<T>>,
+) -> Result<(), HandlerError>
+where
+    R: Resource,
+    T: NvsPartitionId,
+{
+    let body = read_body(&mut request)?;
+    let current = wifi_storage.lock().unwrap().get_info()?;
+    let resource = if request.method() == Method::Patch {
+        serde_json::from_slice(&body).and_then(|patch| {
+            let mut value = serde_json::to_value(R::from_info(&current))?;
+            api::merge_patch(&mut value, patch);
+            serde_json::from_value::<R>(value)
+        })
+    } else {
+        serde_json::from_slice::<R>(&body)
+    };
+    let resource = match resource {
+        Ok(resource) => resource,
+        Err(err) => {
+            return error_response(request, 400, &format!("invalid request body: {err}"));
+        }
+    };
+
+    let info = resource.apply(current)?;
+    if let Err(invalid) = info.validate() {
+        return invalid_settings_response(request, invalid);
+    }
+    let accepted = R::from_info(&info);
+    CMD_QUEUE.enqueue(Command::UpdateWifi(info))?;
+    json_response(request, 202, &accepted)
+}
+
+/// Downloads all settings as a file, without the passwords unless
+/// `secrets=true` is given.
+pub fn api_config_export_handler<T>(
+    request: Request<&mut EspHttpConnection>,
+    wifi_storage: &Mutex<WifiStorage<T>>,
+) -> Result<(), HandlerError>
+where
+    T: NvsPartitionId,
+{
+    let secrets = match query_param(request.uri(), "secrets") {
+        None | Some("false") => false,
+        Some("true") => true,
+        Some(_) => return error_response(request, 400, "secrets must be true or false"),
+    };
+    let (info, syslog_config) = {
+        let wifi_storage = wifi_storage.lock().unwrap();
+        (wifi_storage.get_info()?, wifi_storage.get_syslog()?)
+    };
+    let config = api::ConfigFile::export(&info, syslog_config, secrets);
+    let json = serde_json::to_vec_pretty(&config)?;
+    let mut response = request.into_response(
+        200,
+        None,
+        &[
+            ("Content-Type", "application/json"),
+            (
+                "Content-Disposition",
+                "attachment; filename=\"wifi-config.json\"",
+            ),
+        ],
+    )?;
+    response.write_all(&json)?;
+    Ok(())
+}
+
+/// Applies an exported settings file like a change made in the portal.
+pub fn api_config_import_handler<T>(
+    mut request: Request<&mut EspHttpConnection>,
+    wifi_storage: &Mutex<WifiStorage<T>>,
+) -> Result<(), HandlerError>
+where
+    T: NvsPartitionId,
+{
+    let body = read_body(&mut request)?;
+    let config = match serde_json::from_slice::<api::ConfigFile>(&body) {
+        Ok(config) => config,
+        Err(err) => {
+            return error_response(request, 400, &format!("invalid settings file: {err}"));
+        }
+    };
+    if let Err(invalid) = config.validate() {
+        return invalid_settings_response(request, invalid);
+    }
+    let current = wifi_storage.lock().unwrap().get_info()?;
+    let (info, syslog_config) = config.apply(current)?;
+    let accepted = api::ConfigFile::export(&info, syslog_config.clone(), false);
+    if let Err(invalid) = submit_settings(wifi_storage, info, syslog_config)? {
+        return invalid_settings_response(request, invalid);
+    }
+    info!("importing settings file");
+    json_response(request, 202, &accepted)
+}
+
+pub fn config_page_handler(
+    request: Request<&mut EspHttpConnection>,
+    csrf: &CsrfToken,
+) -> Result<(), HandlerError> {
+    let template = ConfigTemplate {
+        csrf_token: csrf.as_str().to_owned(),
+    };
+    let mut response = request.into_ok_response()?;
+    response.write_all(template.render()?.as_bytes())?;
+    Ok(())
+}
+
+pub fn api_device_handler(
+    request: Request<&mut EspHttpConnection>,
+    https: bool,
+) -> Result<(), HandlerError> {
+    json_response(request, 200, &api::DeviceInfo::current(https)?)
+}
+
+pub fn api_status_handler(
+    request: Request<&mut EspHttpConnection>,
+    recovery: Option<Recovery>,
+) -> Result<(), HandlerError> {
+    json_response(request, 200, &Status::current(recovery)?)
+}
+
+/// Buffered log records, filtered by `?level=` and starting at the `?since=`
+/// cursor returned by the previous call.
+pub fn api_logs_handler(request: Request<&mut EspHttpConnection>) -> Result<(), HandlerError> {
+    #[derive(serde::Serialize)]
+    struct LogsApi {
+        next: u64,
+        entries: Vec<logs::Entry>,
+    }
+
+    let level = match query_param(request.uri(), "level").map(str::parse) {
+        None => LevelFilter::Trace,
+        Some(Ok(level)) => level,
+        Some(Err(_)) => {
+            return error_response(
+                request,
+                400,
+                "level must be error, warn, info, debug or trace",
+            );
+        }
+    };
+    let since = match query_param(request.uri(), "since").map(str::parse) {
+        None => 0,
+        Some(Ok(since)) => since,
+        Some(Err(_)) => return error_response(request, 400, "since must be a number"),
+    };
+    let (entries, next) = logs::entries(since, level);
+    json_response(request, 200, &LogsApi { next, entries })
+}
+
+/// History of the station link starting at the `?since=` cursor returned by
+/// the previous call.
+pub fn api_link_handler(request: Request<&mut EspHttpConnection>) -> Result<(), HandlerError> {
+    #[derive(serde::Serialize)]
+    struct LinkApi {
+        next: u64,
+        interval_secs: u64,
+        samples: Vec<link::Sample>,
+    }
+
+    let since = match query_param(request.uri(), "since").map(str::parse) {
+        None => 0,
+        Some(Ok(since)) => since,
+        Some(Err(_)) => return error_response(request, 400, "since must be a number"),
+    };
+    let (samples, next) = link::samples(since);
+    json_response(
+        request,
+        200,
+        &LinkApi {
+            next,
+            interval_secs: link::INTERVAL.as_secs(),
+            samples,
+        },
+    )
+}
+
+fn log_levels() -> BTreeMap<String, String> {
+    logs::module_levels()
+        .into_iter()
+        .map(|(module, level)| (module, level.as_str().to_ascii_lowercase()))
+        .collect()
+}
+
+pub fn api_log_levels_handler(
+    request: Request<&mut EspHttpConnection>,
+) -> Result<(), HandlerError> {
+    json_response(request, 200, &log_levels())
+}
+
+/// Changes the log level per module, e.g. `{"soft_ap::wifi": "debug"}`. A
+/// `null` level restores the default of that module.
+pub fn api_set_log_levels_handler(
+    mut request: Request<&mut EspHttpConnection>,
+) -> Result<(), HandlerError> {
+    let body = read_body(&mut request)?;
+    let changes: BTreeMap<String, Option<String>> = match serde_json::from_slice(&body) {
+        Ok(changes) => changes,
+        Err(err) => {
+            return error_response(request, 400, &format!("invalid request body: {err}"));
+        }
+    };
+    let mut levels = Vec::new();
+    for (module, level) in changes {
+        let level = match level.as_deref().map(str::parse::<LevelFilter>) {
+            None => None,
+            Some(Ok(level)) => Some(level),
+            Some(Err(_)) => {
+                let message = format!("invalid level for {module}");
+                return error_response(request, 422, &message);
+            }
+        };
+        levels.push((module, level));
+    }
+    for (module, level) in levels {
+        info!("log level of {module} set to {level:?}");
+        logs::set_module_level(&module, level);
+    }
+    json_response(request, 200, &log_levels())
+}
+
+pub fn logs_page_handler(
+    request: Request<&mut EspHttpConnection>,
+    csrf: &CsrfToken,
+) -> Result<(), HandlerError> {
+    let template = LogsTemplate {
+        csrf_token: csrf.as_str().to_owned(),
+    };
+    let mut response = request.into_ok_response()?;
+    response.write_all(template.render()?.as_bytes())?;
+    Ok(())
+}
+
+pub fn api_openapi_handler(request: Request<&mut EspHttpConnection>) -> Result<(), HandlerError> {
+    let mut response =
+        request.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?;
+    response.write_all(api::OPENAPI.as_bytes())?;
+    Ok(())
+}
+
+/// Starts WPS, the outcome is reported as event.
+pub fn api_wps_handler(request: Request<&mut EspHttpConnection>) -> Result<(), HandlerError> {
+    CMD_QUEUE.enqueue(Command::StartWps)?;
+    request.into_response(202, None, &[])?;
+    Ok(())
+}
+
+pub fn api_fleet_handler(request: Request<&mut EspHttpConnection>) -> Result<(), HandlerError> {
+    json_response(request, 200, &fleet::status())
+}
+
+pub fn api_fleet_key_handler<T>(
+    mut request: Request<&mut EspHttpConnection>,
+    wifi_storage: &Mutex<WifiStorage<T>>,
+) -> Result<(), HandlerError>
+where
+    T: NvsPartitionId,
+{
+    #[derive(serde::Deserialize)]
+    struct FleetKeyApi {
+        /// Passphrase the key is derived from, `null` removes the key
+        key: Option<String>,
+    }
+
+    let body = read_body(&mut request)?;
+    let settings: FleetKeyApi = match serde_json::from_slice(&body) {
+        Ok(settings) => settings,
+        Err(err) => {
+            return error_response(request, 400, &format!("invalid request body: {err}"));
+        }
+    };
+    let key = match settings.key.as_deref().map(fleet::key_from_passphrase) {
+        None => None,
+        Some(Ok(key)) => Some(key),
+        Some(Err(invalid)) => return invalid_settings_response(request, invalid),
+    };
+    wifi_storage.lock().unwrap().set_fleet_key(key.as_ref())?;
+    info!(
+        "fleet key {}",
+        if key.is_some() { "set" } else { "removed" }
+    );
+    fleet::set_key(key);
+    json_response(request, 200, &fleet::status())
+}
+
+pub fn api_fleet_push_handler<T>(
+    request: Request<&mut EspHttpConnection>,
+    wifi_storage: &Mutex<WifiStorage<T>>,
+) -> Result<(), HandlerError>
+where
+    T: NvsPartitionId,
+{
+    let info = wifi_storage.lock().unwrap().get_info()?;
+    if let Err(err) = fleet::push(info.sta_config.as_ref()) {
+        return error_response(request, 422, &err.to_string());
+    }
+    json_response(request, 202, &fleet::status())
+}
+
+pub fn confirm_handler(request: Request<&mut EspHttpConnection>) -> Result<(), HandlerError> {
+    CMD_QUEUE.enqueue(Command::ConfirmWifi)?;
+    request.into_ok_response()?;
+    Ok(())
+}
+
+pub fn status_handler(
+    request: Request<&mut EspHttpConnection>,
+    recovery: Option<Recovery>,
+    tls_fingerprint: Option<&str>,
+) -> Result<(), HandlerError> {
+    #[derive(serde::Serialize)]
+    struct StatusApi<'a> {
+        recovery: Option<Recovery>,
+        tls_fingerprint: Option<&'a str>,
+    }
+
+    let json = serde_json::to_vec(&StatusApi {
+        recovery,
+        tls_fingerprint,
+    })?;
+    let mut response =
+        request.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?;
+    response.write_all(&json)?;
+    Ok(())
+}
+
+/// Lists the networks in range, the query takes the parameters of
+/// [`ScanOptions::from_query`].
+pub fn scan_handler<T: NvsPartitionId>(
+    request: Request<&mut EspHttpConnection>,
+    wifi_storage: &Mutex<WifiStorage<T>>,
+) -> Result<(), HandlerError> {
     use embedded_svc::wifi::AccessPointInfo;
 
-    let buf = wifi::scan_aps()?;
+    let query = request.uri().split_once('?').map_or("", |(_, query)| query);
+    let options = match ScanOptions::from_query(query) {
+        Ok(options) => options,
+        Err(message) => return error_response(request, 400, message),
+    };
+    let allowed = wifi_storage
+        .lock()
+        .unwrap()
+        .get_info()?
+        .country
+        .scan_channels();
+    if !options
+        .channels
+        .iter()
+        .all(|channel| allowed.contains(channel))
+    {
+        return error_response(request, 400, "channels are not allowed in the country");
+    }
+    let buf = wifi::scan(&options)?;
     let aps: Vec<_> = buf.into_iter().map(APInfo).collect();
 
     #[derive(serde::Serialize)]
@@ -57,11 +678,7 @@ pub fn scan_handler(request: Request<&mut EspHttpConnection>) -> Result<(), Hand
         use serde::ser::SerializeStruct;
         let mut state = serializer.serialize_struct("AccessPointInfo", 5)?;
         state.serialize_field("ssid", &data.ssid)?;
-        let [a, b, c, d, e, f] = data.bssid;
-        state.serialize_field(
-            "bssid",
-            &format!("{a:02X}:{b:02X}:{c:02X}:{d:02X}:{e:02X}:{f:02X}"),
-        )?;
+        state.serialize_field("bssid", &convert::format_mac(&data.bssid))?;
 
         state.serialize_field("channel", &data.channel)?;
         state.serialize_field("rssi", &data.signal_strength)?;
@@ -76,3 +693,239 @@ pub fn scan_handler(request: Request<&mut EspHttpConnection>) -> Result<(), Hand
     response.write_all(&json)?;
     Ok(())
 }
+
+pub fn ota_page_handler(
+    request: Request<&mut EspHttpConnection>,
+    csrf: &CsrfToken,
+) -> Result<(), HandlerError> {
+    let template = OtaTemplate {
+        version: env!("CARGO_PKG_VERSION"),
+        partition: EspOta::new()?.get_running_slot()?.label.to_string(),
+        csrf_token: csrf.as_str().to_owned(),
+    };
+    let mut response = request.into_ok_response()?;
+    response.write_all(template.render()?.as_bytes())?;
+    Ok(())
+}
+
+/// Streams the request body into the inactive OTA partition and reboots into
+/// it. The optional `sha256` query parameter is checked against the image.
+pub fn ota_handler(mut request: Request<&mut EspHttpConnection>) -> Result<(), HandlerError> {
+    let sha256 = match query_param(request.uri(), "sha256").filter(|hex| !hex.is_empty()) {
+        Some(hex) => match parse_hex(hex) {
+            Some(digest) => Some(digest),
+            None => return error_response(request, 400, "sha256 must be 64 hex digits"),
+        },
+        None => None,
+    };
+    let len = request.content_len().unwrap_or_default() as usize;
+
+    let mut ota = EspOta::new()?;
+    let mut upload = match ota::Upload::begin(&mut ota, len, sha256) {
+        Ok(upload) => upload,
+        Err(err) => return ota_error_response(request, err),
+    };
+    info!("receiving firmware update ({len} bytes)");
+
+    let mut buf = vec![0; 4096];
+    loop {
+        let read = match request.read(&mut buf) {
+            Ok(read) => read,
+            Err(err) => {
+                upload.abort()?;
+                return Err(err.into());
+            }
+        };
+        if read == 0 {
+            break;
+        }
+        if let Err(err) = upload.write(&buf[..read]) {
+            upload.abort()?;
+            return ota_error_response(request, err);
+        }
+    }
+    if let Err(err) = upload.complete() {
+        return ota_error_response(request, err);
+    }
+
+    info!("firmware update complete, rebooting");
+    request.into_ok_response()?;
+    CMD_QUEUE.enqueue(Command::Reboot)?;
+    Ok(())
+}
+
+pub fn tls_page_handler(
+    request: Request<&mut EspHttpConnection>,
+    tls: &SharedTls,
+    csrf: &CsrfToken,
+) -> Result<(), HandlerError> {
+    let tls = tls.lock().unwrap();
+    let template = TlsTemplate {
+        enabled: tls.is_enabled()?,
+        fingerprint: tls
+            .get_identity()?
+            .and_then(|identity| identity.fingerprint())
+            .map(|fingerprint| tls::format_fingerprint(&fingerprint))
+            .unwrap_or_default(),
+        csrf_token: csrf.as_str().to_owned(),
+    };
+    let mut response = request.into_ok_response()?;
+    response.write_all(template.render()?.as_bytes())?;
+    Ok(())
+}
+
+/// Switches HTTPS on or off and optionally replaces the generated certificate
+/// with an uploaded one. Both only take effect after the reboot that follows.
+pub fn tls_handler(
+    mut request: Request<&mut EspHttpConnection>,
+    tls: &SharedTls,
+) -> Result<(), HandlerError> {
+    #[derive(serde::Deserialize)]
+    struct TlsApi {
+        enabled: bool,
+        #[serde(default)]
+        certificate: Option<String>,
+        #[serde(default)]
+        private_key: Option<String>,
+    }
+
+    let TlsApi {
+        enabled,
+        certificate,
+        private_key,
+    } = match serde_json::from_slice(&read_body(&mut request)?) {
+        Ok(settings) => settings,
+        Err(err) => {
+            return error_response(request, 400, &format!("invalid request body: {err}"));
+        }
+    };
+    let mut tls = tls.lock().unwrap();
+    match (
+        certificate.filter(|pem| !pem.trim().is_empty()),
+        private_key.filter(|pem| !pem.trim().is_empty()),
+    ) {
+        (Some(certificate), Some(private_key)) => match Identity::parse(certificate, private_key) {
+            Ok(identity) => {
+                tls.set_identity(&identity)?;
+                info!("uploaded TLS certificate stored");
+            }
+            Err(err) => {
+                let message = format!("invalid certificate or private key: {err}");
+                return error_response(request, 400, &message);
+            }
+        },
+        (None, None) => {}
+        _ => {
+            let message = "certificate and private key have to be uploaded together";
+            return error_response(request, 400, message);
+        }
+    }
+    tls.set_enabled(enabled)?;
+
+    info!(
+        "HTTPS {}, rebooting",
+        if enabled { "enabled" } else { "disabled" }
+    );
+    request.into_ok_response()?;
+    CMD_QUEUE.enqueue(Command::Reboot)?;
+    Ok(())
+}
+
+fn ota_error_response(
+    request: Request<&mut EspHttpConnection>,
+    err: OtaError,
+) -> Result<(), HandlerError> {
+    warn!("firmware update failed: {err}");
+    let status = match err {
+        OtaError::Busy => 409,
+        OtaError::InvalidImage(_) | OtaError::ChecksumMismatch => 400,
+        OtaError::Esp(_) => 500,
+    };
+    error_response(request, status, &err.to_string())
+}
+
+fn read_body(request: &mut Request<&mut EspHttpConnection>) -> Result<Vec<u8>, HandlerError> {
+    let len = request.content_len().unwrap_or_default();
+    let mut buf = vec![0; len as usize];
+    request.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn error_response(
+    request: Request<&mut EspHttpConnection>,
+    status: u16,
+    message: &str,
+) -> Result<(), HandlerError> {
+    error_response_with(request, status, &[], None, message)
+}
+
+fn invalid_settings_response(
+    request: Request<&mut EspHttpConnection>,
+    invalid: InvalidSettings,
+) -> Result<(), HandlerError> {
+    warn!("rejecting invalid settings: {invalid}");
+    error_response_with(request, 422, &[], Some(invalid.field), invalid.message)
+}
+
+/// Answers with a JSON [`api::ErrorBody`] below [`api::PREFIX`] and with
+/// plain text everywhere else.
+fn error_response_with(
+    request: Request<&mut EspHttpConnection>,
+    status: u16,
+    headers: &[(&str, &str)],
+    field: Option<&str>,
+    message: &str,
+) -> Result<(), HandlerError> {
+    let (content_type, body) = if request.uri().starts_with(api::PREFIX) {
+        let body = serde_json::to_vec(&api::ErrorBody {
+            error: message,
+            field,
+        })?;
+        ("application/json", body)
+    } else {
+        let body = match field {
+            Some(field) => format!("{field}: {message}"),
+            None => message.to_owned(),
+        };
+        ("text/plain", body.into_bytes())
+    };
+    let headers: Vec<_> = [("Content-Type", content_type)]
+        .into_iter()
+        .chain(headers.iter().copied())
+        .collect();
+    let mut response = request.into_response(status, None, &headers)?;
+    response.write_all(&body)?;
+    Ok(())
+}
+
+fn json_response<S: serde::Serialize>(
+    request: Request<&mut EspHttpConnection>,
+    status: u16,
+    body: &S,
+) -> Result<(), HandlerError> {
+    let json = serde_json::to_vec(body)?;
+    let mut response =
+        request.into_response(status, None, &[("Content-Type", "application/json")])?;
+    response.write_all(&json)?;
+    Ok(())
+}
+
+/// Returns the (undecoded) value of the query parameter `name`.
+fn query_param<'a>(uri: &'a str, name: &str) -> Option<&'a str> {
+    let (_, query) = uri.split_once('?')?;
+    query.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        (key == name).then_some(value)
+    })
+}
+
+fn parse_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    if hex.len() != 2 * N {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (byte, digits) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(core::str::from_utf8(digits).ok()?, 16).ok()?;
+    }
+    Some(bytes)
+}
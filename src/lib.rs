@@ -0,0 +1,52 @@
+//! Parts of the firmware that build without ESP-IDF
+//!
+//! The firmware binary uses them from here, so they also build on the host
+//! and their tests run there. `embedded-svc` needs a nightly toolchain for
+//! that, and the `.cargo/config.toml` of the checkout has to be left out, as
+//! it selects the ESP32 target and builds `std` from source for it. Cargo only
+//! reads that file below the working directory, so run the tests from outside
+//! the checkout:
+//!
+//! ```text
+//! cd .. && cargo +nightly test --lib --manifest-path soft-ap/Cargo.toml
+//! ```
+//!
+//! with the directory of the checkout in place of `soft-ap`. The few pieces
+//! that need ESP-IDF, like the link sampler, are only built for the device.
+
+pub mod animation;
+pub mod button;
+pub mod convert;
+pub mod link;
+pub mod menu;
+pub mod ring;
+
+pub mod api {
+    pub mod settings;
+}
+
+pub mod console {
+    pub mod command;
+}
+
+pub mod fleet {
+    pub mod frame;
+}
+
+pub mod syslog {
+    pub mod message;
+}
+
+pub mod template {
+    pub mod password;
+}
+
+pub mod wifi {
+    pub mod ap_mode;
+    pub mod channel;
+    pub mod country;
+    pub mod info;
+    pub mod roaming;
+    pub mod scan;
+    pub mod security;
+}
@@ -0,0 +1,119 @@
+//! History of the quality of the station link, to find spots where the
+//! device has bad reception
+//!
+//! A thread samples the connection every [`INTERVAL`] into a ring buffer
+//! that is read through the API, the portal and the on-device menu. The
+//! sampling itself is only built for the device.
+
+use crate::ring::Ring;
+use core::{ops::RangeInclusive, time::Duration};
+#[cfg(target_os = "espidf")]
+use esp_idf_sys as sys;
+use std::sync::Mutex;
+#[cfg(target_os = "espidf")]
+use std::thread;
+
+pub const INTERVAL: Duration = Duration::from_secs(10);
+/// Samples kept in the ring buffer, one hour
+const CAPACITY: usize = 360;
+
+/// Signal strengths shown in charts, in dBm
+pub const RSSI_RANGE: RangeInclusive<i8> = -95..=-30;
+
+static RING: Mutex<Ring<Sample, CAPACITY>> = Mutex::new(Ring::new());
+
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct Sample {
+    pub uptime_secs: u32,
+    /// Signal of the access point in dBm, `None` while not connected
+    pub rssi: Option<i8>,
+    pub channel: Option<u8>,
+    /// Fastest mode the access point offers. ESP-IDF 4.4 does not report the
+    /// bit rate of the link, the mode bounds it.
+    pub phy: Option<Phy>,
+    /// Disconnects since the previous sample
+    pub disconnects: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub enum Phy {
+    #[serde(rename = "11b")]
+    B,
+    #[serde(rename = "11g")]
+    G,
+    #[serde(rename = "11n")]
+    N,
+    /// Espressif long range mode
+    #[serde(rename = "lr")]
+    LongRange,
+}
+
+/// Returns the samples starting at sequence number `since`, oldest first,
+/// together with the cursor for the next call. The samples are consecutive,
+/// the first one has the number `next - samples.len()`.
+pub fn samples(since: u64) -> (Vec<Sample>, u64) {
+    let ring = RING.lock().unwrap();
+    let skip = since.saturating_sub(ring.first_seq()) as usize;
+    (ring.iter().skip(skip).copied().collect(), ring.next_seq())
+}
+
+/// The newest `count` samples, oldest first
+pub fn recent(count: usize) -> Vec<Sample> {
+    let ring = RING.lock().unwrap();
+    let mut samples: Vec<_> = ring.iter().rev().take(count).copied().collect();
+    samples.reverse();
+    samples
+}
+
+/// Starts sampling the station link. `disconnect_count` returns the station
+/// disconnects since boot.
+#[cfg(target_os = "espidf")]
+pub fn spawn(disconnect_count: fn() -> u32) -> std::io::Result<()> {
+    thread::Builder::new()
+        .name("link".into())
+        .stack_size(4 * 1024)
+        .spawn(move || run(disconnect_count))?;
+    Ok(())
+}
+
+#[cfg(target_os = "espidf")]
+fn run(disconnect_count: fn() -> u32) {
+    let mut disconnects = disconnect_count();
+    loop {
+        thread::sleep(INTERVAL);
+
+        let total = disconnect_count();
+        let sample = sample(total.wrapping_sub(disconnects));
+        disconnects = total;
+        RING.lock().unwrap().push(sample);
+    }
+}
+
+#[cfg(target_os = "espidf")]
+fn sample(disconnects: u32) -> Sample {
+    let mut record = sys::wifi_ap_record_t::default();
+    // Fails if the station is not connected
+    let connected = sys::esp!(unsafe { sys::esp_wifi_sta_get_ap_info(&mut record) }).is_ok();
+    Sample {
+        uptime_secs: (unsafe { sys::esp_timer_get_time() } / 1_000_000) as u32,
+        rssi: connected.then_some(record.rssi),
+        channel: connected.then_some(record.primary),
+        phy: connected.then(|| phy(&record)).flatten(),
+        disconnects: disconnects.min(u8::MAX.into()) as u8,
+    }
+}
+
+#[cfg(target_os = "espidf")]
+fn phy(record: &sys::wifi_ap_record_t) -> Option<Phy> {
+    if record.phy_11n() != 0 {
+        Some(Phy::N)
+    } else if record.phy_11g() != 0 {
+        Some(Phy::G)
+    } else if record.phy_11b() != 0 {
+        Some(Phy::B)
+    } else if record.phy_lr() != 0 {
+        Some(Phy::LongRange)
+    } else {
+        None
+    }
+}
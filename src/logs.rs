@@ -0,0 +1,124 @@
+//! Logger that keeps the most recent records in RAM, so they can be read
+//! through the API instead of a serial cable.
+
+use crate::events::{self, Event};
+use esp_idf_svc::log::EspLogger;
+use esp_idf_sys as sys;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use soft_ap::ring::Ring;
+use std::sync::Mutex;
+
+/// Records kept in the ring buffer
+const CAPACITY: usize = 64;
+/// Longer messages are truncated in the ring buffer (not on UART)
+const MAX_MESSAGE_LEN: usize = 200;
+
+static RING: Mutex<Ring<Entry, CAPACITY>> = Mutex::new(Ring::new());
+/// Runtime overrides of the log level per module (log target prefix)
+static MODULE_LEVELS: Mutex<Vec<(String, LevelFilter)>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Entry {
+    /// Increases by one for every record, usable as a cursor
+    pub seq: u64,
+    pub uptime_ms: u64,
+    #[serde(serialize_with = "serialize_level")]
+    pub level: Level,
+    pub target: String,
+    pub message: String,
+}
+
+fn serialize_level<S: serde::Serializer>(level: &Level, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(level.as_str())
+}
+
+/// Returns the buffered records starting at sequence number `since` that
+/// pass `level`, together with the cursor for the next call.
+pub fn entries(since: u64, level: LevelFilter) -> (Vec<Entry>, u64) {
+    let ring = RING.lock().unwrap();
+    let entries = ring
+        .iter()
+        .filter(|entry| entry.seq >= since && entry.level <= level)
+        .cloned()
+        .collect();
+    (entries, ring.next_seq())
+}
+
+pub fn module_levels() -> Vec<(String, LevelFilter)> {
+    MODULE_LEVELS.lock().unwrap().clone()
+}
+
+/// Overrides the level of `module` and everything below it, `None` restores
+/// the default.
+pub fn set_module_level(module: &str, level: Option<LevelFilter>) {
+    let mut levels = MODULE_LEVELS.lock().unwrap();
+    levels.retain(|(other, _)| other != module);
+    if let Some(level) = level {
+        levels.push((module.to_owned(), level));
+    }
+}
+
+/// Level override of the most specific module `target` belongs to
+fn module_level(target: &str) -> Option<LevelFilter> {
+    MODULE_LEVELS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(module, _)| {
+            target
+                .strip_prefix(module.as_str())
+                .map_or(false, |rest| rest.is_empty() || rest.starts_with("::"))
+        })
+        .max_by_key(|(module, _)| module.len())
+        .map(|(_, level)| *level)
+}
+
+/// Logs through [`EspLogger`] and tees the records into the ring buffer and
+/// the event stream.
+pub struct Logger(pub EspLogger);
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match module_level(metadata.target()) {
+            Some(level) => metadata.level() <= level,
+            None => self.0.enabled(metadata),
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        self.0.log(record);
+
+        let mut message = record.args().to_string();
+        if message.len() > MAX_MESSAGE_LEN {
+            let mut end = MAX_MESSAGE_LEN;
+            while !message.is_char_boundary(end) {
+                end -= 1;
+            }
+            message.truncate(end);
+        }
+        if record.level() <= Level::Info {
+            events::publish(Event::Log {
+                level: record.level().as_str(),
+                target: record.target().to_owned(),
+                message: message.clone(),
+            });
+        }
+
+        let mut ring = RING.lock().unwrap();
+        let seq = ring.next_seq();
+        ring.push(Entry {
+            seq,
+            uptime_ms: (unsafe { sys::esp_timer_get_time() } / 1000) as u64,
+            level: record.level(),
+            target: record.target().to_owned(),
+            message,
+        });
+    }
+
+    fn flush(&self) {
+        self.0.flush();
+    }
+}
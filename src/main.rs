@@ -1,44 +1,100 @@
-mod animation;
-mod convert;
+mod api;
+mod auth;
+mod boot;
+mod console;
+mod diagnostics;
+mod events;
+mod fleet;
 mod http;
+mod logs;
+mod ota;
+mod provisioning;
+mod syslog;
 mod template;
+mod tls;
 mod wifi;
+mod wps;
 
 use crate::{
-    animation::Loader,
-    template::WifiSettingsTemplate,
-    wifi::{storage::WifiStorage, update_wifi},
+    api::Resource,
+    auth::{Auth, SharedAuth},
+    boot::{BootCounter, Recovery, SharedRecovery},
+    events::{ApplyState, Event, WpsState},
+    http::CsrfToken,
+    logs::Logger,
+    tls::{self, Identity, RedirectServer, SharedTls, TlsStorage},
+    wifi::{
+        self, default_ap_config, roaming, scan_aps, storage::WifiStorage, update_wifi, ApMode,
+        PendingUpdate, WifiInfo,
+    },
 };
 use core::time::Duration;
 use embedded_graphics::{
     image::{Image, ImageRaw},
     mono_font::{ascii::FONT_6X10, MonoTextStyleBuilder},
     pixelcolor::BinaryColor,
-    prelude::Point,
-    primitives::PrimitiveStyle,
+    prelude::{Point, Size},
+    primitives::{PrimitiveStyle, Rectangle},
     text::{Baseline, Text},
     Drawable,
 };
-use embedded_svc::http::Method;
-use esp_idf_hal::{i2c::I2cDriver, prelude::Peripherals};
-use esp_idf_svc::{eventloop::EspSystemEventLoop, http::server::EspHttpServer, nvs, wifi::EspWifi};
-use log::info;
+use embedded_svc::{http::Method, ipv4::Ipv4Addr, wifi::Configuration};
+use esp_idf_hal::{
+    gpio::{PinDriver, Pull},
+    i2c::I2cDriver,
+    prelude::Peripherals,
+};
+use esp_idf_svc::{
+    eventloop::EspSystemEventLoop,
+    http::server::EspHttpServer,
+    log::EspLogger,
+    netif::IpEvent,
+    nvs,
+    wifi::{EspWifi, WifiEvent},
+};
+use log::{error, info, warn};
+use soft_ap::{
+    animation::{Loader, ProgressBar},
+    button::Button,
+    convert, link,
+    menu::{Action, Menu},
+};
 use ssd1306::{
     prelude::DisplayConfig, rotation::DisplayRotation, size::DisplaySize128x64,
     I2CDisplayInterface, Ssd1306,
 };
+use std::sync::{Arc, Mutex};
 // If using the `binstart` feature of `esp-idf-sys`, always keep this module imported
 use esp_idf_sys as sys;
 
-static CMD_QUEUE: heapless::mpmc::Q2<Command> = heapless::mpmc::Q2::new();
+/// Holding the button for longer than this starts the factory reset countdown
+const FACTORY_RESET_ARM: Duration = Duration::from_secs(2);
+/// Holding the button for this long wipes the settings and reboots
+const FACTORY_RESET_HOLD: Duration = Duration::from_secs(7);
+/// Pause between the scans for the saved network while it is out of reach
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(15);
+/// Pause between the checks of the signal while roaming is monitored
+const ROAMING_INTERVAL: Duration = Duration::from_secs(10);
+
+static LOGGER: Logger = Logger(EspLogger);
+
+static CMD_QUEUE: heapless::mpmc::Q4<Command> = heapless::mpmc::Q4::new();
 
 #[derive(Debug, Clone)]
 enum Command {
-    UpdateWifi(WifiSettingsTemplate),
+    UpdateWifi(WifiInfo),
+    /// Changes only the station settings, keeping everything else as stored
+    UpdateClient(api::ClientSettings),
+    ConfirmWifi,
+    /// Connects to a router in WPS push button mode
+    StartWps,
+    SetApMode(ApMode),
+    Reboot,
+    FactoryReset,
 }
 
 fn main() -> anyhow::Result<()> {
-    esp_idf_svc::log::EspLogger::initialize_default();
+    log::set_logger(&LOGGER).unwrap();
     log::set_max_level(log::LevelFilter::Trace);
     // It is necessary to call this function once. Otherwise some patches to the runtime
     // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
@@ -52,25 +108,344 @@ fn main() -> anyhow::Result<()> {
     let nvs_partition = nvs::EspDefaultNvsPartition::take()?;
 
     info!("Load WiFi settings from NVS");
-    let mut wifi_storage = WifiStorage::new(nvs_partition.clone())?;
-    let wifi_info = wifi_storage.get_info()?;
+    let wifi_storage = WifiStorage::new(nvs_partition.clone())?;
+    let mut boot_counter = BootCounter::new(nvs_partition.clone())?;
+    let recovery = boot_counter.begin()?;
+    let wifi_info = match recovery {
+        Some(recovery) => {
+            warn!("skipping stored WiFi settings, starting recovery AP: {recovery}");
+            Recovery::wifi_info()
+        }
+        None => wifi_storage.get_info()?,
+    };
 
     let sysloop = EspSystemEventLoop::take()?;
     let mut wifi = EspWifi::new(modem, sysloop.clone(), Some(nvs_partition.clone()))?;
 
     info!("Wifi capabilities: {:?}", wifi.get_capabilities()?);
+    diagnostics::track_disconnects()?;
+    link::spawn(diagnostics::disconnect_count)?;
+    events::spawn_sender()?;
+    syslog::configure(wifi_storage.get_syslog()?);
+    syslog::spawn()?;
+    let _wifi_events = sysloop.subscribe(|event: &WifiEvent| {
+        events::publish(Event::Network {
+            event: format!("{event:?}"),
+        })
+    })?;
+    let _ip_events = sysloop.subscribe(|event: &IpEvent| {
+        events::publish(Event::Network {
+            event: format!("{event:?}"),
+        })
+    })?;
     update_wifi(&mut wifi, &sysloop, wifi_info)?;
+    let auth: SharedAuth = Arc::new(Mutex::new(Auth::new(nvs_partition.clone())?));
+
+    info!("Load TLS certificate from NVS");
+    let mut tls_storage = TlsStorage::new(nvs_partition.clone())?;
+    let identity = match tls_storage.get_identity()? {
+        Some(identity) => identity,
+        None => {
+            info!("generating self-signed TLS certificate");
+            let identity = Identity::generate(&default_ap_config().ssid)?;
+            tls_storage.set_identity(&identity)?;
+            identity
+        }
+    };
+    let tls_fingerprint = identity.fingerprint();
+    let https = tls_storage.is_enabled()?;
+    let tls: SharedTls = Arc::new(Mutex::new(tls_storage));
+
+    let mut app = App {
+        wifi,
+        sysloop,
+        wifi_storage,
+        auth: auth.clone(),
+        tls: tls.clone(),
+        tls_fingerprint,
+        pending: None,
+        wps_deadline: None,
+        reconnect_at: None,
+        roaming_at: None,
+        transition_requested: false,
+        steered_at: None,
+        recovery: Arc::new(Mutex::new(recovery)),
+    };
+    // Offer BLE provisioning as long as there is no network to connect to
+    if recovery.is_some() || !app.has_sta_config()? {
+        if let Err(err) = provisioning::start() {
+            warn!("BLE provisioning not available: {err}");
+        }
+    }
+
+    let fleet_key = app.wifi_storage.get_fleet_key()?;
+    let follow = fleet_key.is_some() && (recovery.is_some() || !app.has_sta_config()?);
+    match fleet::init(fleet_key) {
+        Ok(()) if follow => fleet::follow(),
+        Ok(()) => {}
+        Err(err) => warn!("ESP-NOW fleet configuration not available: {err}"),
+    }
 
     // TODO: implement [`Captive Portal`](https://gitlab.com/defcronyke/wifi-captive-portal-esp-idf).
     // This requires that we need a simple DNS server.
-    let mut http_server = EspHttpServer::new(&Default::default())?;
+    let mut http_config = esp_idf_svc::http::server::Configuration {
+        max_uri_handlers: 48,
+        ..Default::default()
+    };
+    if https {
+        info!("serving settings over HTTPS");
+        let (certificate, private_key) = identity.into_x509();
+        // The TLS handshake needs a lot more stack than plain HTTP
+        http_config.stack_size = 10240;
+        http_config.server_certificate = Some(certificate);
+        http_config.private_key = Some(private_key);
+    }
+    let mut http_server = EspHttpServer::new(&http_config)?;
+    let _redirect_server = if https {
+        Some(RedirectServer::start()?)
+    } else {
+        None
+    };
+    const JSON: &str = "application/json";
+    let csrf = CsrfToken::generate();
+    let http_storage = Arc::new(Mutex::new(WifiStorage::new(nvs_partition)?));
+    console::spawn(http_storage.clone(), app.recovery.clone())?;
     http_server
-        .fn_handler("/", Method::Get, {
-            let wifi_storage = WifiStorage::new(nvs_partition)?;
-            move |request| http::root_handler(request, &wifi_storage)
+        .fn_handler(
+            "/",
+            Method::Get,
+            http::authenticated(&auth, {
+                let wifi_storage = http_storage.clone();
+                let csrf = csrf.clone();
+                move |request| http::root_handler(request, &wifi_storage, &csrf)
+            }),
+        )?
+        .fn_handler(
+            "/json/net",
+            Method::Get,
+            http::authenticated(&auth, {
+                let wifi_storage = http_storage.clone();
+                move |request| http::scan_handler(request, &wifi_storage)
+            }),
+        )?
+        .fn_handler(
+            "/json/status",
+            Method::Get,
+            http::authenticated(&auth, {
+                let recovery = app.recovery.clone();
+                let tls_fingerprint =
+                    tls_fingerprint.map(|fingerprint| tls::format_fingerprint(&fingerprint));
+                move |request| {
+                    let recovery = *recovery.lock().unwrap();
+                    http::status_handler(request, recovery, tls_fingerprint.as_deref())
+                }
+            }),
+        )?
+        .fn_handler(
+            "/json/csrf",
+            Method::Get,
+            http::authenticated(&auth, {
+                let csrf = csrf.clone();
+                move |request| http::csrf_handler(request, &csrf)
+            }),
+        )?
+        .fn_handler(
+            "/",
+            Method::Post,
+            http::authenticated(
+                &auth,
+                http::csrf_protected(&csrf, JSON, {
+                    let wifi_storage = http_storage.clone();
+                    move |request| http::post_handler(request, &wifi_storage)
+                }),
+            ),
+        )?
+        .fn_handler(
+            "/confirm",
+            Method::Post,
+            http::authenticated(
+                &auth,
+                http::csrf_protected(&csrf, JSON, http::confirm_handler),
+            ),
+        )?
+        .fn_handler(
+            "/ota",
+            Method::Get,
+            http::authenticated(&auth, {
+                let csrf = csrf.clone();
+                move |request| http::ota_page_handler(request, &csrf)
+            }),
+        )?
+        .fn_handler(
+            "/api/ota",
+            Method::Post,
+            http::authenticated(
+                &auth,
+                http::csrf_protected(&csrf, "application/octet-stream", http::ota_handler),
+            ),
+        )?
+        .fn_handler(
+            "/tls",
+            Method::Get,
+            http::authenticated(&auth, {
+                let tls = tls.clone();
+                let csrf = csrf.clone();
+                move |request| http::tls_page_handler(request, &tls, &csrf)
+            }),
+        )?
+        .fn_handler(
+            "/api/tls",
+            Method::Post,
+            http::authenticated(
+                &auth,
+                http::csrf_protected(&csrf, JSON, move |request| http::tls_handler(request, &tls)),
+            ),
+        )?
+        .fn_handler("/setup", Method::Get, {
+            let auth = auth.clone();
+            let csrf = csrf.clone();
+            move |request| http::setup_page_handler(request, &auth, &csrf)
         })?
-        .fn_handler("/json/net", Method::Get, http::scan_handler)?
-        .fn_handler("/", Method::Post, http::post_handler)?;
+        .fn_handler(
+            "/setup",
+            Method::Post,
+            http::csrf_protected(&csrf, JSON, {
+                let auth = auth.clone();
+                move |request| http::setup_handler(request, &auth)
+            }),
+        )?
+        .ws_handler("/api/events", {
+            let csrf = csrf.clone();
+            move |ws| events::handle_ws(ws, &csrf)
+        })?;
+
+    serve_resource::<api::ClientSettings>(&mut http_server, "client", &auth, &csrf, &http_storage)?;
+    serve_resource::<api::ApSettings>(&mut http_server, "ap", &auth, &csrf, &http_storage)?;
+    serve_resource::<api::IpSettings>(&mut http_server, "ip", &auth, &csrf, &http_storage)?;
+    serve_resource::<api::RoamingSettings>(
+        &mut http_server,
+        "roaming",
+        &auth,
+        &csrf,
+        &http_storage,
+    )?;
+    http_server
+        .fn_handler(
+            &format!("{}/device", api::PREFIX),
+            Method::Get,
+            http::authenticated(&auth, move |request| {
+                http::api_device_handler(request, https)
+            }),
+        )?
+        .fn_handler(
+            &format!("{}/status", api::PREFIX),
+            Method::Get,
+            http::authenticated(&auth, {
+                let recovery = app.recovery.clone();
+                move |request| {
+                    let recovery = *recovery.lock().unwrap();
+                    http::api_status_handler(request, recovery)
+                }
+            }),
+        )?
+        .fn_handler(
+            &format!("{}/logs", api::PREFIX),
+            Method::Get,
+            http::authenticated(&auth, http::api_logs_handler),
+        )?
+        .fn_handler(
+            &format!("{}/link", api::PREFIX),
+            Method::Get,
+            http::authenticated(&auth, http::api_link_handler),
+        )?
+        .fn_handler(
+            &format!("{}/log-levels", api::PREFIX),
+            Method::Get,
+            http::authenticated(&auth, http::api_log_levels_handler),
+        )?
+        .fn_handler(
+            &format!("{}/log-levels", api::PREFIX),
+            Method::Patch,
+            http::authenticated(
+                &auth,
+                http::csrf_protected(&csrf, JSON, http::api_set_log_levels_handler),
+            ),
+        )?
+        .fn_handler(
+            &format!("{}/wps", api::PREFIX),
+            Method::Post,
+            http::authenticated(
+                &auth,
+                http::csrf_protected(&csrf, JSON, http::api_wps_handler),
+            ),
+        )?
+        .fn_handler(
+            &format!("{}/fleet", api::PREFIX),
+            Method::Get,
+            http::authenticated(&auth, http::api_fleet_handler),
+        )?
+        .fn_handler(
+            &format!("{}/fleet/key", api::PREFIX),
+            Method::Put,
+            http::authenticated(
+                &auth,
+                http::csrf_protected(&csrf, JSON, {
+                    let wifi_storage = http_storage.clone();
+                    move |request| http::api_fleet_key_handler(request, &wifi_storage)
+                }),
+            ),
+        )?
+        .fn_handler(
+            &format!("{}/fleet/push", api::PREFIX),
+            Method::Post,
+            http::authenticated(
+                &auth,
+                http::csrf_protected(&csrf, JSON, {
+                    let wifi_storage = http_storage.clone();
+                    move |request| http::api_fleet_push_handler(request, &wifi_storage)
+                }),
+            ),
+        )?
+        .fn_handler(
+            &format!("{}/config/export", api::PREFIX),
+            Method::Get,
+            http::authenticated(&auth, {
+                let wifi_storage = http_storage.clone();
+                move |request| http::api_config_export_handler(request, &wifi_storage)
+            }),
+        )?
+        .fn_handler(
+            &format!("{}/config/import", api::PREFIX),
+            Method::Post,
+            http::authenticated(
+                &auth,
+                http::csrf_protected(&csrf, JSON, {
+                    let wifi_storage = http_storage.clone();
+                    move |request| http::api_config_import_handler(request, &wifi_storage)
+                }),
+            ),
+        )?
+        .fn_handler(
+            "/config",
+            Method::Get,
+            http::authenticated(&auth, {
+                let csrf = csrf.clone();
+                move |request| http::config_page_handler(request, &csrf)
+            }),
+        )?
+        .fn_handler(
+            "/logs",
+            Method::Get,
+            http::authenticated(&auth, {
+                let csrf = csrf.clone();
+                move |request| http::logs_page_handler(request, &csrf)
+            }),
+        )?
+        .fn_handler(
+            &format!("{}/openapi.json", api::PREFIX),
+            Method::Get,
+            http::api_openapi_handler,
+        )?;
 
     // Setup SSD1306 Display
     let display_driver = I2cDriver::new(
@@ -90,6 +465,24 @@ fn main() -> anyhow::Result<()> {
         .text_color(BinaryColor::On)
         .build();
     let message = Text::with_baseline("Hello Rust!", Point::zero(), text_style, Baseline::Top);
+    let recovery_message = recovery.map(|recovery| {
+        format!(
+            "Recovery mode\n{} failed boots\nReset: {}\nAP: {}",
+            recovery.failed_boots,
+            recovery.reset_reason,
+            default_ap_config().ssid
+        )
+    });
+
+    // The BOOT button doubles as the input for the on-device menu
+    let mut boot_button = PinDriver::input(pins.gpio0)?;
+    boot_button.set_pull(Pull::Up)?;
+    let mut button = Button::default();
+    let mut menu = Menu::default();
+    let mut progress_bar = ProgressBar::new(
+        Rectangle::new(Point::new(4, 40), Size::new(120, 12)),
+        BinaryColor::On,
+    );
 
     let raw = ImageRaw::<BinaryColor>::new(include_bytes!("../assets/rust.raw"), 64);
     let img = Image::new(&raw, Point::new(32, 0));
@@ -99,30 +492,616 @@ fn main() -> anyhow::Result<()> {
         PrimitiveStyle::with_stroke(BinaryColor::On, 2),
     );
 
-    let mut last = current_time()?;
+    let started = current_time()?;
+    let mut boot_counter = Some(boot_counter);
+    let mut ota_unverified = ota::is_unverified()?;
+    let mut last = started;
     loop {
-        if let Some(Command::UpdateWifi(template)) = CMD_QUEUE.dequeue() {
-            info!("setting new Wifi configuration");
-            let wifi_info = template.try_into()?;
-            wifi_storage.set_info(Some(&wifi_info))?;
-            update_wifi(&mut wifi, &sysloop, wifi_info)?;
+        if let Some(command) = CMD_QUEUE.dequeue() {
+            app.handle_command(command)?;
         }
         let now = current_time()?;
+        app.check_pending(Duration::from_micros(now))?;
+        app.check_wps(Duration::from_micros(now))?;
+        app.check_reconnect(Duration::from_micros(now))?;
+        app.check_roaming(Duration::from_micros(now))?;
+        if Duration::from_micros(now - started) >= boot::STABLE_AFTER {
+            if let Some(mut boot_counter) = boot_counter.take() {
+                info!("boot successful");
+                boot_counter.mark_successful()?;
+            }
+        }
+        // A new firmware image is only kept once it managed to come online
+        if ota_unverified {
+            if app.has_ip()? || (boot_counter.is_none() && !app.has_sta_config()?) {
+                info!("new firmware is online, marking it valid");
+                ota::mark_valid()?;
+                ota_unverified = false;
+            } else if Duration::from_micros(now - started) >= ota::VERIFY_TIMEOUT {
+                error!(
+                    "new firmware did not come online within {:?}, rolling back",
+                    ota::VERIFY_TIMEOUT
+                );
+                return Err(ota::rollback().into());
+            }
+        }
+        if let Some(press) = button.update(boot_button.is_low(), Duration::from_micros(now)) {
+            if let Some(action) = menu.handle(press) {
+                app.handle_action(action, &mut menu)?;
+            }
+        }
+        // Releasing the button before the countdown ran out cancels the reset
+        let reset_held = button
+            .held_for(Duration::from_micros(now))
+            .filter(|held| *held >= FACTORY_RESET_ARM);
+        if let Some(held) = reset_held {
+            button.suppress();
+            if held >= FACTORY_RESET_HOLD {
+                app.handle_command(Command::FactoryReset)?;
+            }
+            progress_bar.set_progress(
+                (held - FACTORY_RESET_ARM).as_secs_f32()
+                    / (FACTORY_RESET_HOLD - FACTORY_RESET_ARM).as_secs_f32(),
+            );
+        }
         // Clear screen
         display.clear();
         // Update (animation) components
         loader.update(Duration::from_micros(now - last));
 
         // redraw all component
-        message.draw(display.as_mut()).unwrap();
-        img.draw(display.as_mut()).unwrap();
-        loader.draw(display.as_mut()).unwrap();
+        if let Some(progress) = ota::progress() {
+            Text::with_baseline(
+                "Updating firmware",
+                Point::new(0, 12),
+                text_style,
+                Baseline::Top,
+            )
+            .draw(display.as_mut())
+            .unwrap();
+            progress_bar.set_progress(progress);
+            progress_bar.draw(display.as_mut()).unwrap();
+        } else if let Some(held) = reset_held {
+            let remaining = (FACTORY_RESET_HOLD - held).as_secs() + 1;
+            let countdown = format!("Factory reset in {remaining}s");
+            Text::with_baseline(&countdown, Point::new(0, 12), text_style, Baseline::Top)
+                .draw(display.as_mut())
+                .unwrap();
+            Text::with_baseline(
+                "Release to cancel",
+                Point::new(0, 24),
+                text_style,
+                Baseline::Top,
+            )
+            .draw(display.as_mut())
+            .unwrap();
+            progress_bar.draw(display.as_mut()).unwrap();
+        } else if let Some(remaining) = app.wps_remaining(Duration::from_micros(now)) {
+            let lines = [
+                "WPS".to_owned(),
+                "Press the WPS".to_owned(),
+                "button on the router".to_owned(),
+                format!("{}s left", remaining.as_secs()),
+            ];
+            for (row, line) in lines.iter().enumerate() {
+                Text::with_baseline(
+                    line,
+                    Point::new(0, row as i32 * 12),
+                    text_style,
+                    Baseline::Top,
+                )
+                .draw(display.as_mut())
+                .unwrap();
+            }
+        } else if !menu.is_idle() {
+            menu.draw(display.as_mut()).unwrap();
+        } else if let Some(recovery_message) = recovery_message
+            .as_ref()
+            .filter(|_| app.recovery().is_some())
+        {
+            Text::with_baseline(recovery_message, Point::zero(), text_style, Baseline::Top)
+                .draw(display.as_mut())
+                .unwrap();
+        } else {
+            message.draw(display.as_mut()).unwrap();
+            img.draw(display.as_mut()).unwrap();
+            loader.draw(display.as_mut()).unwrap();
+        }
         display.flush().unwrap();
         last = now;
         unsafe { sys::usleep(10_000) };
     }
 }
 
+/// Owns the WiFi driver together with its persisted settings
+struct App {
+    wifi: EspWifi<'static>,
+    sysloop: EspSystemEventLoop,
+    wifi_storage: WifiStorage<nvs::NvsDefault>,
+    auth: SharedAuth,
+    tls: SharedTls,
+    tls_fingerprint: Option<[u8; 32]>,
+    pending: Option<PendingUpdate>,
+    /// Set while WPS is running
+    wps_deadline: Option<Duration>,
+    /// Next scan for the saved network, while the station is not connected
+    reconnect_at: Option<Duration>,
+    /// Next check of the signal, while the station is connected
+    roaming_at: Option<Duration>,
+    /// The access point was asked to steer the station since the signal got
+    /// weak
+    transition_requested: bool,
+    /// The station was pointed at one access point to roam to, its unpinned
+    /// configuration is restored once the connect is over
+    steered_at: Option<Duration>,
+    recovery: SharedRecovery,
+}
+
+impl App {
+    fn handle_command(&mut self, command: Command) -> anyhow::Result<()> {
+        match command {
+            Command::UpdateWifi(wifi_info) => {
+                info!("trying new Wifi configuration");
+                events::publish(Event::Apply {
+                    state: ApplyState::Trying,
+                    reason: None,
+                });
+                // An unconfirmed configuration is never a fallback target, so
+                // keep reverting to whatever was known to work before it
+                let last_known_good = match (self.pending.take(), self.recovery()) {
+                    (Some(pending), _) => pending.last_known_good,
+                    (None, Some(_)) => Recovery::wifi_info(),
+                    (None, None) => self.wifi_storage.get_info()?,
+                };
+                let pending = PendingUpdate::new(
+                    wifi_info.clone(),
+                    last_known_good,
+                    Duration::from_micros(current_time()?),
+                );
+                self.pending = Some(pending);
+                if let Err(err) = update_wifi(&mut self.wifi, &self.sysloop, wifi_info) {
+                    warn!("applying new Wifi configuration failed: {err}");
+                    self.revert(format!("applying failed: {err}"))?;
+                }
+            }
+            Command::UpdateClient(settings) => {
+                let wifi_info = settings.apply(self.wifi_storage.get_info()?)?;
+                match wifi_info.validate() {
+                    Ok(()) => self.handle_command(Command::UpdateWifi(wifi_info))?,
+                    Err(invalid) => warn!("rejecting station settings: {invalid}"),
+                }
+            }
+            Command::StartWps => {
+                if self.wps_deadline.is_some() {
+                    return Ok(());
+                }
+                info!("starting WPS, waiting for the router's button to be pressed");
+                match wps::start() {
+                    Ok(()) => {
+                        self.wps_deadline =
+                            Some(Duration::from_micros(current_time()?) + wps::TIMEOUT);
+                        events::publish(Event::Wps {
+                            state: WpsState::Started,
+                            reason: None,
+                        });
+                    }
+                    Err(err) => self.wps_failed(format!("starting failed: {err}"))?,
+                }
+            }
+            Command::ConfirmWifi => {
+                if self.pending.is_some() {
+                    info!("new Wifi configuration confirmed by the user");
+                    self.commit()?;
+                }
+            }
+            Command::SetApMode(ap_mode) => {
+                // The stored settings would replace the ones on trial
+                if self.pending.is_some() {
+                    warn!("not setting AP mode to {ap_mode:?} while new settings are on trial");
+                    return Ok(());
+                }
+                info!("setting AP mode to {ap_mode:?}");
+                let wifi_info = WifiInfo {
+                    ap_mode,
+                    ..self.wifi_storage.get_info()?
+                };
+                self.wifi_storage.set_info(Some(&wifi_info))?;
+                update_wifi(&mut self.wifi, &self.sysloop, wifi_info)?;
+            }
+            Command::Reboot => {
+                info!("rebooting");
+                unsafe { sys::esp_restart() };
+            }
+            Command::FactoryReset => {
+                info!("resetting WiFi settings, admin password and HTTPS to factory defaults");
+                self.wifi_storage.set_info(None)?;
+                self.wifi_storage.set_syslog(&Default::default())?;
+                self.wifi_storage.clear_fleet_key()?;
+                self.auth.lock().unwrap().set_password(None)?;
+                self.tls.lock().unwrap().set_enabled(false)?;
+                // The driver keeps its own copy of the last configuration, so make
+                // sure the default AP is what comes up after the reboot
+                self.wifi.set_configuration(&Configuration::Mixed(
+                    Default::default(),
+                    default_ap_config(),
+                ))?;
+                unsafe { sys::esp_restart() };
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_action(&mut self, action: Action, menu: &mut Menu) -> anyhow::Result<()> {
+        match action {
+            Action::NetworkInfo => {
+                let mut lines = network_info(&self.wifi)?;
+                if let Some((service_name, pop)) = provisioning::session() {
+                    lines.push(format!("BLE: {service_name}"));
+                    lines.push(format!("PoP: {pop}"));
+                }
+                if let Some(fingerprint) = self.tls_fingerprint {
+                    // Six bytes per line fit the width of the display
+                    lines.push("TLS SHA-256:".into());
+                    lines.extend(fingerprint.chunks(6).map(tls::format_fingerprint));
+                }
+                menu.show_text("Network info", lines);
+            }
+            Action::LinkQuality => menu.show_link(),
+            Action::Scan => {
+                let lines = match scan_aps() {
+                    Ok(aps) => aps
+                        .into_iter()
+                        .map(|ap| format!("{:>4} {}", ap.signal_strength, ap.ssid))
+                        .collect(),
+                    Err(err) => vec![format!("Scan failed: {err}")],
+                };
+                menu.show_text("Networks", lines);
+            }
+            Action::ToggleAp if self.pending.is_some() => {
+                let lines = vec!["New settings are".to_owned(), "on trial".to_owned()];
+                menu.show_text("AP mode", lines);
+            }
+            Action::ToggleAp => {
+                let ap_mode = match self.wifi_storage.get_info()?.ap_mode {
+                    ApMode::Always => ApMode::NoConnOnBoot,
+                    ApMode::NoConnOnBoot | ApMode::Never => ApMode::Always,
+                };
+                self.handle_command(Command::SetApMode(ap_mode))?;
+                menu.show_text("AP mode", vec![format!("{ap_mode:?}")]);
+            }
+            Action::Wps => self.handle_command(Command::StartWps)?,
+            Action::Reboot => self.handle_command(Command::Reboot)?,
+            Action::FactoryReset => self.handle_command(Command::FactoryReset)?,
+        }
+        Ok(())
+    }
+
+    /// Persists a pending configuration once it got an IP address, or rolls
+    /// it back once it timed out.
+    fn check_pending(&mut self, now: Duration) -> anyhow::Result<()> {
+        let Some(pending) = self.pending.as_ref() else {
+            return Ok(());
+        };
+        // WPS takes over the station, a rollback would interrupt it
+        if self.wps_deadline.is_some() {
+            return Ok(());
+        }
+
+        if pending.info.sta_config.is_some() && self.has_ip()? {
+            info!("new Wifi configuration got an IP address");
+            self.commit()?;
+        } else if pending.is_expired(now) {
+            let reason = if pending.info.sta_config.is_some() {
+                format!("no IP address within {:?}", PendingUpdate::TIMEOUT)
+            } else {
+                format!("not confirmed within {:?}", PendingUpdate::TIMEOUT)
+            };
+            warn!("new Wifi configuration failed: {reason}");
+            self.revert(reason)?;
+        }
+        Ok(())
+    }
+
+    /// Hands credentials received over WPS to the regular update, or
+    /// reconnects as before once WPS failed.
+    fn check_wps(&mut self, now: Duration) -> anyhow::Result<()> {
+        let Some(deadline) = self.wps_deadline else {
+            return Ok(());
+        };
+        match wps::take_outcome() {
+            Some(Ok(settings)) => {
+                wps::stop();
+                self.wps_deadline = None;
+                events::publish(Event::Wps {
+                    state: WpsState::Succeeded,
+                    reason: None,
+                });
+                self.handle_command(Command::UpdateClient(settings))?;
+            }
+            Some(Err(reason)) => self.wps_failed(reason.to_owned())?,
+            None if now >= deadline => {
+                self.wps_failed(format!("no credentials within {:?}", wps::TIMEOUT))?
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    fn wps_failed(&mut self, reason: String) -> anyhow::Result<()> {
+        warn!("WPS failed: {reason}");
+        wps::stop();
+        self.wps_deadline = None;
+        events::publish(Event::Wps {
+            state: WpsState::Failed,
+            reason: Some(reason),
+        });
+        // WPS disconnected the station, go back to the configuration in use
+        let wifi_info = match &self.pending {
+            Some(pending) => pending.info.clone(),
+            None => self.wifi_storage.get_info()?,
+        };
+        update_wifi(&mut self.wifi, &self.sysloop, wifi_info)?;
+        Ok(())
+    }
+
+    /// Looks for the saved network with a short targeted scan while the
+    /// station is not connected, and reconnects once it is back in range.
+    fn check_reconnect(&mut self, now: Duration) -> anyhow::Result<()> {
+        // Trials, WPS and BLE provisioning connect the station themselves
+        if self.pending.is_some()
+            || self.wps_deadline.is_some()
+            || provisioning::session().is_some()
+        {
+            self.reconnect_at = None;
+            return Ok(());
+        }
+        if self.wifi.is_connected()? {
+            self.reconnect_at = None;
+            return Ok(());
+        }
+        let active = self
+            .wifi
+            .get_configuration()?
+            .as_client_conf_ref()
+            .filter(|config| !config.ssid.is_empty())
+            .cloned();
+        // After a failed connect only the access point is left, the stored
+        // network is tried again unless the settings are skipped for recovery
+        let saved = match &active {
+            Some(config) => Some(config.clone()),
+            None if self.recovery().is_none() => self.wifi_storage.get_info()?.sta_config,
+            None => None,
+        };
+        let Some(saved) = saved else {
+            self.reconnect_at = None;
+            return Ok(());
+        };
+        let next = *self.reconnect_at.get_or_insert(now + RECONNECT_INTERVAL);
+        if now < next {
+            return Ok(());
+        }
+        self.reconnect_at = Some(now + RECONNECT_INTERVAL);
+        // Scanning leaves the channel of the access point, its clients would
+        // lose their connection
+        if wifi::ap_client_count() > 0 {
+            return Ok(());
+        }
+
+        let hidden = self.wifi_storage.get_info()?.sta_hidden;
+        let ap = match wifi::find_network(&saved, hidden) {
+            Ok(Some(ap)) => ap,
+            Ok(None) => return Ok(()),
+            Err(err) => {
+                // Tried again with the next interval
+                warn!("scanning for {} failed: {err}", saved.ssid);
+                return Ok(());
+            }
+        };
+        info!(
+            "{} is in range again on channel {}, reconnecting",
+            ap.ssid, ap.channel
+        );
+        if active.is_some() {
+            self.wifi.connect()?;
+        } else {
+            // Bringing the station back restarts the access point
+            update_wifi(&mut self.wifi, &self.sysloop, self.wifi_storage.get_info()?)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the station to a clearly stronger access point of its network
+    /// once the signal gets weaker than the roaming threshold. The access
+    /// point is asked first, it may know its neighbors (802.11k) and steer the
+    /// station itself (802.11v).
+    fn check_roaming(&mut self, now: Duration) -> anyhow::Result<()> {
+        // Later reconnects choose the access point by signal again
+        if let Some(steered_at) = self.steered_at {
+            if self.wifi.is_connected()? || now >= steered_at + ROAMING_INTERVAL {
+                wifi::steer_station(None)?;
+                self.steered_at = None;
+            }
+        }
+        if self.pending.is_some() || self.wps_deadline.is_some() || !self.wifi.is_connected()? {
+            self.roaming_at = None;
+            self.transition_requested = false;
+            return Ok(());
+        }
+        let next = *self.roaming_at.get_or_insert(now + ROAMING_INTERVAL);
+        if now < next {
+            return Ok(());
+        }
+        self.roaming_at = Some(now + ROAMING_INTERVAL);
+
+        let info = self.wifi_storage.get_info()?;
+        // A pinned access point is kept however weak it gets
+        let Some(config) = info.sta_config.filter(|config| config.bssid.is_none()) else {
+            return Ok(());
+        };
+        let Some(current) = wifi::sta_ap_info() else {
+            return Ok(());
+        };
+        if !info.roaming.is_weak(current.signal_strength) {
+            self.transition_requested = false;
+            return Ok(());
+        }
+        // Give the access point one interval to answer
+        if !self.transition_requested {
+            self.transition_requested = true;
+            if wifi::request_transition() {
+                info!(
+                    "signal of {} dBm is weak, asked the AP for a better one",
+                    current.signal_strength
+                );
+                return Ok(());
+            }
+        }
+
+        let candidates = match wifi::find_candidates(&config, info.sta_hidden) {
+            Ok(candidates) => candidates,
+            Err(err) => {
+                warn!(
+                    "scanning for access points of {} failed: {err}",
+                    config.ssid
+                );
+                return Ok(());
+            }
+        };
+        let Some(better) =
+            roaming::better_candidate(current.bssid, current.signal_strength, &candidates)
+        else {
+            return Ok(());
+        };
+        // The access point follows the channel of the station, its clients
+        // would lose their connection
+        if better.channel != current.channel && wifi::ap_client_count() > 0 {
+            return Ok(());
+        }
+        info!(
+            "roaming from {} ({} dBm) to {} ({} dBm) on channel {}",
+            convert::format_mac(&current.bssid),
+            current.signal_strength,
+            convert::format_mac(&better.bssid),
+            better.rssi,
+            better.channel
+        );
+        events::publish(Event::Roaming {
+            from: convert::format_mac(&current.bssid),
+            to: convert::format_mac(&better.bssid),
+            rssi: better.rssi,
+        });
+        wifi::steer_station(Some(better))?;
+        self.wifi.disconnect()?;
+        self.wifi.connect()?;
+        self.steered_at = Some(now);
+        self.transition_requested = false;
+        Ok(())
+    }
+
+    /// Time left until WPS gives up, `None` if it is not running
+    fn wps_remaining(&self, now: Duration) -> Option<Duration> {
+        self.wps_deadline
+            .map(|deadline| deadline.saturating_sub(now))
+    }
+
+    /// Returns `true` once the STA is connected and got an IP address.
+    fn has_ip(&self) -> anyhow::Result<bool> {
+        Ok(self.wifi.is_connected()?
+            && self.wifi.sta_netif().get_ip_info()?.ip != Ipv4Addr::UNSPECIFIED)
+    }
+
+    /// Why the stored settings were skipped, until new ones are committed
+    fn recovery(&self) -> Option<Recovery> {
+        *self.recovery.lock().unwrap()
+    }
+
+    fn has_sta_config(&self) -> anyhow::Result<bool> {
+        Ok(self
+            .wifi
+            .get_configuration()?
+            .as_client_conf_ref()
+            .map_or(false, |config| !config.ssid.is_empty()))
+    }
+
+    fn commit(&mut self) -> anyhow::Result<()> {
+        if let Some(pending) = self.pending.take() {
+            info!("persisting new Wifi configuration");
+            self.wifi_storage.set_info(Some(&pending.info))?;
+            provisioning::stop();
+            fleet::stop_following();
+            *self.recovery.lock().unwrap() = None;
+            events::publish(Event::Apply {
+                state: ApplyState::Committed,
+                reason: None,
+            });
+        }
+        Ok(())
+    }
+
+    fn revert(&mut self, reason: String) -> anyhow::Result<()> {
+        if let Some(pending) = self.pending.take() {
+            warn!("reverting to last known good Wifi configuration");
+            events::publish(Event::Apply {
+                state: ApplyState::Reverted,
+                reason: Some(reason),
+            });
+            update_wifi(&mut self.wifi, &self.sysloop, pending.last_known_good)?;
+            if !self.has_sta_config()? {
+                fleet::follow();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Serves `GET`, `PUT` and `PATCH` for a settings resource of the REST API.
+fn serve_resource<R: Resource + 'static>(
+    http_server: &mut EspHttpServer,
+    name: &str,
+    auth: &SharedAuth,
+    csrf: &CsrfToken,
+    wifi_storage: &Arc<Mutex<WifiStorage<nvs::NvsDefault>>>,
+) -> Result<(), sys::EspError> {
+    let uri = format!("{}/{name}", api::PREFIX);
+    http_server.fn_handler(
+        &uri,
+        Method::Get,
+        http::authenticated(auth, {
+            let wifi_storage = wifi_storage.clone();
+            move |request| http::api_get_handler::<R, _>(request, &wifi_storage)
+        }),
+    )?;
+    for method in [Method::Put, Method::Patch] {
+        http_server.fn_handler(
+            &uri,
+            method,
+            http::authenticated(
+                auth,
+                http::csrf_protected(csrf, "application/json", {
+                    let wifi_storage = wifi_storage.clone();
+                    move |request| http::api_update_handler::<R, _>(request, &wifi_storage)
+                }),
+            ),
+        )?;
+    }
+    Ok(())
+}
+
+fn network_info(wifi: &EspWifi) -> anyhow::Result<Vec<String>> {
+    let configuration = wifi.get_configuration()?;
+    let mut lines = Vec::new();
+    if let Some(config) = configuration.as_client_conf_ref() {
+        lines.push(format!("SSID: {}", config.ssid));
+        lines.push(format!("Connected: {}", wifi.is_connected()?));
+        lines.push(format!("IP: {}", wifi.sta_netif().get_ip_info()?.ip));
+    }
+    if let Some(config) = configuration.as_ap_conf_ref() {
+        lines.push(format!("AP: {}", config.ssid));
+        lines.push(format!("AP IP: {}", wifi.ap_netif().get_ip_info()?.ip));
+    }
+    Ok(lines)
+}
+
 fn current_time() -> Result<u64, sys::EspError> {
     let mut tv_now = Default::default();
     unsafe { sys::esp!(sys::gettimeofday(&mut tv_now, core::ptr::null_mut()))? };
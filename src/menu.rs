@@ -0,0 +1,318 @@
+use crate::{animation::Sparkline, button::Press, link};
+use embedded_graphics::{
+    mono_font::{ascii::FONT_6X10, MonoTextStyle, MonoTextStyleBuilder},
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::Rectangle,
+    text::{Baseline, Text},
+};
+
+/// An entry of the on-device menu that is handed back to the caller once it is
+/// activated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    NetworkInfo,
+    LinkQuality,
+    Scan,
+    ToggleAp,
+    Wps,
+    Reboot,
+    FactoryReset,
+}
+
+impl Action {
+    const ALL: [Action; 7] = [
+        Action::NetworkInfo,
+        Action::LinkQuality,
+        Action::Scan,
+        Action::ToggleAp,
+        Action::Wps,
+        Action::Reboot,
+        Action::FactoryReset,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            Action::NetworkInfo => "Network info",
+            Action::LinkQuality => "Link quality",
+            Action::Scan => "Scan networks",
+            Action::ToggleAp => "Toggle AP mode",
+            Action::Wps => "WPS connect",
+            Action::Reboot => "Reboot",
+            Action::FactoryReset => "Factory reset",
+        }
+    }
+
+    /// Destructive actions have to be confirmed by a second long press.
+    fn needs_confirmation(self) -> bool {
+        matches!(self, Action::Reboot | Action::FactoryReset)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+enum Screen {
+    #[default]
+    Idle,
+    Menu(usize),
+    Text {
+        title: &'static str,
+        lines: Vec<String>,
+        offset: usize,
+    },
+    Confirm(Action),
+    /// Signal history of the station link, updated while it is shown
+    Link,
+}
+
+/// Menu state machine driven by [`Press`] gestures.
+///
+/// * `Single` selects the next entry (or scrolls a text page),
+/// * `Long` activates the selected entry (or goes back),
+/// * `Double` closes the menu (or goes back).
+#[derive(Default)]
+pub struct Menu {
+    screen: Screen,
+    selected: usize,
+}
+
+impl Menu {
+    const LINES: usize = 5;
+    const LINE_HEIGHT: i32 = 10;
+
+    /// Returns `true` if the menu is closed and the regular screen should be
+    /// drawn.
+    pub fn is_idle(&self) -> bool {
+        self.screen == Screen::Idle
+    }
+
+    /// Advances the menu by one gesture and returns the activated action, if
+    /// any.
+    pub fn handle(&mut self, press: Press) -> Option<Action> {
+        match (&mut self.screen, press) {
+            (Screen::Idle, _) => {
+                self.selected = 0;
+                self.screen = Screen::Menu(0);
+            }
+            (Screen::Menu(selected), Press::Single) => {
+                *selected = (*selected + 1) % Action::ALL.len();
+                self.selected = *selected;
+            }
+            (Screen::Menu(_), Press::Double) => self.screen = Screen::Idle,
+            (Screen::Menu(selected), Press::Long) => {
+                let action = Action::ALL[*selected];
+                if action.needs_confirmation() {
+                    self.screen = Screen::Confirm(action);
+                } else {
+                    return Some(action);
+                }
+            }
+            (Screen::Text { lines, offset, .. }, Press::Single) => {
+                *offset = if *offset + Self::LINES < lines.len() {
+                    *offset + 1
+                } else {
+                    0
+                };
+            }
+            (Screen::Confirm(action), Press::Long) => {
+                let action = *action;
+                self.screen = Screen::Menu(self.selected);
+                return Some(action);
+            }
+            (Screen::Text { .. } | Screen::Confirm(_) | Screen::Link, _) => {
+                self.screen = Screen::Menu(self.selected);
+            }
+        }
+        None
+    }
+
+    /// Shows a scrollable text page, e.g. the result of an [`Action`].
+    pub fn show_text(&mut self, title: &'static str, lines: Vec<String>) {
+        self.screen = Screen::Text {
+            title,
+            lines,
+            offset: 0,
+        };
+    }
+
+    /// Shows the signal history of the station link.
+    pub fn show_link(&mut self) {
+        self.screen = Screen::Link;
+    }
+
+    fn draw_line<D>(
+        target: &mut D,
+        row: usize,
+        text: &str,
+        style: MonoTextStyle<'_, BinaryColor>,
+    ) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        Text::with_baseline(
+            text,
+            Point::new(0, row as i32 * Self::LINE_HEIGHT),
+            style,
+            Baseline::Top,
+        )
+        .draw(target)?;
+        Ok(())
+    }
+}
+
+impl Drawable for Menu {
+    type Color = BinaryColor;
+    type Output = ();
+
+    fn draw<D>(&self, target: &mut D) -> Result<Self::Output, D::Error>
+    where
+        D: DrawTarget<Color = Self::Color>,
+    {
+        let normal = MonoTextStyleBuilder::new()
+            .font(&FONT_6X10)
+            .text_color(BinaryColor::On)
+            .build();
+        let inverted = MonoTextStyleBuilder::new()
+            .font(&FONT_6X10)
+            .text_color(BinaryColor::Off)
+            .background_color(BinaryColor::On)
+            .build();
+
+        match &self.screen {
+            Screen::Idle => {}
+            Screen::Menu(selected) => {
+                Self::draw_line(target, 0, "Menu", inverted)?;
+                // Scroll once the selection leaves the screen
+                let first = selected.saturating_sub(Self::LINES - 1);
+                let visible = Action::ALL.iter().enumerate().skip(first).take(Self::LINES);
+                for (row, (index, action)) in visible.enumerate() {
+                    let style = if index == *selected { inverted } else { normal };
+                    Self::draw_line(target, row + 1, action.label(), style)?;
+                }
+            }
+            Screen::Text {
+                title,
+                lines,
+                offset,
+            } => {
+                Self::draw_line(target, 0, title, inverted)?;
+                for (row, line) in lines.iter().skip(*offset).take(Self::LINES).enumerate() {
+                    Self::draw_line(target, row + 1, line, normal)?;
+                }
+            }
+            Screen::Confirm(action) => {
+                Self::draw_line(target, 0, action.label(), inverted)?;
+                Self::draw_line(target, 2, "Hold to confirm", normal)?;
+                Self::draw_line(target, 3, "Press to cancel", normal)?;
+            }
+            Screen::Link => {
+                let width = target.bounding_box().size.width;
+                let samples = link::recent(width as usize);
+                let last = samples.last().filter(|sample| sample.rssi.is_some());
+                let title = match last {
+                    Some(sample) => format!(
+                        "Link {} dBm ch {}",
+                        sample.rssi.unwrap_or_default(),
+                        sample.channel.unwrap_or_default()
+                    ),
+                    None => "Link: not connected".to_owned(),
+                };
+                Self::draw_line(target, 0, &title, inverted)?;
+
+                let mut chart = Sparkline::new(
+                    Rectangle::new(
+                        Point::new(0, Self::LINE_HEIGHT + 2),
+                        Size::new(width, 4 * Self::LINE_HEIGHT as u32 - 4),
+                    ),
+                    BinaryColor::On,
+                    (*link::RSSI_RANGE.start()).into()..=(*link::RSSI_RANGE.end()).into(),
+                );
+                chart.set_values(samples.iter().map(|sample| sample.rssi.map(i32::from)));
+                chart.draw(target)?;
+
+                let disconnects: u32 = samples
+                    .iter()
+                    .map(|sample| u32::from(sample.disconnects))
+                    .sum();
+                let minutes = samples.len() as u64 * link::INTERVAL.as_secs() / 60;
+                let footer = format!("{minutes} min, {disconnects} drops");
+                Self::draw_line(target, Self::LINES, &footer, normal)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A menu with `action` selected
+    fn select(action: Action) -> Menu {
+        let mut menu = Menu::default();
+        assert_eq!(menu.handle(Press::Single), None);
+        while Action::ALL[menu.selected] != action {
+            assert_eq!(menu.handle(Press::Single), None);
+        }
+        menu
+    }
+
+    #[test]
+    fn navigation() {
+        let mut menu = Menu::default();
+        assert!(menu.is_idle());
+        assert_eq!(menu.handle(Press::Long), None);
+        assert_eq!(menu.screen, Screen::Menu(0));
+
+        for index in (1..Action::ALL.len()).chain([0]) {
+            assert_eq!(menu.handle(Press::Single), None);
+            assert_eq!(menu.screen, Screen::Menu(index));
+        }
+
+        assert_eq!(menu.handle(Press::Double), None);
+        assert!(menu.is_idle());
+        // Reopening starts at the top
+        menu.handle(Press::Single);
+        assert_eq!(menu.screen, Screen::Menu(0));
+    }
+
+    #[test]
+    fn activate() {
+        let mut menu = select(Action::Scan);
+        assert_eq!(menu.handle(Press::Long), Some(Action::Scan));
+
+        menu.show_text("Networks", (0..7).map(|n| n.to_string()).collect());
+        for offset in [1, 2, 0] {
+            assert_eq!(menu.handle(Press::Single), None);
+            assert!(matches!(menu.screen, Screen::Text { offset: o, .. } if o == offset));
+        }
+        assert_eq!(menu.handle(Press::Double), None);
+        assert_eq!(menu.screen, Screen::Menu(2));
+    }
+
+    #[test]
+    fn confirm() {
+        for action in [Action::Reboot, Action::FactoryReset] {
+            let mut menu = select(action);
+            assert_eq!(menu.handle(Press::Long), None);
+            assert_eq!(menu.screen, Screen::Confirm(action));
+            assert_eq!(menu.handle(Press::Long), Some(action));
+            assert_eq!(menu.screen, Screen::Menu(menu.selected));
+        }
+    }
+
+    #[test]
+    fn cancel() {
+        for press in [Press::Single, Press::Double] {
+            let mut menu = select(Action::FactoryReset);
+            assert_eq!(menu.handle(Press::Long), None);
+            assert_eq!(menu.handle(press), None);
+            assert_eq!(menu.screen, Screen::Menu(6));
+        }
+
+        let mut menu = select(Action::LinkQuality);
+        assert_eq!(menu.handle(Press::Long), Some(Action::LinkQuality));
+        menu.show_link();
+        assert_eq!(menu.handle(Press::Single), None);
+        assert_eq!(menu.screen, Screen::Menu(1));
+    }
+}
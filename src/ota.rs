@@ -0,0 +1,197 @@
+//! Firmware updates uploaded through the portal
+//!
+//! An upload is streamed into the inactive OTA partition. Its image header is
+//! checked, and its SHA-256 digest if the client sent one. That digest comes
+//! from the same client as the image, so it only detects a damaged transfer:
+//! there is no signature check, and anyone who can log into the portal can
+//! install any firmware. Builds with signed app images
+//! (`CONFIG_SECURE_SIGNED_APPS_NO_SECURE_BOOT` or secure boot) make ESP-IDF
+//! verify the signature when the update is finished.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use embedded_svc::{io::Write, ota::SlotState};
+use esp_idf_svc::ota::{EspOta, EspOtaUpdate};
+use esp_idf_sys as sys;
+use sha2::{Digest, Sha256};
+
+/// Time a freshly updated image has to come online before it is rolled back
+pub const VERIFY_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// `esp_image_header_t` followed by the first segment header and the magic
+/// word of `esp_app_desc_t`
+const HEADER_LEN: usize = 24 + 8 + 4;
+const IMAGE_MAGIC: u8 = 0xE9;
+const APP_DESC_MAGIC: u32 = 0xABCD_5432;
+
+static WRITTEN: AtomicUsize = AtomicUsize::new(0);
+static TOTAL: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the fraction of the running update that has been written, if an
+/// update is in progress.
+pub fn progress() -> Option<f32> {
+    let total = TOTAL.load(Ordering::Relaxed);
+    (total != 0).then(|| WRITTEN.load(Ordering::Relaxed) as f32 / total as f32)
+}
+
+/// Returns `true` if the running image was just installed and has not been
+/// marked as valid yet.
+pub fn is_unverified() -> Result<bool, sys::EspError> {
+    Ok(EspOta::new()?.get_running_slot()?.state == SlotState::Unverified)
+}
+
+/// Marks the running image as valid, cancelling the rollback.
+pub fn mark_valid() -> Result<(), sys::EspError> {
+    EspOta::new()?.mark_running_slot_valid()
+}
+
+/// Marks the running image as invalid and reboots into the previous one.
+pub fn rollback() -> sys::EspError {
+    match EspOta::new() {
+        Ok(mut ota) => ota.mark_running_slot_invalid_and_reboot(),
+        Err(err) => err,
+    }
+}
+
+#[derive(Debug)]
+pub enum OtaError {
+    Busy,
+    InvalidImage(&'static str),
+    ChecksumMismatch,
+    Esp(sys::EspError),
+}
+
+impl fmt::Display for OtaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OtaError::Busy => write!(f, "another update is in progress"),
+            OtaError::InvalidImage(reason) => write!(f, "invalid image: {reason}"),
+            OtaError::ChecksumMismatch => write!(f, "SHA-256 checksum mismatch"),
+            OtaError::Esp(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<sys::EspError> for OtaError {
+    fn from(err: sys::EspError) -> Self {
+        OtaError::Esp(err)
+    }
+}
+
+/// Streams a firmware image into the inactive OTA partition.
+///
+/// The image header is checked as soon as enough bytes arrived, and the
+/// optional SHA-256 digest before the boot partition is switched.
+pub struct Upload<'a> {
+    update: EspOtaUpdate<'a>,
+    header: heapless::Vec<u8, HEADER_LEN>,
+    hasher: Sha256,
+    sha256: Option<[u8; 32]>,
+    _progress: Progress,
+}
+
+impl<'a> Upload<'a> {
+    pub fn begin(
+        ota: &'a mut EspOta,
+        len: usize,
+        sha256: Option<[u8; 32]>,
+    ) -> Result<Self, OtaError> {
+        let progress = Progress::start(len)?;
+        Ok(Self {
+            update: ota.initiate_update()?,
+            header: heapless::Vec::new(),
+            hasher: Sha256::new(),
+            sha256,
+            _progress: progress,
+        })
+    }
+
+    pub fn write(&mut self, chunk: &[u8]) -> Result<(), OtaError> {
+        if !self.header.is_full() {
+            let missing = (HEADER_LEN - self.header.len()).min(chunk.len());
+            self.header
+                .extend_from_slice(&chunk[..missing])
+                .expect("header has room for the missing bytes");
+            if self.header.is_full() {
+                self.check_header()?;
+            }
+        }
+
+        self.hasher.update(chunk);
+        self.update.write_all(chunk)?;
+        WRITTEN.fetch_add(chunk.len(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Verifies the image and makes it the one to boot next.
+    pub fn complete(self) -> Result<(), OtaError> {
+        let Self {
+            update,
+            header,
+            hasher,
+            sha256,
+            ..
+        } = self;
+        let invalid = if !header.is_full() {
+            Some(OtaError::InvalidImage("image is truncated"))
+        } else {
+            sha256
+                .filter(|expected| hasher.finalize()[..] != expected[..])
+                .map(|_| OtaError::ChecksumMismatch)
+        };
+        if let Some(err) = invalid {
+            update.abort()?;
+            return Err(err);
+        }
+        // Releases the update whatever the outcome
+        update.complete()?;
+        Ok(())
+    }
+
+    /// Discards the partially written image.
+    pub fn abort(self) -> Result<(), OtaError> {
+        let Self { update, .. } = self;
+        update.abort()?;
+        Ok(())
+    }
+
+    fn check_header(&self) -> Result<(), OtaError> {
+        let header = &self.header;
+        if header[0] != IMAGE_MAGIC {
+            return Err(OtaError::InvalidImage("not an ESP image"));
+        }
+        if u32::from(u16::from_le_bytes([header[12], header[13]]))
+            != sys::CONFIG_IDF_FIRMWARE_CHIP_ID
+        {
+            return Err(OtaError::InvalidImage("image is built for another chip"));
+        }
+        if u32::from_le_bytes([header[32], header[33], header[34], header[35]]) != APP_DESC_MAGIC {
+            return Err(OtaError::InvalidImage("missing application description"));
+        }
+        Ok(())
+    }
+}
+
+/// Publishes the progress of an [`Upload`] and resets it once the upload is
+/// done, whatever its outcome.
+struct Progress;
+
+impl Progress {
+    fn start(len: usize) -> Result<Self, OtaError> {
+        TOTAL
+            .compare_exchange(0, len.max(1), Ordering::Relaxed, Ordering::Relaxed)
+            .map_err(|_| OtaError::Busy)?;
+        WRITTEN.store(0, Ordering::Relaxed);
+        Ok(Self)
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        TOTAL.store(0, Ordering::Relaxed);
+        WRITTEN.store(0, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,186 @@
+//! BLE provisioning with the unified provisioning protocol of ESP-IDF, so the
+//! Espressif provisioning apps (or `esp_prov.py`) can set up the station
+//! without joining the access point first
+//!
+//! The provisioning manager handles scans and connects the station itself to
+//! report the result to the phone. Credentials that worked are then handed to
+//! the main loop as [`Command::UpdateClient`], so they are validated, tried and
+//! stored like the ones entered in the portal.
+
+use crate::{api::ClientSettings, wifi::ClientAuth, Command, CMD_QUEUE};
+use core::ffi::c_void;
+use esp_idf_sys::{self as sys, EspError};
+use log::{info, warn};
+use std::{ffi::CString, sync::Mutex};
+
+static SESSION: Mutex<Option<Session>> = Mutex::new(None);
+/// Credentials sent by the phone, until the manager reports whether they work
+static CREDENTIALS: Mutex<Option<ClientSettings>> = Mutex::new(None);
+
+/// A running provisioning service. The strings are borrowed by the manager.
+struct Session {
+    service_name: CString,
+    pop: CString,
+}
+
+/// Starts advertising the provisioning service, protected by a random proof
+/// of possession that is shown on the display.
+pub fn start() -> Result<(), EspError> {
+    let mut session = SESSION.lock().unwrap();
+    if session.is_some() {
+        return Ok(());
+    }
+
+    let mut mac = [0u8; 6];
+    sys::esp!(unsafe {
+        sys::esp_read_mac(mac.as_mut_ptr(), sys::esp_mac_type_t_ESP_MAC_WIFI_STA)
+    })?;
+    // Naming scheme of the Espressif apps, which only list `PROV_` devices
+    let service_name = format!("PROV_{:02X}{:02X}{:02X}", mac[3], mac[4], mac[5]);
+    let pop = format!("{:08}", unsafe { sys::esp_random() } % 100_000_000);
+    let new_session = Session {
+        service_name: CString::new(service_name).unwrap(),
+        pop: CString::new(pop).unwrap(),
+    };
+
+    // The BLE scheme switches the driver to station only, but the access
+    // point has to stay up for the portal
+    let mut mode = sys::wifi_mode_t_WIFI_MODE_NULL;
+    sys::esp!(unsafe { sys::esp_wifi_get_mode(&mut mode) })?;
+
+    let config = sys::wifi_prov_mgr_config_t {
+        scheme: unsafe { sys::wifi_prov_scheme_ble },
+        // Classic Bluetooth is not needed, release its memory
+        scheme_event_handler: sys::wifi_prov_event_handler_t {
+            event_cb: Some(sys::wifi_prov_scheme_ble_event_cb_free_btdm),
+            user_data: core::ptr::null_mut(),
+        },
+        app_event_handler: sys::wifi_prov_event_handler_t {
+            event_cb: None,
+            user_data: core::ptr::null_mut(),
+        },
+    };
+    sys::esp!(unsafe { sys::wifi_prov_mgr_init(config) })?;
+    let started = sys::esp!(unsafe {
+        sys::esp_event_handler_register(
+            sys::WIFI_PROV_EVENT,
+            sys::ESP_EVENT_ANY_ID,
+            Some(on_event),
+            core::ptr::null_mut(),
+        )
+    })
+    .and_then(|()| {
+        sys::esp!(unsafe {
+            sys::wifi_prov_mgr_start_provisioning(
+                sys::wifi_prov_security_WIFI_PROV_SECURITY_1,
+                new_session.pop.as_ptr(),
+                new_session.service_name.as_ptr(),
+                core::ptr::null(),
+            )
+        })
+    })
+    .and_then(|()| sys::esp!(unsafe { sys::esp_wifi_set_mode(mode) }));
+    if let Err(err) = started {
+        // Deinitializing also stops the service if it was started
+        unregister();
+        unsafe { sys::wifi_prov_mgr_deinit() };
+        return Err(err);
+    }
+
+    info!(
+        "BLE provisioning started as {}",
+        new_session.service_name.to_string_lossy()
+    );
+    *session = Some(new_session);
+    Ok(())
+}
+
+/// Stops the provisioning service, e.g. because the station was set up
+/// through the portal.
+pub fn stop() {
+    if SESSION.lock().unwrap().is_some() {
+        // Cleaned up on `WIFI_PROV_END`
+        unsafe { sys::wifi_prov_mgr_stop_provisioning() };
+    }
+}
+
+/// Service name and proof of possession of the running service
+pub fn session() -> Option<(String, String)> {
+    SESSION.lock().unwrap().as_ref().map(|session| {
+        (
+            session.service_name.to_string_lossy().into_owned(),
+            session.pop.to_string_lossy().into_owned(),
+        )
+    })
+}
+
+fn unregister() {
+    unsafe {
+        sys::esp_event_handler_unregister(
+            sys::WIFI_PROV_EVENT,
+            sys::ESP_EVENT_ANY_ID,
+            Some(on_event),
+        )
+    };
+}
+
+unsafe extern "C" fn on_event(
+    _: *mut c_void,
+    _: sys::esp_event_base_t,
+    event_id: i32,
+    event_data: *mut c_void,
+) {
+    match event_id as sys::wifi_prov_cb_event_t {
+        sys::wifi_prov_cb_event_t_WIFI_PROV_CRED_RECV => {
+            let config = &*(event_data as *const sys::wifi_sta_config_t);
+            let ssid = until_nul(&config.ssid);
+            info!("received credentials for {ssid} over BLE");
+            let mut settings = ClientSettings {
+                ssid: heapless::String::new(),
+                password: Some(heapless::String::new()),
+                password_set: false,
+                hidden: false,
+                auth_method: ClientAuth::Auto,
+                bssid: None,
+            };
+            // Both fit, the buffers of the driver have the same size
+            let _ = settings.ssid.push_str(&ssid);
+            if let Some(password) = &mut settings.password {
+                let _ = password.push_str(&until_nul(&config.password));
+            }
+            *CREDENTIALS.lock().unwrap() = Some(settings);
+        }
+        sys::wifi_prov_cb_event_t_WIFI_PROV_CRED_FAIL => {
+            let reason = *(event_data as *const sys::wifi_prov_sta_fail_reason_t);
+            let reason = if reason == sys::wifi_prov_sta_fail_reason_t_WIFI_PROV_STA_AUTH_ERROR {
+                "authentication failed"
+            } else {
+                "network not found"
+            };
+            warn!("credentials received over BLE do not work: {reason}");
+            CREDENTIALS.lock().unwrap().take();
+        }
+        sys::wifi_prov_cb_event_t_WIFI_PROV_CRED_SUCCESS => {
+            if let Some(settings) = CREDENTIALS.lock().unwrap().take() {
+                if CMD_QUEUE.enqueue(Command::UpdateClient(settings)).is_err() {
+                    warn!("dropping credentials received over BLE, command queue is full");
+                }
+            }
+        }
+        sys::wifi_prov_cb_event_t_WIFI_PROV_END => {
+            info!("BLE provisioning stopped");
+            unregister();
+            sys::wifi_prov_mgr_deinit();
+            SESSION.lock().unwrap().take();
+        }
+        _ => {}
+    }
+}
+
+fn until_nul(bytes: &[u8]) -> String {
+    let len = bytes
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len]).into_owned()
+}
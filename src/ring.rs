@@ -0,0 +1,78 @@
+//! Buffer of the newest items of a stream, e.g. log records or link samples
+
+/// Keeps the last `N` items pushed, overwriting the oldest ones. Every item
+/// gets a sequence number, which readers use as a cursor.
+#[derive(Debug)]
+pub struct Ring<T, const N: usize> {
+    items: Vec<T>,
+    next_seq: u64,
+}
+
+impl<T, const N: usize> Ring<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            items: Vec::new(),
+            next_seq: 0,
+        }
+    }
+
+    /// Adds `item`, dropping the oldest one if the ring is full.
+    pub fn push(&mut self, item: T) {
+        if self.items.len() < N {
+            self.items.push(item);
+        } else {
+            let index = (self.next_seq % N as u64) as usize;
+            self.items[index] = item;
+        }
+        self.next_seq += 1;
+    }
+
+    /// Sequence number of the next item
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    /// Sequence number of the oldest item that is still kept
+    pub fn first_seq(&self) -> u64 {
+        self.next_seq - self.items.len() as u64
+    }
+
+    /// Items from oldest to newest
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &T> {
+        let split = if self.items.len() < N {
+            0
+        } else {
+            (self.next_seq % N as u64) as usize
+        };
+        self.items[split..].iter().chain(&self.items[..split])
+    }
+}
+
+impl<T, const N: usize> Default for Ring<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overwrites_oldest() {
+        let mut ring = Ring::<u32, 4>::new();
+        assert_eq!(ring.iter().count(), 0);
+        for item in 0..3 {
+            ring.push(item);
+        }
+        assert_eq!(ring.iter().copied().collect::<Vec<_>>(), [0, 1, 2]);
+        assert_eq!((ring.first_seq(), ring.next_seq()), (0, 3));
+
+        for item in 3..10 {
+            ring.push(item);
+        }
+        assert_eq!(ring.iter().copied().collect::<Vec<_>>(), [6, 7, 8, 9]);
+        assert_eq!(ring.iter().next_back(), Some(&9));
+        assert_eq!((ring.first_seq(), ring.next_seq()), (6, 10));
+    }
+}
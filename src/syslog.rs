@@ -0,0 +1,173 @@
+//! Forwards log records to a remote syslog server (RFC 5424 over UDP)
+//!
+//! Records are taken from the ring buffer of [`logs`], so whatever was logged
+//! while the station was offline is sent once it is connected again, as long
+//! as it has not been overwritten in the meantime.
+
+use crate::{logs, wifi::InvalidSettings};
+use core::{ffi::CStr, time::Duration};
+use esp_idf_sys as sys;
+use log::LevelFilter;
+use soft_ap::syslog::message::{connect, format_message};
+use std::{net::UdpSocket, sync::Mutex, thread};
+
+const DEFAULT_PORT: u16 = 514;
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+static CONFIG: Mutex<Option<SyslogConfig>> = Mutex::new(None);
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SyslogConfig {
+    /// Host name or IP address, empty disables forwarding
+    pub server: heapless::String<64>,
+    #[serde(default = "default_port")]
+    pub port: u16,
+}
+
+impl Default for SyslogConfig {
+    fn default() -> Self {
+        Self {
+            server: heapless::String::new(),
+            port: DEFAULT_PORT,
+        }
+    }
+}
+
+fn default_port() -> u16 {
+    DEFAULT_PORT
+}
+
+impl SyslogConfig {
+    pub fn validate(&self) -> Result<(), InvalidSettings> {
+        if !self.server.chars().all(|c| c.is_ascii_graphic()) {
+            return Err(InvalidSettings {
+                field: "syslog.server",
+                message: "must be a host name or IP address",
+            });
+        }
+        if self.port == 0 {
+            return Err(InvalidSettings {
+                field: "syslog.port",
+                message: "must be between 1 and 65535",
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Applies a new configuration to the running forwarder.
+pub fn configure(config: SyslogConfig) {
+    *CONFIG.lock().unwrap() = (!config.server.is_empty()).then_some(config);
+}
+
+/// Starts the thread that forwards log records.
+pub fn spawn() -> std::io::Result<()> {
+    thread::Builder::new()
+        .name("syslog".into())
+        .stack_size(6 * 1024)
+        .spawn(run)?;
+    Ok(())
+}
+
+fn run() {
+    let mut cursor = 0;
+    let mut connection: Option<(SyslogConfig, UdpSocket)> = None;
+    let mut message = String::new();
+    let mut unreachable = false;
+    loop {
+        thread::sleep(POLL_INTERVAL);
+
+        let Some(config) = CONFIG.lock().unwrap().clone() else {
+            connection = None;
+            continue;
+        };
+        // Keep the records buffered until there is a way to send them
+        let Some(hostname) = sta_hostname() else {
+            continue;
+        };
+        if connection
+            .as_ref()
+            .map_or(true, |(connected, _)| *connected != config)
+        {
+            connection = match connect(&config.server, config.port) {
+                Ok(socket) => {
+                    unreachable = false;
+                    Some((config, socket))
+                }
+                Err(err) => {
+                    // Retried with the next poll, but only reported once
+                    if !unreachable {
+                        log::warn!(
+                            "syslog server {}:{} unreachable: {err}",
+                            config.server,
+                            config.port
+                        );
+                        unreachable = true;
+                    }
+                    continue;
+                }
+            };
+        }
+        let Some((_, socket)) = &connection else {
+            continue;
+        };
+
+        let (entries, next) = logs::entries(cursor, LevelFilter::Info);
+        let now = unix_time_us();
+        let uptime_ms = (unsafe { sys::esp_timer_get_time() } / 1000) as u64;
+        for entry in &entries {
+            // Buffered records keep the time they were logged at
+            let timestamp = now.map(|now| {
+                let at = now - 1000 * uptime_ms.saturating_sub(entry.uptime_ms) as i64;
+                (at.div_euclid(1_000_000), at.rem_euclid(1_000_000) as u32)
+            });
+            message.clear();
+            format_message(
+                &mut message,
+                entry.level,
+                &entry.target,
+                &entry.message,
+                &hostname,
+                timestamp,
+            );
+            if socket.send(message.as_bytes()).is_err() {
+                // Retried with the next poll
+                break;
+            }
+            cursor = entry.seq + 1;
+        }
+        if entries.is_empty() {
+            cursor = next;
+        }
+    }
+}
+
+/// Host name of the station interface, `None` while it has no IP address
+fn sta_hostname() -> Option<String> {
+    let netif = unsafe { sys::esp_netif_get_handle_from_ifkey(b"WIFI_STA_DEF\0".as_ptr().cast()) };
+    if netif.is_null() {
+        return None;
+    }
+    let mut ip_info = sys::esp_netif_ip_info_t::default();
+    sys::esp!(unsafe { sys::esp_netif_get_ip_info(netif, &mut ip_info) }).ok()?;
+    if ip_info.ip.addr == 0 {
+        return None;
+    }
+    let mut hostname = core::ptr::null();
+    sys::esp!(unsafe { sys::esp_netif_get_hostname(netif, &mut hostname) }).ok()?;
+    Some(if hostname.is_null() {
+        "-".to_owned()
+    } else {
+        unsafe { CStr::from_ptr(hostname) }
+            .to_string_lossy()
+            .into_owned()
+    })
+}
+
+/// Wall clock time in microseconds, `None` as long as it has not been set
+fn unix_time_us() -> Option<i64> {
+    let mut now = sys::timeval::default();
+    unsafe { sys::gettimeofday(&mut now, core::ptr::null_mut()) };
+    // Anything before 2023 means the clock was never set
+    (now.tv_sec >= 1_672_531_200).then(|| now.tv_sec as i64 * 1_000_000 + now.tv_usec as i64)
+}
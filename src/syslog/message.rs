@@ -0,0 +1,182 @@
+//! RFC 5424 syslog messages and the UDP socket they are sent over
+
+use core::fmt::Write as _;
+use log::Level;
+use std::net::{ToSocketAddrs, UdpSocket};
+
+/// `user-level messages`
+const FACILITY: u8 = 1;
+
+/// Opens a socket to the syslog server `server`, a host name or IP address.
+pub fn connect(server: &str, port: u16) -> std::io::Result<UdpSocket> {
+    let server = (server, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or(std::io::ErrorKind::NotFound)?;
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(server)?;
+    Ok(socket)
+}
+
+/// Formats a record as RFC 5424 syslog message.
+///
+/// `timestamp` is the wall clock time as seconds and microseconds since the
+/// Unix epoch, if known.
+pub fn format_message(
+    out: &mut String,
+    level: Level,
+    target: &str,
+    message: &str,
+    hostname: &str,
+    timestamp: Option<(i64, u32)>,
+) {
+    let severity = match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    };
+    let _ = write!(out, "<{}>1 ", FACILITY * 8 + severity);
+    match timestamp {
+        Some((secs, micros)) => {
+            let (year, month, day) = civil_from_days(secs.div_euclid(86_400));
+            let secs_of_day = secs.rem_euclid(86_400);
+            let _ = write!(
+                out,
+                "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{micros:06}Z",
+                secs_of_day / 3600,
+                secs_of_day / 60 % 60,
+                secs_of_day % 60
+            );
+        }
+        None => out.push('-'),
+    }
+    out.push(' ');
+    push_header_field(out, hostname, 255);
+    out.push(' ');
+    push_header_field(out, env!("CARGO_PKG_NAME"), 48);
+    // PROCID
+    out.push_str(" - ");
+    push_header_field(out, target, 32);
+    // STRUCTURED-DATA
+    out.push_str(" - ");
+    out.push_str(message);
+}
+
+/// Header fields are limited to printable ASCII without spaces, `-` stands
+/// for an empty value.
+fn push_header_field(out: &mut String, value: &str, max_len: usize) {
+    let start = out.len();
+    out.extend(value.chars().filter(|c| c.is_ascii_graphic()).take(max_len));
+    if out.len() == start {
+        out.push('-');
+    }
+}
+
+/// Converts days since the Unix epoch to a (year, month, day) date.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(level: Level, target: &str, hostname: &str, timestamp: Option<(i64, u32)>) -> String {
+        let mut out = String::new();
+        format_message(
+            &mut out,
+            level,
+            target,
+            "connected to Home",
+            hostname,
+            timestamp,
+        );
+        out
+    }
+
+    #[test]
+    fn message() {
+        assert_eq!(
+            format(Level::Info, "soft_ap::wifi", "soft-ap-1", Some((1_700_000_000, 5))),
+            "<14>1 2023-11-14T22:13:20.000005Z soft-ap-1 soft-ap - soft_ap::wifi - connected to Home"
+        );
+    }
+
+    #[test]
+    fn priority() {
+        for (level, priority) in [
+            (Level::Error, "<11>1 "),
+            (Level::Warn, "<12>1 "),
+            (Level::Info, "<14>1 "),
+            (Level::Debug, "<15>1 "),
+            (Level::Trace, "<15>1 "),
+        ] {
+            assert!(format(level, "main", "host", None).starts_with(priority));
+        }
+    }
+
+    #[test]
+    fn timestamp() {
+        for (timestamp, formatted) in [
+            ((0, 0), "1970-01-01T00:00:00.000000Z"),
+            ((-1, 999_999), "1969-12-31T23:59:59.999999Z"),
+            ((951_782_400, 0), "2000-02-29T00:00:00.000000Z"),
+            ((1_709_164_799, 123_456), "2024-02-28T23:59:59.123456Z"),
+            ((4_107_542_400, 0), "2100-03-01T00:00:00.000000Z"),
+        ] {
+            let message = format(Level::Info, "main", "host", Some(timestamp));
+            assert_eq!(&message[6..6 + formatted.len()], formatted);
+        }
+        assert!(format(Level::Info, "main", "host", None).starts_with("<14>1 - host "));
+    }
+
+    #[test]
+    fn nil_values() {
+        assert_eq!(
+            format(Level::Info, "", "", None),
+            "<14>1 - - soft-ap - - - connected to Home"
+        );
+    }
+
+    #[test]
+    fn header_fields() {
+        // Spaces and non-ASCII characters would split or corrupt the header
+        assert_eq!(
+            format(Level::Info, "wifi scan \u{fc}", "my host\n", None),
+            "<14>1 - myhost soft-ap - wifiscan - connected to Home"
+        );
+        assert_eq!(
+            format(Level::Info, "\u{fc} ", "\t", None),
+            "<14>1 - - soft-ap - - - connected to Home"
+        );
+        let target = "a".repeat(40);
+        let message = format(Level::Info, &target, "host", None);
+        assert!(message.contains(&format!(" - {} - ", "a".repeat(32))));
+    }
+
+    #[test]
+    fn send() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = server.local_addr().unwrap().port();
+        let message = format(Level::Warn, "main", "host", Some((0, 0)));
+        connect("127.0.0.1", port)
+            .unwrap()
+            .send(message.as_bytes())
+            .unwrap();
+
+        let mut buf = [0; 256];
+        let len = server.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], message.as_bytes());
+    }
+}
@@ -1,16 +1,25 @@
-use crate::wifi::{self, default_ap_config, ApMode, WifiInfo};
-use askama::Template;
-use embedded_svc::{
-    ipv4,
-    wifi::{AccessPointConfiguration, AuthMethod, ClientConfiguration},
+use crate::{
+    api::Mac,
+    convert,
+    syslog::SyslogConfig,
+    wifi::{self, default_ap_config, ApMode, ClientAuth, Country, Roaming, WifiInfo},
 };
-use enumset::EnumSet;
+use askama::Template;
+use embedded_svc::ipv4;
+
+pub use soft_ap::template::password;
 
 #[derive(Debug, Clone, Template, serde::Serialize, serde::Deserialize)]
 #[template(path = "wifi.html")]
 pub struct WifiSettingsTemplate {
     pub client: WifiClientSettings,
     pub ap: WifiApSettings,
+    /// Stored on its own, it is not part of the trial period of the WiFi
+    /// settings
+    #[serde(default)]
+    pub syslog: SyslogConfig,
+    #[serde(skip)]
+    pub csrf_token: String,
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -21,6 +30,15 @@ pub struct WifiClientSettings {
     pub gateway: [u8; 4],
     pub subnet_mask: [u8; 4],
     pub mdns: heapless::String<32>,
+    #[serde(default)]
+    pub hidden: bool,
+    #[serde(default)]
+    pub auth_method: ClientAuth,
+    /// Pinned access point
+    #[serde(default)]
+    pub bssid: Option<Mac>,
+    #[serde(default)]
+    pub roaming: Roaming,
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -30,6 +48,44 @@ pub struct WifiApSettings {
     pub password: heapless::String<64>,
     pub channel: u8,
     pub mode: ApMode,
+    #[serde(default)]
+    pub country: Country,
+}
+
+#[derive(Debug, Clone, Template)]
+#[template(path = "ota.html")]
+pub struct OtaTemplate {
+    pub version: &'static str,
+    pub partition: String,
+    pub csrf_token: String,
+}
+
+#[derive(Debug, Clone, Template)]
+#[template(path = "setup.html")]
+pub struct SetupTemplate {
+    pub username: &'static str,
+    pub min_password_len: usize,
+    pub csrf_token: String,
+}
+
+#[derive(Debug, Clone, Template)]
+#[template(path = "tls.html")]
+pub struct TlsTemplate {
+    pub enabled: bool,
+    pub fingerprint: String,
+    pub csrf_token: String,
+}
+
+#[derive(Debug, Clone, Template)]
+#[template(path = "logs.html")]
+pub struct LogsTemplate {
+    pub csrf_token: String,
+}
+
+#[derive(Debug, Clone, Template)]
+#[template(path = "config.html")]
+pub struct ConfigTemplate {
+    pub csrf_token: String,
 }
 
 impl From<WifiInfo> for WifiSettingsTemplate {
@@ -39,39 +95,66 @@ impl From<WifiInfo> for WifiSettingsTemplate {
             sta_config,
             ap_config,
             ap_mode,
+            country,
+            sta_hidden,
+            roaming,
         }: WifiInfo,
     ) -> Self {
+        let auth_method = sta_config.as_ref().map_or(ClientAuth::Auto, |config| {
+            ClientAuth::from_method(config.auth_method)
+        });
         let sta_config = sta_config.unwrap_or_default();
         let ap_config = ap_config.unwrap_or_else(default_ap_config);
         Self {
             client: WifiClientSettings {
                 ssid: sta_config.ssid,
-                password: sta_config.password,
+                password: password::placeholder(&sta_config.password),
                 ip: ip_info.ip.octets(),
                 gateway: ip_info.subnet.gateway.octets(),
                 subnet_mask: (!(u32::MAX >> u32::from(ip_info.subnet.mask.0))).to_be_bytes(),
                 mdns: Default::default(),
+                hidden: sta_hidden,
+                auth_method,
+                bssid: sta_config.bssid.map(Mac),
+                roaming,
             },
             ap: WifiApSettings {
                 ssid: ap_config.ssid,
                 hidden: ap_config.ssid_hidden,
-                password: ap_config.password,
+                password: password::placeholder(&ap_config.password),
                 channel: ap_config.channel,
                 mode: ap_mode,
+                country,
             },
+            syslog: SyslogConfig::default(),
+            csrf_token: String::new(),
         }
     }
 }
 
-impl TryFrom<WifiSettingsTemplate> for WifiInfo {
-    type Error = esp_idf_sys::EspError;
-
-    fn try_from(
-        WifiSettingsTemplate { client, ap }: WifiSettingsTemplate,
-    ) -> Result<Self, Self::Error> {
-        let ap_info = wifi::scan_aps()?
-            .into_iter()
-            .find(|ap| (ap.ssid == client.ssid));
+impl WifiSettingsTemplate {
+    /// Converts the submitted settings into a [`WifiInfo`]. Passwords that
+    /// come back as [`password::PLACEHOLDER`] are kept from `current`.
+    pub fn into_info(self, current: &WifiInfo) -> Result<WifiInfo, esp_idf_sys::EspError> {
+        let Self {
+            mut client, mut ap, ..
+        } = self;
+        client.password = password::submitted(
+            client.password,
+            current
+                .sta_config
+                .as_ref()
+                .map(|config| config.password.clone())
+                .unwrap_or_default(),
+        );
+        ap.password = password::submitted(
+            ap.password,
+            current
+                .ap_config
+                .clone()
+                .unwrap_or_else(default_ap_config)
+                .password,
+        );
 
         let ip_info = ipv4::IpInfo {
             ip: ipv4::Ipv4Addr::from(client.ip),
@@ -82,39 +165,99 @@ impl TryFrom<WifiSettingsTemplate> for WifiInfo {
             dns: None,
             secondary_dns: None,
         };
-        let sta_config = (!client.ssid.is_empty()).then_some(ClientConfiguration {
-            ssid: client.ssid,
-            bssid: None,
-            auth_method: ap_info
-                .as_ref()
-                .map(|ap| ap.auth_method)
-                .unwrap_or_else(|| {
-                    if client.password.is_empty() {
-                        AuthMethod::None
-                    } else {
-                        AuthMethod::WPA2Personal
-                    }
-                }),
-            password: client.password,
-            channel: ap_info.map(|ap| ap.channel),
-        });
-
-        let ap_config = (!ap.ssid.is_empty()).then_some(AccessPointConfiguration {
-            ssid: ap.ssid,
-            ssid_hidden: ap.hidden,
-            channel: ap.channel,
-            secondary_channel: None,
-            protocols: EnumSet::empty(),
-            auth_method: AuthMethod::WPA2Personal,
-            password: ap.password,
-            max_connections: 10,
-        });
+        let sta_config = if client.ssid.is_empty() {
+            None
+        } else {
+            Some(wifi::client_config(
+                client.ssid,
+                client.password,
+                client.bssid.map(|Mac(mac)| mac),
+                client.hidden,
+                client.auth_method,
+            )?)
+        };
+        let ap_config = (!ap.ssid.is_empty())
+            .then(|| wifi::ap_config(ap.ssid, ap.password, ap.hidden, ap.channel));
 
-        Ok(Self {
+        Ok(WifiInfo {
             ip_info,
             sta_config,
             ap_config,
             ap_mode: ap.mode,
+            country: ap.country,
+            sta_hidden: client.hidden && sta_config.is_some(),
+            roaming: client.roaming,
         })
     }
+
+    /// The pinned access point, empty if there is none
+    pub fn client_bssid(&self) -> String {
+        self.client
+            .bssid
+            .map(|Mac(mac)| convert::format_mac(&mac))
+            .unwrap_or_default()
+    }
+
+    /// Value, label and the `selected` attribute of the authentication
+    /// select of the client network
+    pub fn auth_options(&self) -> Vec<(&'static str, &'static str, &'static str)> {
+        [
+            (ClientAuth::Auto, "auto", "Automatic"),
+            (ClientAuth::Open, "open", "Open"),
+            (ClientAuth::Wep, "wep", "WEP"),
+            (ClientAuth::Wpa, "wpa", "WPA"),
+            (ClientAuth::Wpa2, "wpa2", "WPA2"),
+            (ClientAuth::WpaWpa2, "wpa_wpa2", "WPA/WPA2"),
+            (ClientAuth::Wpa3, "wpa3", "WPA3"),
+            (ClientAuth::Wpa2Wpa3, "wpa2_wpa3", "WPA2/WPA3"),
+        ]
+        .into_iter()
+        .map(|(auth, value, label)| (value, label, selected(auth == self.client.auth_method)))
+        .collect()
+    }
+
+    /// Code, name, highest channel and the `selected` attribute of the
+    /// country select
+    pub fn country_options(&self) -> Vec<(Country, &'static str, u8, &'static str)> {
+        Country::ALL
+            .iter()
+            .map(|country| {
+                let name = if *country == Country::WORLD {
+                    "World"
+                } else {
+                    country.as_str()
+                };
+                (
+                    *country,
+                    name,
+                    *country.channels().end(),
+                    selected(*country == self.ap.country),
+                )
+            })
+            .collect()
+    }
+
+    /// Value, label and the `selected` attribute of the channel select:
+    /// automatic and the channels allowed in the current country
+    pub fn channel_options(&self) -> Vec<(u8, String, &'static str)> {
+        core::iter::once(wifi::AUTO_CHANNEL)
+            .chain(self.ap.country.channels())
+            .map(|channel| {
+                let label = if channel == wifi::AUTO_CHANNEL {
+                    "Auto".to_owned()
+                } else {
+                    channel.to_string()
+                };
+                (channel, label, selected(channel == self.ap.channel))
+            })
+            .collect()
+    }
+}
+
+fn selected(selected: bool) -> &'static str {
+    if selected {
+        "selected"
+    } else {
+        ""
+    }
 }
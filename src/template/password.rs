@@ -0,0 +1,58 @@
+//! Stored passwords in the portal, which never sends them back to the browser
+
+/// Sent to the page instead of a stored password. WPA passphrases consist of
+/// printable ASCII characters only, so it can not clash with a real password.
+pub const PLACEHOLDER: &str = "••••••••";
+
+/// What the page shows for the `stored` password, nothing if there is none
+pub fn placeholder(stored: &str) -> heapless::String<64> {
+    if stored.is_empty() {
+        heapless::String::new()
+    } else {
+        heapless::String::from(PLACEHOLDER)
+    }
+}
+
+/// The password to store for a `submitted` one: the [`PLACEHOLDER`] keeps the
+/// `stored` password, anything else replaces it and an empty one clears it.
+pub fn submitted(
+    submitted: heapless::String<64>,
+    stored: heapless::String<64>,
+) -> heapless::String<64> {
+    if submitted == PLACEHOLDER {
+        stored
+    } else {
+        submitted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unchanged() {
+        for stored in ["correct horse", ""] {
+            let shown = placeholder(stored);
+            assert_eq!(shown, if stored.is_empty() { "" } else { PLACEHOLDER });
+            assert_eq!(submitted(shown, stored.into()), stored);
+        }
+    }
+
+    #[test]
+    fn replaced() {
+        assert_eq!(
+            submitted("battery staple".into(), "correct horse".into()),
+            "battery staple"
+        );
+        assert_eq!(
+            submitted("battery staple".into(), "".into()),
+            "battery staple"
+        );
+    }
+
+    #[test]
+    fn cleared() {
+        assert_eq!(submitted("".into(), "correct horse".into()), "");
+    }
+}
@@ -0,0 +1,367 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use core::{
+    ffi::{c_void, CStr},
+    fmt,
+};
+use embedded_svc::storage::RawStorage;
+use esp_idf_svc::{nvs, tls::X509};
+use esp_idf_sys::{self as sys, mbedtls};
+use sha2::{Digest, Sha256};
+use std::{
+    ffi::CString,
+    sync::{Arc, Mutex},
+};
+
+pub type SharedTls = Arc<Mutex<TlsStorage<nvs::NvsDefault>>>;
+
+/// Persists the TLS identity of the settings server and whether HTTPS is
+/// enabled.
+pub struct TlsStorage<P: nvs::NvsPartitionId> {
+    nvs: nvs::EspNvs<P>,
+}
+
+impl<P: nvs::NvsPartitionId> TlsStorage<P> {
+    const ENABLED_KEY: &str = "enabled";
+    const CERTIFICATE_KEY: &str = "cert";
+    const PRIVATE_KEY_KEY: &str = "key";
+
+    pub fn new(nvs_partition: nvs::EspNvsPartition<P>) -> Result<Self, sys::EspError> {
+        let nvs = nvs::EspNvs::new(nvs_partition, "tls", true)?;
+        Ok(Self { nvs })
+    }
+
+    pub fn is_enabled(&self) -> Result<bool, sys::EspError> {
+        let mut buf = [0u8; 1];
+        Ok(self
+            .nvs
+            .get_raw(Self::ENABLED_KEY, &mut buf)?
+            .map_or(false, |enabled| enabled[0] != 0))
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) -> Result<(), sys::EspError> {
+        self.nvs.set_raw(Self::ENABLED_KEY, &[enabled.into()])?;
+        Ok(())
+    }
+
+    pub fn get_identity(&self) -> Result<Option<Identity>, anyhow::Error> {
+        let (Some(certificate), Some(private_key)) = (
+            self.get_string(Self::CERTIFICATE_KEY)?,
+            self.get_string(Self::PRIVATE_KEY_KEY)?,
+        ) else {
+            return Ok(None);
+        };
+        Ok(Some(Identity {
+            certificate,
+            private_key,
+        }))
+    }
+
+    pub fn set_identity(&mut self, identity: &Identity) -> Result<(), sys::EspError> {
+        self.nvs
+            .set_raw(Self::CERTIFICATE_KEY, identity.certificate.as_bytes())?;
+        self.nvs
+            .set_raw(Self::PRIVATE_KEY_KEY, identity.private_key.as_bytes())?;
+        Ok(())
+    }
+
+    fn get_string(&self, key: &str) -> Result<Option<String>, anyhow::Error> {
+        let Some(len) = self.nvs.len(key)? else {
+            return Ok(None);
+        };
+        let mut buf = vec![0; len];
+        self.nvs.get_raw(key, &mut buf)?;
+        Ok(Some(String::from_utf8(buf)?))
+    }
+}
+
+/// A PEM encoded certificate together with its private key
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub certificate: String,
+    pub private_key: String,
+}
+
+impl Identity {
+    /// Generates a self-signed ECDSA (P-256) certificate for `common_name`.
+    pub fn generate(common_name: &str) -> Result<Self, TlsError> {
+        let subject = CString::new(format!("CN={common_name}")).map_err(|_| TlsError(-1))?;
+        let mut serial = [0u8; 16];
+        fill_random(&mut serial);
+        // Serial numbers have to be positive
+        serial[0] &= 0x7f;
+
+        unsafe {
+            let mut key: mbedtls::mbedtls_pk_context = core::mem::zeroed();
+            let mut certificate: mbedtls::mbedtls_x509write_cert = core::mem::zeroed();
+            let mut serial_mpi: mbedtls::mbedtls_mpi = core::mem::zeroed();
+            mbedtls::mbedtls_pk_init(&mut key);
+            mbedtls::mbedtls_x509write_crt_init(&mut certificate);
+            mbedtls::mbedtls_mpi_init(&mut serial_mpi);
+
+            let result = (|| {
+                check(mbedtls::mbedtls_pk_setup(
+                    &mut key,
+                    mbedtls::mbedtls_pk_info_from_type(mbedtls::mbedtls_pk_type_t_MBEDTLS_PK_ECKEY),
+                ))?;
+                check(mbedtls::mbedtls_ecp_gen_key(
+                    mbedtls::mbedtls_ecp_group_id_MBEDTLS_ECP_DP_SECP256R1,
+                    key.pk_ctx.cast(),
+                    Some(random),
+                    core::ptr::null_mut(),
+                ))?;
+                check(mbedtls::mbedtls_mpi_read_binary(
+                    &mut serial_mpi,
+                    serial.as_ptr(),
+                    serial.len(),
+                ))?;
+
+                mbedtls::mbedtls_x509write_crt_set_version(
+                    &mut certificate,
+                    mbedtls::MBEDTLS_X509_CRT_VERSION_3 as _,
+                );
+                mbedtls::mbedtls_x509write_crt_set_md_alg(
+                    &mut certificate,
+                    mbedtls::mbedtls_md_type_t_MBEDTLS_MD_SHA256,
+                );
+                mbedtls::mbedtls_x509write_crt_set_subject_key(&mut certificate, &mut key);
+                mbedtls::mbedtls_x509write_crt_set_issuer_key(&mut certificate, &mut key);
+                check(mbedtls::mbedtls_x509write_crt_set_subject_name(
+                    &mut certificate,
+                    subject.as_ptr(),
+                ))?;
+                check(mbedtls::mbedtls_x509write_crt_set_issuer_name(
+                    &mut certificate,
+                    subject.as_ptr(),
+                ))?;
+                check(mbedtls::mbedtls_x509write_crt_set_serial(
+                    &mut certificate,
+                    &serial_mpi,
+                ))?;
+                check(mbedtls::mbedtls_x509write_crt_set_validity(
+                    &mut certificate,
+                    b"20230101000000\0".as_ptr().cast(),
+                    b"20491231235959\0".as_ptr().cast(),
+                ))?;
+                check(mbedtls::mbedtls_x509write_crt_set_basic_constraints(
+                    &mut certificate,
+                    0,
+                    -1,
+                ))?;
+
+                let mut buf = vec![0u8; 2048];
+                check(mbedtls::mbedtls_x509write_crt_pem(
+                    &mut certificate,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    Some(random),
+                    core::ptr::null_mut(),
+                ))?;
+                let certificate = pem_from_buf(&buf);
+
+                buf.fill(0);
+                check(mbedtls::mbedtls_pk_write_key_pem(
+                    &mut key,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                ))?;
+                let private_key = pem_from_buf(&buf);
+                buf.fill(0);
+
+                Ok(Self {
+                    certificate,
+                    private_key,
+                })
+            })();
+
+            mbedtls::mbedtls_mpi_free(&mut serial_mpi);
+            mbedtls::mbedtls_x509write_crt_free(&mut certificate);
+            mbedtls::mbedtls_pk_free(&mut key);
+            result
+        }
+    }
+
+    /// Checks that an uploaded certificate and key can be parsed and belong
+    /// together.
+    pub fn parse(certificate: String, private_key: String) -> Result<Self, TlsError> {
+        let certificate_pem = CString::new(certificate.as_str()).map_err(|_| TlsError(-1))?;
+        let private_key_pem = CString::new(private_key.as_str()).map_err(|_| TlsError(-1))?;
+
+        unsafe {
+            let mut crt: mbedtls::mbedtls_x509_crt = core::mem::zeroed();
+            let mut key: mbedtls::mbedtls_pk_context = core::mem::zeroed();
+            mbedtls::mbedtls_x509_crt_init(&mut crt);
+            mbedtls::mbedtls_pk_init(&mut key);
+
+            let result = (|| {
+                let certificate_pem = certificate_pem.as_bytes_with_nul();
+                check(mbedtls::mbedtls_x509_crt_parse(
+                    &mut crt,
+                    certificate_pem.as_ptr(),
+                    certificate_pem.len(),
+                ))?;
+                let private_key_pem = private_key_pem.as_bytes_with_nul();
+                check(mbedtls::mbedtls_pk_parse_key(
+                    &mut key,
+                    private_key_pem.as_ptr(),
+                    private_key_pem.len(),
+                    core::ptr::null(),
+                    0,
+                ))?;
+                check(mbedtls::mbedtls_pk_check_pair(&mut crt.pk, &mut key))
+            })();
+
+            mbedtls::mbedtls_pk_free(&mut key);
+            mbedtls::mbedtls_x509_crt_free(&mut crt);
+            result?;
+        }
+
+        Ok(Self {
+            certificate,
+            private_key,
+        })
+    }
+
+    /// Returns the SHA-256 fingerprint of the (DER encoded) certificate.
+    pub fn fingerprint(&self) -> Option<[u8; 32]> {
+        let base64: String = self
+            .certificate
+            .lines()
+            .skip_while(|line| !line.starts_with("-----BEGIN"))
+            .skip(1)
+            .take_while(|line| !line.starts_with("-----END"))
+            .collect();
+        let der = BASE64.decode(base64.trim()).ok()?;
+        Some(Sha256::digest(der).into())
+    }
+
+    /// Converts the identity for the HTTPS server, which keeps referring to
+    /// the PEM data for as long as it runs.
+    pub fn into_x509(self) -> (X509<'static>, X509<'static>) {
+        fn leak(pem: String) -> &'static [u8] {
+            let mut bytes = pem.into_bytes();
+            bytes.push(0);
+            Box::leak(bytes.into_boxed_slice())
+        }
+
+        (
+            X509::pem_until_nul(leak(self.certificate)),
+            X509::pem_until_nul(leak(self.private_key)),
+        )
+    }
+}
+
+/// Formats a fingerprint the way browsers show it, e.g. `AB:CD:...`.
+pub fn format_fingerprint(fingerprint: &[u8]) -> String {
+    fingerprint
+        .iter()
+        .map(|byte| format!("{byte:02X}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// An error code returned by mbedTLS
+#[derive(Debug, Clone, Copy)]
+pub struct TlsError(i32);
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "mbedTLS error -0x{:04X}", self.0.unsigned_abs())
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+fn check(code: i32) -> Result<(), TlsError> {
+    if code == 0 {
+        Ok(())
+    } else {
+        Err(TlsError(code))
+    }
+}
+
+fn pem_from_buf(buf: &[u8]) -> String {
+    unsafe { CStr::from_ptr(buf.as_ptr().cast()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn fill_random(buf: &mut [u8]) {
+    unsafe { sys::esp_fill_random(buf.as_mut_ptr().cast(), buf.len() as _) };
+}
+
+unsafe extern "C" fn random(_: *mut c_void, output: *mut u8, len: usize) -> i32 {
+    sys::esp_fill_random(output.cast(), len as _);
+    0
+}
+
+/// Plain HTTP server that redirects every request to HTTPS.
+///
+/// It is started directly through `esp_http_server`, because it needs a
+/// different control port than the HTTPS server.
+pub struct RedirectServer(sys::httpd_handle_t);
+
+impl RedirectServer {
+    pub fn start() -> Result<Self, sys::EspError> {
+        let config = sys::httpd_config_t {
+            task_priority: 5,
+            stack_size: 4096,
+            core_id: i32::MAX,
+            server_port: 80,
+            ctrl_port: 32769,
+            max_open_sockets: 3,
+            max_uri_handlers: 1,
+            max_resp_headers: 4,
+            backlog_conn: 5,
+            lru_purge_enable: true,
+            recv_wait_timeout: 5,
+            send_wait_timeout: 5,
+            uri_match_fn: Some(sys::httpd_uri_match_wildcard),
+            ..Default::default()
+        };
+        let mut handle = core::ptr::null_mut();
+        unsafe { sys::esp!(sys::httpd_start(&mut handle, &config))? };
+        let server = Self(handle);
+
+        let uri = sys::httpd_uri_t {
+            uri: b"/*\0".as_ptr().cast(),
+            method: sys::http_method_HTTP_GET as _,
+            handler: Some(redirect),
+            user_ctx: core::ptr::null_mut(),
+            ..Default::default()
+        };
+        unsafe { sys::esp!(sys::httpd_register_uri_handler(server.0, &uri))? };
+        Ok(server)
+    }
+}
+
+impl Drop for RedirectServer {
+    fn drop(&mut self) {
+        unsafe { sys::httpd_stop(self.0) };
+    }
+}
+
+unsafe extern "C" fn redirect(request: *mut sys::httpd_req_t) -> sys::esp_err_t {
+    let mut host = [0u8; 64];
+    let host = if sys::esp!(sys::httpd_req_get_hdr_value_str(
+        request,
+        b"Host\0".as_ptr().cast(),
+        host.as_mut_ptr().cast(),
+        host.len(),
+    ))
+    .is_ok()
+    {
+        CStr::from_ptr(host.as_ptr().cast()).to_string_lossy()
+    } else {
+        return sys::httpd_resp_send_404(request);
+    };
+    // Drop the port of the plain HTTP server, if any
+    let host = host.split(':').next().unwrap_or_default();
+    let uri = CStr::from_ptr((*request).uri.as_ptr()).to_string_lossy();
+    let Ok(location) = CString::new(format!("https://{host}{uri}")) else {
+        return sys::httpd_resp_send_404(request);
+    };
+
+    sys::httpd_resp_set_status(request, b"301 Moved Permanently\0".as_ptr().cast());
+    sys::httpd_resp_set_hdr(request, b"Location\0".as_ptr().cast(), location.as_ptr());
+    sys::httpd_resp_send(request, core::ptr::null(), 0)
+}
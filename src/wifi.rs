@@ -1,6 +1,19 @@
 pub mod storage;
 
-use crate::convert::Newtype;
+pub use soft_ap::wifi::{ap_mode, channel, country, info, roaming, scan, security};
+
+pub use ap_mode::ApMode;
+pub use country::Country;
+pub use info::{default_ap_config, InvalidSettings, WifiInfo, AUTO_CHANNEL};
+pub use roaming::Roaming;
+pub use scan::ScanOptions;
+pub use security::ClientAuth;
+
+use self::{channel::Neighbor, roaming::Candidate};
+use crate::{
+    convert::Newtype,
+    events::{self, Event},
+};
 use core::time::Duration;
 use embedded_svc::{
     ipv4,
@@ -15,73 +28,187 @@ use esp_idf_svc::{
     handle::RawHandle,
     wifi::{EspWifi, WifiWait},
 };
-use esp_idf_sys as sys;
-
-/// This holds a WiFi configuration
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
-pub struct WifiInfo {
-    pub ip_info: ipv4::IpInfo,
-    pub sta_config: Option<ClientConfiguration>,
-    pub ap_config: Option<AccessPointConfiguration>,
-    pub ap_mode: ApMode,
-}
-
-#[derive(
-    Debug,
-    Clone,
-    Copy,
-    PartialEq,
-    Eq,
-    Default,
-    serde_repr::Serialize_repr,
-    serde_repr::Deserialize_repr,
-)]
-#[repr(u8)]
-pub enum ApMode {
-    #[default]
-    NoConnOnBoot = 0,
-    Always = 2,
-    Never = 3,
-}
-
-const DEDAULT_IP_INFO: ipv4::IpInfo = ipv4::IpInfo {
-    ip: ipv4::Ipv4Addr::UNSPECIFIED,
-    subnet: ipv4::Subnet {
-        gateway: ipv4::Ipv4Addr::UNSPECIFIED,
-        mask: ipv4::Mask(24),
-    },
-    dns: None,
-    secondary_dns: None,
-};
+use esp_idf_sys::{self as sys, wnm};
+use log::{info, warn};
+
+/// A configuration that has been applied, but is only persisted once it is
+/// known to work.
+#[derive(Debug, Clone)]
+pub struct PendingUpdate {
+    pub info: WifiInfo,
+    pub last_known_good: WifiInfo,
+    deadline: Duration,
+}
+
+impl PendingUpdate {
+    /// Time after which an unconfirmed configuration is rolled back
+    pub const TIMEOUT: Duration = Duration::from_secs(60);
+
+    pub fn new(info: WifiInfo, last_known_good: WifiInfo, now: Duration) -> Self {
+        Self {
+            info,
+            last_known_good,
+            deadline: now + Self::TIMEOUT,
+        }
+    }
+
+    pub fn is_expired(&self, now: Duration) -> bool {
+        now >= self.deadline
+    }
+}
+
+/// Builds the configuration for joining `ssid`, only through the access point
+/// `bssid` if it is set. The channel is taken from a scan if the network is in
+/// range, the authentication method as described at [`security::resolve`].
+pub fn client_config(
+    ssid: heapless::String<32>,
+    password: heapless::String<64>,
+    bssid: Option<[u8; 6]>,
+    hidden: bool,
+    auth: ClientAuth,
+) -> Result<ClientConfiguration, sys::EspError> {
+    // Probes for the name make hidden networks answer as well
+    let options = ScanOptions {
+        bssid,
+        ..ScanOptions::targeted(ssid.clone(), None, hidden)
+    };
+    let ap_info = scan(&options)?
+        .into_iter()
+        .filter(|ap| ap.ssid == ssid)
+        .max_by_key(|ap| ap.signal_strength);
+    Ok(ClientConfiguration {
+        ssid,
+        bssid,
+        auth_method: security::resolve(auth, ap_info.as_ref().map(|ap| ap.auth_method), &password),
+        password,
+        channel: ap_info.map(|ap| ap.channel),
+    })
+}
 
-pub fn default_ap_config() -> AccessPointConfiguration {
+/// Builds the configuration of the own access point. An empty password opens
+/// the network.
+pub fn ap_config(
+    ssid: heapless::String<32>,
+    password: heapless::String<64>,
+    hidden: bool,
+    channel: u8,
+) -> AccessPointConfiguration {
     AccessPointConfiguration {
-        ssid: heapless::String::from("ESP32"),
-        ssid_hidden: false,
-        channel: 1,
-        secondary_channel: Some(2),
+        ssid,
+        ssid_hidden: hidden,
+        channel,
+        secondary_channel: None,
         protocols: EnumSet::empty(),
-        auth_method: AuthMethod::WPA2Personal,
-        password: heapless::String::from("test1234"),
+        auth_method: if password.is_empty() {
+            AuthMethod::None
+        } else {
+            AuthMethod::WPA2Personal
+        },
+        password,
         max_connections: 10,
     }
 }
 
 pub fn scan_aps() -> Result<Vec<AccessPointInfo>, sys::EspError> {
-    unsafe { sys::esp!(sys::esp_wifi_scan_start(core::ptr::null(), true))? };
-    let mut num = 0;
-    unsafe { sys::esp!(sys::esp_wifi_scan_get_ap_num(&mut num))? };
-    let mut buf = vec![sys::wifi_ap_record_t::default(); num as usize];
-    unsafe {
-        sys::esp!(sys::esp_wifi_scan_get_ap_records(
-            &mut num,
-            buf.as_mut_ptr()
-        ))?
+    scan(&ScanOptions::default())
+}
+
+/// Scans with `options`. A channel subset is scanned one channel after the
+/// other, the driver only takes a single channel or all of them.
+pub fn scan(options: &ScanOptions) -> Result<Vec<AccessPointInfo>, sys::EspError> {
+    let mut ssid = [0u8; 33];
+    if let Some(filter) = &options.ssid {
+        ssid[..filter.len()].copy_from_slice(filter.as_bytes());
+    }
+    let mut bssid = options.bssid.unwrap_or_default();
+    let mut config = sys::wifi_scan_config_t {
+        ssid: if options.ssid.is_some() {
+            ssid.as_mut_ptr()
+        } else {
+            core::ptr::null_mut()
+        },
+        bssid: if options.bssid.is_some() {
+            bssid.as_mut_ptr()
+        } else {
+            core::ptr::null_mut()
+        },
+        channel: 0,
+        show_hidden: options.show_hidden,
+        scan_type: if options.passive {
+            sys::wifi_scan_type_t_WIFI_SCAN_TYPE_PASSIVE
+        } else {
+            sys::wifi_scan_type_t_WIFI_SCAN_TYPE_ACTIVE
+        },
+        ..Default::default()
     };
-    Ok(buf
-        .into_iter()
-        .map(|record| AccessPointInfo::from(Newtype(record)))
-        .collect())
+    let millis = |dwell: Option<Duration>| dwell.map_or(0, |dwell| dwell.as_millis() as u32);
+    config.scan_time.active.min = millis(options.min_dwell);
+    config.scan_time.active.max = millis(options.max_dwell);
+    config.scan_time.passive = millis(options.max_dwell);
+
+    let channels: &[u8] = if options.channels.is_empty() {
+        // All channels
+        &[0]
+    } else {
+        &options.channels
+    };
+    let mut aps = Vec::new();
+    for channel in channels {
+        config.channel = *channel;
+        unsafe { sys::esp!(sys::esp_wifi_scan_start(&config, true))? };
+        let mut num = 0;
+        unsafe { sys::esp!(sys::esp_wifi_scan_get_ap_num(&mut num))? };
+        let mut buf = vec![sys::wifi_ap_record_t::default(); num as usize];
+        unsafe {
+            sys::esp!(sys::esp_wifi_scan_get_ap_records(
+                &mut num,
+                buf.as_mut_ptr()
+            ))?
+        };
+        aps.extend(
+            buf.into_iter()
+                .map(|record| AccessPointInfo::from(Newtype(record))),
+        );
+    }
+    events::publish(Event::ScanDone {
+        networks: aps.len(),
+    });
+    Ok(aps)
+}
+
+/// Number of stations connected to the own access point
+pub fn ap_client_count() -> usize {
+    let mut stations = sys::wifi_sta_list_t::default();
+    // Fails if the access point is not running
+    match sys::esp!(unsafe { sys::esp_wifi_ap_get_sta_list(&mut stations) }) {
+        Ok(()) => stations.num as usize,
+        Err(_) => 0,
+    }
+}
+
+/// Looks for the network of `config` with a short scan, first on its last
+/// known channel. Returns the access point with the strongest signal, or the
+/// pinned one.
+pub fn find_network(
+    config: &ClientConfiguration,
+    hidden: bool,
+) -> Result<Option<AccessPointInfo>, sys::EspError> {
+    let strongest = |aps: Vec<AccessPointInfo>| {
+        aps.into_iter()
+            .filter(|ap| ap.ssid == config.ssid)
+            .max_by_key(|ap| ap.signal_strength)
+    };
+    let targeted = |channel| ScanOptions {
+        bssid: config.bssid,
+        ..ScanOptions::targeted(config.ssid.clone(), channel, hidden)
+    };
+    if config.channel.is_some() {
+        let found = strongest(scan(&targeted(config.channel))?);
+        if found.is_some() {
+            return Ok(found);
+        }
+    }
+    Ok(strongest(scan(&targeted(None))?))
 }
 
 pub fn update_wifi(
@@ -92,8 +219,12 @@ pub fn update_wifi(
         sta_config,
         ap_config,
         ap_mode,
+        country,
+        sta_hidden: _,
+        roaming,
     }: WifiInfo,
 ) -> Result<(), sys::EspError> {
+    set_country(country)?;
     set_ip_info(wifi, ip_info)?;
     match ap_mode {
         ApMode::NoConnOnBoot => {
@@ -101,10 +232,21 @@ pub fn update_wifi(
                 // We should try client only first and setup an ap only if
                 // connection failed
                 wifi.set_configuration(&Configuration::Client(config.clone()))?;
-            } else if let Some(current) = wifi.get_configuration()?.as_ap_conf_ref() {
+            } else if let Some(current) = wifi.get_configuration()?.as_ap_conf_ref().cloned() {
                 let next = ap_config.unwrap_or_else(default_ap_config);
+                // A channel that was chosen automatically before is kept
+                let unchanged = if next.channel == AUTO_CHANNEL {
+                    country.channels().contains(&current.channel)
+                        && AccessPointConfiguration {
+                            channel: current.channel,
+                            ..next.clone()
+                        } == current
+                } else {
+                    next == current
+                };
 
-                if current != &next {
+                if !unchanged {
+                    let next = with_channel(wifi, next, None, country)?;
                     wifi.set_configuration(&Configuration::Mixed(Default::default(), next))?;
                     wifi.start()?;
                 }
@@ -115,15 +257,13 @@ pub fn update_wifi(
         ApMode::Always => {
             // We should _ALWYAS_ setup an AP
             if let Some(config) = sta_config.as_ref() {
-                wifi.set_configuration(&Configuration::Mixed(
-                    config.clone(),
-                    ap_config.clone().unwrap_or_else(default_ap_config),
-                ))?;
+                let ap_config = ap_config.clone().unwrap_or_else(default_ap_config);
+                let ap_config = with_channel(wifi, ap_config, Some(config), country)?;
+                wifi.set_configuration(&Configuration::Mixed(config.clone(), ap_config))?;
             } else {
-                wifi.set_configuration(&Configuration::Mixed(
-                    Default::default(),
-                    ap_config.unwrap_or_else(default_ap_config),
-                ))?;
+                let ap_config = ap_config.unwrap_or_else(default_ap_config);
+                let ap_config = with_channel(wifi, ap_config, None, country)?;
+                wifi.set_configuration(&Configuration::Mixed(Default::default(), ap_config))?;
                 wifi.start()?;
                 return Ok(());
             }
@@ -140,6 +280,7 @@ pub fn update_wifi(
         }
     }
 
+    configure_station(roaming)?;
     wifi.start()?;
     wifi.connect()?;
 
@@ -150,25 +291,194 @@ pub fn update_wifi(
     if !WifiWait::new(sysloop)?.wait_with_timeout(Duration::from_secs(15), || {
         wifi.is_started().unwrap() && wifi.is_connected().unwrap()
     }) {
-        wifi.set_configuration(&Configuration::Mixed(
-            Default::default(),
-            ap_config.unwrap_or_else(default_ap_config),
-        ))?;
+        let ap_config = ap_config.unwrap_or_else(default_ap_config);
+        let ap_config = with_channel(wifi, ap_config, None, country)?;
+        wifi.set_configuration(&Configuration::Mixed(Default::default(), ap_config))?;
         wifi.start()?;
     };
 
     Ok(())
 }
 
+/// Sets the station options `embedded-svc` has no fields for. Access points
+/// may steer the station with 802.11v BSS transition requests and query it
+/// with 802.11k radio measurements while roaming is monitored.
+fn configure_station(roaming: Roaming) -> Result<(), sys::EspError> {
+    let mut config = sys::wifi_config_t::default();
+    sys::esp!(unsafe { sys::esp_wifi_get_config(sys::wifi_interface_t_WIFI_IF_STA, &mut config) })?;
+    let sta = unsafe { &mut config.sta };
+    // A pinned access point is connected to regardless of its signal
+    if !sta.bssid_set && (roaming.strongest || roaming.monitor) {
+        // Otherwise the driver takes the first access point it finds,
+        // starting at the last channel
+        sta.scan_method = sys::wifi_scan_method_t_WIFI_ALL_CHANNEL_SCAN;
+        sta.sort_method = sys::wifi_sort_method_t_WIFI_CONNECT_AP_BY_SIGNAL;
+        sta.channel = 0;
+    }
+    sta.set_rm_enabled(roaming.monitor.into());
+    sta.set_btm_enabled(roaming.monitor.into());
+    sys::esp!(unsafe { sys::esp_wifi_set_config(sys::wifi_interface_t_WIFI_IF_STA, &mut config) })
+}
+
+/// Points the next connect of the station at one access point of its
+/// network, `None` lets it choose one by signal again.
+pub fn steer_station(target: Option<Candidate>) -> Result<(), sys::EspError> {
+    let mut config = sys::wifi_config_t::default();
+    sys::esp!(unsafe { sys::esp_wifi_get_config(sys::wifi_interface_t_WIFI_IF_STA, &mut config) })?;
+    let sta = unsafe { &mut config.sta };
+    match target {
+        Some(candidate) => {
+            sta.bssid_set = true;
+            sta.bssid = candidate.bssid;
+            sta.channel = candidate.channel;
+        }
+        None => {
+            sta.bssid_set = false;
+            sta.channel = 0;
+        }
+    }
+    sys::esp!(unsafe { sys::esp_wifi_set_config(sys::wifi_interface_t_WIFI_IF_STA, &mut config) })
+}
+
+/// The access point the station is connected to
+pub fn sta_ap_info() -> Option<AccessPointInfo> {
+    let mut record = sys::wifi_ap_record_t::default();
+    // Fails if the station is not connected
+    sys::esp!(unsafe { sys::esp_wifi_sta_get_ap_info(&mut record) }).ok()?;
+    Some(AccessPointInfo::from(Newtype(record)))
+}
+
+/// Asks the connected access point to steer the station to a better one,
+/// with an 802.11v BSS transition query. Returns `false` if the network does
+/// not support it.
+pub fn request_transition() -> bool {
+    let sent = unsafe {
+        wnm::esp_wnm_send_bss_transition_mgmt_query(
+            wnm::btm_query_reason_REASON_RSSI,
+            core::ptr::null(),
+            0,
+        )
+    };
+    sent == 0
+}
+
+/// All access points of the network of `config` in range
+pub fn find_candidates(
+    config: &ClientConfiguration,
+    hidden: bool,
+) -> Result<Vec<Candidate>, sys::EspError> {
+    let options = ScanOptions::targeted(config.ssid.clone(), None, hidden);
+    Ok(scan(&options)?
+        .into_iter()
+        .filter(|ap| ap.ssid == config.ssid)
+        .map(|ap| Candidate {
+            bssid: ap.bssid,
+            channel: ap.channel,
+            rssi: ap.signal_strength,
+        })
+        .collect())
+}
+
+/// Resolves [`AUTO_CHANNEL`]. Next to a station the access point has to use
+/// the channel of the station's network anyway, otherwise the least congested
+/// channel is taken.
+fn with_channel(
+    wifi: &mut EspWifi,
+    mut config: AccessPointConfiguration,
+    sta_config: Option<&ClientConfiguration>,
+    country: Country,
+) -> Result<AccessPointConfiguration, sys::EspError> {
+    if config.channel != AUTO_CHANNEL {
+        return Ok(config);
+    }
+    let allowed = country.channels();
+    let sta_channel = sta_config
+        .and_then(|config| config.channel)
+        .filter(|channel| allowed.contains(channel));
+    config.channel = match sta_channel {
+        Some(channel) => channel,
+        None => {
+            // Scans need a running station
+            if !wifi.is_started()? {
+                wifi.set_configuration(&Configuration::Client(Default::default()))?;
+                wifi.start()?;
+            }
+            match scan_aps() {
+                Ok(aps) => {
+                    let neighbors: Vec<_> = aps
+                        .iter()
+                        .map(|ap| Neighbor {
+                            channel: ap.channel,
+                            rssi: ap.signal_strength,
+                        })
+                        .collect();
+                    channel::least_congested(&neighbors, allowed)
+                }
+                Err(err) => {
+                    warn!("scan for the AP channel failed, using the first one: {err}");
+                    *allowed.start()
+                }
+            }
+        }
+    };
+    info!("AP channel {} chosen automatically", config.channel);
+    Ok(config)
+}
+
+fn set_country(country: Country) -> Result<(), sys::EspError> {
+    let [a, b] = country.code();
+    // The access point is limited to `country.channels()` by validation
+    let channels = country.scan_channels();
+    let config = sys::wifi_country_t {
+        cc: [a as _, b as _, 0],
+        schan: *channels.start(),
+        nchan: channels.end() - channels.start() + 1,
+        max_tx_power: 20,
+        // Do not take over the country announced by the connected network,
+        // the channels must match the validated settings
+        policy: sys::wifi_country_policy_t_WIFI_COUNTRY_POLICY_MANUAL,
+    };
+    sys::esp!(unsafe { sys::esp_wifi_set_country(&config) })
+}
+
+/// The regulatory domain the driver currently uses
+pub fn current_country() -> Result<String, sys::EspError> {
+    let mut config = sys::wifi_country_t::default();
+    sys::esp!(unsafe { sys::esp_wifi_get_country(&mut config) })?;
+    Ok(config.cc[..2].iter().map(|c| *c as u8 as char).collect())
+}
+
+/// Uses the static address of `ip_info`, or DHCP if it is unspecified
 fn set_ip_info(wifi: &mut EspWifi, ip_info: ipv4::IpInfo) -> Result<(), sys::EspError> {
     unsafe {
         let handle = wifi.sta_netif_mut().handle();
-        sys::esp!(sys::esp_netif_dhcpc_stop(handle))?;
+        // Fails if a static address stopped the client already
+        let _ = sys::esp_netif_dhcpc_stop(handle);
         sys::esp!(sys::esp_netif_set_ip_info(
             handle,
             &Newtype::from(ip_info).0
         ))?;
-        sys::esp!(sys::esp_netif_dhcpc_start(handle))?;
+        if ip_info.ip == ipv4::Ipv4Addr::UNSPECIFIED {
+            sys::esp!(sys::esp_netif_dhcpc_start(handle))?;
+        } else {
+            // Servers that are left out keep the ones from before
+            let servers = [
+                (sys::esp_netif_dns_type_t_ESP_NETIF_DNS_MAIN, ip_info.dns),
+                (
+                    sys::esp_netif_dns_type_t_ESP_NETIF_DNS_BACKUP,
+                    ip_info.secondary_dns,
+                ),
+            ];
+            for (kind, server) in servers {
+                let Some(server) = server else {
+                    continue;
+                };
+                let mut dns = sys::esp_netif_dns_info_t::default();
+                dns.ip.type_ = sys::ESP_IPADDR_TYPE_V4 as _;
+                dns.ip.u_addr.ip4 = Newtype::from(server).0;
+                sys::esp!(sys::esp_netif_set_dns_info(handle, kind, &mut dns))?;
+            }
+        }
     };
 
     Ok(())
@@ -0,0 +1,19 @@
+//! When the device runs its own access point
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Default,
+    serde_repr::Serialize_repr,
+    serde_repr::Deserialize_repr,
+)]
+#[repr(u8)]
+pub enum ApMode {
+    #[default]
+    NoConnOnBoot = 0,
+    Always = 2,
+    Never = 3,
+}
@@ -0,0 +1,118 @@
+//! Choice of the access point channel from scan results
+
+use core::ops::RangeInclusive;
+
+/// 2.4 GHz channels are 5 MHz apart but 20 MHz wide, a network disturbs the
+/// channels up to this distance
+const OVERLAP: u8 = 4;
+
+/// Channels that do not overlap each other, preferred if several channels are
+/// equally good
+const NON_OVERLAPPING: [u8; 3] = [1, 6, 11];
+
+/// A network found by a scan
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Neighbor {
+    pub channel: u8,
+    pub rssi: i8,
+}
+
+/// Picks the channel in `allowed` that is disturbed least by `neighbors`.
+///
+/// Every network adds its received power to its own channel and, weighted by
+/// the overlap, to the channels next to it. Ties go to 1, 6 and 11 first, then
+/// to the lowest channel.
+pub fn least_congested(neighbors: &[Neighbor], allowed: RangeInclusive<u8>) -> u8 {
+    let start = *allowed.start();
+    allowed
+        .map(|channel| (channel, interference(neighbors, channel)))
+        .min_by(|(a, a_score), (b, b_score)| {
+            a_score
+                .total_cmp(b_score)
+                .then_with(|| preference(*a).cmp(&preference(*b)))
+        })
+        .map_or(start, |(channel, _)| channel)
+}
+
+/// Sum of the power of all networks reaching `channel`, in milliwatts
+pub fn interference(neighbors: &[Neighbor], channel: u8) -> f32 {
+    neighbors
+        .iter()
+        .map(|neighbor| {
+            let distance = neighbor.channel.abs_diff(channel);
+            if distance > OVERLAP {
+                return 0.0;
+            }
+            let overlap = f32::from(OVERLAP + 1 - distance) / f32::from(OVERLAP + 1);
+            overlap * milliwatts(neighbor.rssi)
+        })
+        .sum()
+}
+
+fn milliwatts(rssi: i8) -> f32 {
+    10f32.powf(f32::from(rssi) / 10.0)
+}
+
+fn preference(channel: u8) -> (bool, u8) {
+    (!NON_OVERLAPPING.contains(&channel), channel)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wifi::country::Country;
+
+    fn neighbor(channel: u8, rssi: i8) -> Neighbor {
+        Neighbor { channel, rssi }
+    }
+
+    #[test]
+    fn empty_scan() {
+        assert_eq!(least_congested(&[], 1..=11), 1);
+        assert_eq!(least_congested(&[], 5..=11), 6);
+    }
+
+    #[test]
+    fn crowded_non_overlapping() {
+        let neighbors = [neighbor(1, -40), neighbor(1, -60), neighbor(6, -50)];
+        assert_eq!(least_congested(&neighbors, 1..=11), 11);
+    }
+
+    #[test]
+    fn overlap_weighting() {
+        let neighbors = [neighbor(3, -30)];
+        let scores: Vec<_> = (1..=11)
+            .map(|channel| interference(&neighbors, channel))
+            .collect();
+        // Channels 1 to 7 are disturbed, the further from 3 the less
+        assert!(scores[..7].iter().all(|score| *score > 0.0));
+        assert!(scores[7..].iter().all(|score| *score == 0.0));
+        assert!(scores[2] > scores[1] && scores[1] > scores[0]);
+        assert!(scores[2] > scores[3] && scores[3] > scores[4]);
+        assert!(scores[4] > scores[5] && scores[5] > scores[6]);
+        assert_eq!(scores[1], scores[3]);
+        // A weaker network further away disturbs less than a strong one
+        assert!(interference(&[neighbor(3, -80)], 3) < scores[6]);
+        assert_eq!(least_congested(&neighbors, 1..=7), 7);
+    }
+
+    #[test]
+    fn tie_break() {
+        // 7 to 11 are free, 11 does not overlap with 1 and 6
+        assert_eq!(least_congested(&[neighbor(2, -50)], 1..=11), 11);
+        // 1 and 11 are free
+        assert_eq!(least_congested(&[neighbor(6, -50)], 1..=11), 1);
+        // Without a non-overlapping one the lowest
+        assert_eq!(least_congested(&[neighbor(2, -50)], 7..=10), 7);
+    }
+
+    #[test]
+    fn allowed_range() {
+        let neighbors = [neighbor(1, -40), neighbor(6, -40), neighbor(11, -40)];
+        let japan = Country::from_code("JP").unwrap();
+        assert_eq!(least_congested(&neighbors, japan.channels()), 14);
+        let channel = least_congested(&neighbors, Country::WORLD.channels());
+        assert!((1..=11).contains(&channel));
+        assert_eq!(least_congested(&neighbors, 1..=1), 1);
+    }
+}
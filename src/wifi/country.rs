@@ -0,0 +1,111 @@
+use core::{fmt, ops::RangeInclusive};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Regulatory domain the radio is operated in, as ISO 3166-1 alpha-2 code
+///
+/// Only the countries listed in [`Country::ALL`] can be chosen, so the
+/// allowed channels are always known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Country([u8; 2]);
+
+impl Country {
+    /// Channels allowed everywhere, used until a country is chosen
+    pub const WORLD: Self = Self(*b"01");
+
+    pub const ALL: &[Self] = &[
+        Self::WORLD,
+        Self(*b"AT"),
+        Self(*b"AU"),
+        Self(*b"BE"),
+        Self(*b"BR"),
+        Self(*b"CA"),
+        Self(*b"CH"),
+        Self(*b"CN"),
+        Self(*b"CZ"),
+        Self(*b"DE"),
+        Self(*b"DK"),
+        Self(*b"ES"),
+        Self(*b"FI"),
+        Self(*b"FR"),
+        Self(*b"GB"),
+        Self(*b"IE"),
+        Self(*b"IN"),
+        Self(*b"IT"),
+        Self(*b"JP"),
+        Self(*b"KR"),
+        Self(*b"MX"),
+        Self(*b"NL"),
+        Self(*b"NO"),
+        Self(*b"NZ"),
+        Self(*b"PL"),
+        Self(*b"PT"),
+        Self(*b"SE"),
+        Self(*b"SG"),
+        Self(*b"TW"),
+        Self(*b"US"),
+    ];
+
+    pub fn from_code(code: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|country| country.as_str().eq_ignore_ascii_case(code))
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only built from the ASCII codes above
+        core::str::from_utf8(&self.0).unwrap()
+    }
+
+    pub fn code(&self) -> [u8; 2] {
+        self.0
+    }
+
+    /// 2.4 GHz channels the access point may use
+    pub fn channels(&self) -> RangeInclusive<u8> {
+        match &self.0 {
+            // Channel 14 is 802.11b only, the driver takes care of that
+            b"JP" => 1..=14,
+            // FCC rules, and the world domain where 12 and 13 may only be
+            // scanned passively
+            b"01" | b"CA" | b"MX" | b"TW" | b"US" => 1..=11,
+            _ => 1..=13,
+        }
+    }
+
+    /// 2.4 GHz channels the station scans and connects on. The world domain
+    /// scans 12 and 13 passively, so networks in countries that allow them
+    /// are still found.
+    pub fn scan_channels(&self) -> RangeInclusive<u8> {
+        match &self.0 {
+            b"01" => 1..=13,
+            _ => self.channels(),
+        }
+    }
+}
+
+impl Default for Country {
+    fn default() -> Self {
+        Self::WORLD
+    }
+}
+
+impl fmt::Display for Country {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for Country {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Country {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let code = String::deserialize(deserializer)?;
+        Self::from_code(&code)
+            .ok_or_else(|| serde::de::Error::custom(format!("unsupported country {code}")))
+    }
+}
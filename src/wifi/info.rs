@@ -0,0 +1,116 @@
+//! The WiFi settings as they are stored and applied
+
+use super::{ap_mode::ApMode, country::Country, roaming::Roaming, security};
+use core::fmt;
+use embedded_svc::{
+    ipv4,
+    wifi::{AccessPointConfiguration, AuthMethod, ClientConfiguration},
+};
+use enumset::EnumSet;
+
+/// This holds a WiFi configuration
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct WifiInfo {
+    pub ip_info: ipv4::IpInfo,
+    pub sta_config: Option<ClientConfiguration>,
+    pub ap_config: Option<AccessPointConfiguration>,
+    pub ap_mode: ApMode,
+    /// Regulatory domain, it limits the channels of the access point and the
+    /// ones scanned by the station
+    pub country: Country,
+    /// The client network does not broadcast its name, scans for it have to
+    /// include hidden networks
+    pub sta_hidden: bool,
+    /// Choice of the access point if the client network has several
+    pub roaming: Roaming,
+}
+
+const DEDAULT_IP_INFO: ipv4::IpInfo = ipv4::IpInfo {
+    ip: ipv4::Ipv4Addr::UNSPECIFIED,
+    subnet: ipv4::Subnet {
+        gateway: ipv4::Ipv4Addr::UNSPECIFIED,
+        mask: ipv4::Mask(24),
+    },
+    dns: None,
+    secondary_dns: None,
+};
+
+/// A setting that was rejected by [`WifiInfo::validate`]
+#[derive(Debug, Clone, Copy)]
+pub struct InvalidSettings {
+    /// Path of the offending field, e.g. `ap.channel`
+    pub field: &'static str,
+    pub message: &'static str,
+}
+
+impl fmt::Display for InvalidSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+impl WifiInfo {
+    /// Checks settings submitted by a user before they are applied.
+    pub fn validate(&self) -> Result<(), InvalidSettings> {
+        let invalid = |field, message| Err(InvalidSettings { field, message });
+
+        if let Some(config) = &self.sta_config {
+            if let Err(message) = security::check_password(config.auth_method, &config.password) {
+                return invalid("client.password", message);
+            }
+        }
+        if let Err(message) = self.roaming.validate() {
+            return invalid("client.roaming.threshold", message);
+        }
+        if let Some(config) = &self.ap_config {
+            if config.channel != AUTO_CHANNEL && !self.country.channels().contains(&config.channel)
+            {
+                return invalid("ap.channel", "is not allowed in the selected country");
+            }
+            if let Err(message) = security::check_password(config.auth_method, &config.password) {
+                return invalid("ap.password", message);
+            }
+        }
+        if self.ip_info.subnet.mask.0 > 32 {
+            return invalid("ip.prefix_len", "must be between 0 and 32");
+        }
+        if self.ap_mode == ApMode::Never && self.sta_config.is_none() {
+            return invalid(
+                "ap.mode",
+                "the AP can only be disabled when a client network is configured",
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Default for WifiInfo {
+    fn default() -> Self {
+        Self {
+            ip_info: DEDAULT_IP_INFO,
+            sta_config: None,
+            ap_config: None,
+            ap_mode: ApMode::NoConnOnBoot,
+            country: Country::WORLD,
+            sta_hidden: false,
+            roaming: Roaming::default(),
+        }
+    }
+}
+
+/// Access point channel that is chosen when the access point starts, see
+/// [`super::channel::least_congested`]
+pub const AUTO_CHANNEL: u8 = 0;
+
+pub fn default_ap_config() -> AccessPointConfiguration {
+    AccessPointConfiguration {
+        ssid: heapless::String::from("ESP32"),
+        ssid_hidden: false,
+        channel: AUTO_CHANNEL,
+        secondary_channel: Some(2),
+        protocols: EnumSet::empty(),
+        auth_method: AuthMethod::WPA2Personal,
+        password: heapless::String::from("test1234"),
+        max_connections: 10,
+    }
+}
@@ -0,0 +1,117 @@
+//! Choice of the access point within a network of several ones, e.g. the
+//! nodes of a mesh system
+
+/// Weakest allowed roaming threshold, below it connections hardly work
+pub const MIN_THRESHOLD: i8 = -90;
+/// Strongest allowed roaming threshold, above it the station would roam
+/// while standing next to an access point
+pub const MAX_THRESHOLD: i8 = -50;
+
+/// Signal an access point needs above the current one to be roamed to, so
+/// the station does not hop between two about equally good ones
+pub const HYSTERESIS: i8 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Roaming {
+    /// Connect to the access point with the strongest signal instead of the
+    /// first one found
+    pub strongest: bool,
+    /// Look for a better access point while the signal is weaker than
+    /// `threshold`
+    pub monitor: bool,
+    /// Signal strength in dBm
+    pub threshold: i8,
+}
+
+impl Default for Roaming {
+    fn default() -> Self {
+        Self {
+            strongest: false,
+            monitor: false,
+            threshold: -75,
+        }
+    }
+}
+
+impl Roaming {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if (MIN_THRESHOLD..=MAX_THRESHOLD).contains(&self.threshold) {
+            Ok(())
+        } else {
+            Err("must be between -90 and -50 dBm")
+        }
+    }
+
+    /// The current connection is weak enough to look for another access
+    /// point
+    pub fn is_weak(&self, rssi: i8) -> bool {
+        self.monitor && rssi < self.threshold
+    }
+}
+
+/// An access point of the network found by a scan
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Candidate {
+    pub bssid: [u8; 6],
+    pub channel: u8,
+    pub rssi: i8,
+}
+
+/// The strongest of `candidates` if it is at least [`HYSTERESIS`] better
+/// than the access point `current` is connected to with `rssi`
+pub fn better_candidate(current: [u8; 6], rssi: i8, candidates: &[Candidate]) -> Option<Candidate> {
+    candidates
+        .iter()
+        .filter(|candidate| candidate.bssid != current)
+        .max_by_key(|candidate| candidate.rssi)
+        .filter(|candidate| i16::from(candidate.rssi) >= i16::from(rssi) + i16::from(HYSTERESIS))
+        .copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CURRENT: [u8; 6] = [0x02, 0, 0, 0, 0, 1];
+
+    fn candidate(last: u8, rssi: i8) -> Candidate {
+        Candidate {
+            bssid: [0x02, 0, 0, 0, 0, last],
+            channel: 6,
+            rssi,
+        }
+    }
+
+    #[test]
+    fn current_is_strongest() {
+        let candidates = [candidate(1, -60), candidate(2, -70), candidate(3, -75)];
+        assert_eq!(better_candidate(CURRENT, -60, &candidates), None);
+    }
+
+    #[test]
+    fn hysteresis() {
+        let inside = [candidate(1, -80), candidate(2, -73), candidate(3, -90)];
+        assert_eq!(better_candidate(CURRENT, -80, &inside), None);
+
+        let outside = [candidate(1, -80), candidate(2, -72), candidate(3, -90)];
+        assert_eq!(
+            better_candidate(CURRENT, -80, &outside),
+            Some(candidate(2, -72))
+        );
+    }
+
+    #[test]
+    fn strongest_of_several() {
+        let candidates = [candidate(2, -65), candidate(3, -55), candidate(4, -70)];
+        assert_eq!(
+            better_candidate(CURRENT, -80, &candidates),
+            Some(candidate(3, -55))
+        );
+    }
+
+    #[test]
+    fn no_candidates() {
+        assert_eq!(better_candidate(CURRENT, -85, &[]), None);
+    }
+}
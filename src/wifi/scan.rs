@@ -0,0 +1,176 @@
+//! Parameters of a network scan, e.g. from the query of `/json/net`
+
+use crate::convert::parse_mac;
+use core::time::Duration;
+
+/// Longest time spent on one channel. Longer dwell times make the station
+/// miss beacons of its own network.
+pub const MAX_DWELL: Duration = Duration::from_millis(1500);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScanOptions {
+    /// Only report networks with this name
+    pub ssid: Option<heapless::String<32>>,
+    /// Only report this access point
+    pub bssid: Option<[u8; 6]>,
+    /// Channels to scan, all allowed channels if empty
+    pub channels: heapless::Vec<u8, 14>,
+    /// Listen for beacons instead of sending probe requests
+    pub passive: bool,
+    /// Time per channel, the driver defaults if `None`. Passive scans only use
+    /// the maximum.
+    pub min_dwell: Option<Duration>,
+    pub max_dwell: Option<Duration>,
+    /// Include networks that do not broadcast their name
+    pub show_hidden: bool,
+}
+
+impl ScanOptions {
+    /// Options for finding a known network quickly, on its last channel if
+    /// that is known. The probe requests carry the name, so hidden networks
+    /// answer as well.
+    pub fn targeted(ssid: heapless::String<32>, channel: Option<u8>, hidden: bool) -> Self {
+        Self {
+            ssid: Some(ssid),
+            channels: channel.into_iter().collect(),
+            min_dwell: Some(Duration::from_millis(30)),
+            max_dwell: Some(Duration::from_millis(100)),
+            show_hidden: hidden,
+            ..Default::default()
+        }
+    }
+
+    /// Parses the query string of a request (without the `?`). Unknown
+    /// parameters are ignored.
+    ///
+    /// `ssid`, `bssid` (`AA:BB:CC:DD:EE:FF`), `channels` (`1,6,11`),
+    /// `type` (`active` or `passive`), `min_dwell` and `max_dwell` in
+    /// milliseconds and `hidden` (`true` or `false`)
+    pub fn from_query(query: &str) -> Result<Self, &'static str> {
+        let mut options = Self::default();
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = percent_decode(value).ok_or("invalid percent encoding")?;
+            match key {
+                "ssid" => {
+                    let mut ssid = heapless::String::new();
+                    ssid.push_str(&value).map_err(|_| "ssid is too long")?;
+                    options.ssid = Some(ssid);
+                }
+                "bssid" => options.bssid = Some(parse_mac(&value).ok_or("invalid bssid")?),
+                "channels" => {
+                    options.channels.clear();
+                    for channel in value.split(',') {
+                        let channel = channel
+                            .trim()
+                            .parse()
+                            .ok()
+                            .filter(|channel| (1..=14).contains(channel))
+                            .ok_or("channels must be between 1 and 14")?;
+                        if !options.channels.contains(&channel) {
+                            // At most 14 different channels
+                            options.channels.push(channel).unwrap();
+                        }
+                    }
+                }
+                "type" => {
+                    options.passive = match value.as_str() {
+                        "active" => false,
+                        "passive" => true,
+                        _ => return Err("type must be active or passive"),
+                    }
+                }
+                "min_dwell" => options.min_dwell = Some(parse_dwell(&value)?),
+                "max_dwell" => options.max_dwell = Some(parse_dwell(&value)?),
+                "hidden" => options.show_hidden = parse_bool(&value)?,
+                _ => {}
+            }
+        }
+        if let (Some(min), Some(max)) = (options.min_dwell, options.max_dwell) {
+            if min > max {
+                return Err("min_dwell must not exceed max_dwell");
+            }
+        }
+        Ok(options)
+    }
+}
+
+fn parse_dwell(value: &str) -> Result<Duration, &'static str> {
+    value
+        .parse()
+        .ok()
+        .map(Duration::from_millis)
+        .filter(|dwell| *dwell <= MAX_DWELL)
+        .ok_or("dwell times must be between 0 and 1500 ms")
+}
+
+fn parse_bool(value: &str) -> Result<bool, &'static str> {
+    match value {
+        "true" | "1" => Ok(true),
+        "false" | "0" => Ok(false),
+        _ => Err("hidden must be true or false"),
+    }
+}
+
+/// Decodes `%XX` escapes and `+` of a query value.
+fn percent_decode(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = rest.get(..2)?;
+                bytes.push(u8::from_str_radix(core::str::from_utf8(hex).ok()?, 16).ok()?);
+                rest = &rest[2..];
+            }
+            byte => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query() {
+        assert_eq!(ScanOptions::from_query(""), Ok(ScanOptions::default()));
+        let options = ScanOptions::from_query(
+            "ssid=Home+network%21&bssid=00:1a:2b:c3:d4:ff&channels=1,%206,1&type=passive\
+             &min_dwell=50&max_dwell=1500&hidden=1&unknown",
+        )
+        .unwrap();
+        assert_eq!(options.ssid.as_deref(), Some("Home network!"));
+        assert_eq!(options.bssid, Some([0x00, 0x1a, 0x2b, 0xc3, 0xd4, 0xff]));
+        assert_eq!(options.channels, [1, 6]);
+        assert!(options.passive);
+        assert_eq!(options.min_dwell, Some(Duration::from_millis(50)));
+        assert_eq!(options.max_dwell, Some(MAX_DWELL));
+        assert!(options.show_hidden);
+    }
+
+    #[test]
+    fn invalid_query() {
+        for (query, error) in [
+            ("ssid=%4", "invalid percent encoding"),
+            ("bssid=00:1a", "invalid bssid"),
+            ("channels=0", "channels must be between 1 and 14"),
+            ("channels=15", "channels must be between 1 and 14"),
+            ("type=both", "type must be active or passive"),
+            (
+                "max_dwell=1501",
+                "dwell times must be between 0 and 1500 ms",
+            ),
+            (
+                "min_dwell=200&max_dwell=100",
+                "min_dwell must not exceed max_dwell",
+            ),
+            ("hidden=yes", "hidden must be true or false"),
+        ] {
+            assert_eq!(ScanOptions::from_query(query), Err(error), "{query}");
+        }
+    }
+}
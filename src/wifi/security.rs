@@ -0,0 +1,210 @@
+//! Authentication method of the client connection
+
+use embedded_svc::wifi::AuthMethod;
+
+/// Authentication method chosen for the client network. The driver takes it
+/// as the weakest method it accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientAuth {
+    /// Taken from a scan, see [`resolve`]
+    #[default]
+    Auto,
+    Open,
+    Wep,
+    Wpa,
+    Wpa2,
+    WpaWpa2,
+    Wpa3,
+    Wpa2Wpa3,
+}
+
+impl ClientAuth {
+    /// The method to configure, `None` for [`ClientAuth::Auto`]
+    pub fn method(self) -> Option<AuthMethod> {
+        Some(match self {
+            ClientAuth::Auto => return None,
+            ClientAuth::Open => AuthMethod::None,
+            ClientAuth::Wep => AuthMethod::WEP,
+            ClientAuth::Wpa => AuthMethod::WPA,
+            ClientAuth::Wpa2 => AuthMethod::WPA2Personal,
+            ClientAuth::WpaWpa2 => AuthMethod::WPAWPA2Personal,
+            ClientAuth::Wpa3 => AuthMethod::WPA3Personal,
+            ClientAuth::Wpa2Wpa3 => AuthMethod::WPA2WPA3Personal,
+        })
+    }
+
+    /// The selection that configures `method`, [`ClientAuth::Auto`] for the
+    /// unsupported enterprise and WAPI methods
+    pub fn from_method(method: AuthMethod) -> Self {
+        match method {
+            AuthMethod::None => ClientAuth::Open,
+            AuthMethod::WEP => ClientAuth::Wep,
+            AuthMethod::WPA => ClientAuth::Wpa,
+            AuthMethod::WPA2Personal => ClientAuth::Wpa2,
+            AuthMethod::WPAWPA2Personal => ClientAuth::WpaWpa2,
+            AuthMethod::WPA3Personal => ClientAuth::Wpa3,
+            AuthMethod::WPA2WPA3Personal => ClientAuth::Wpa2Wpa3,
+            AuthMethod::WPA2Enterprise | AuthMethod::WAPIPersonal => ClientAuth::Auto,
+        }
+    }
+}
+
+/// The method to configure for the client network:
+///
+/// 1. an explicit selection is used as is,
+/// 2. otherwise the method the network announced in a scan,
+/// 3. otherwise, e.g. for a hidden network that did not answer, an open
+///    network without password and WPA2 with one. WPA and WEP networks have to
+///    be selected explicitly then.
+pub fn resolve(selected: ClientAuth, scanned: Option<AuthMethod>, password: &str) -> AuthMethod {
+    if let Some(method) = selected.method() {
+        return method;
+    }
+    match scanned {
+        Some(method) => method,
+        None if password.is_empty() => AuthMethod::None,
+        None => AuthMethod::WPA2Personal,
+    }
+}
+
+/// Checks that `password` can be used with `method`.
+pub fn check_password(method: AuthMethod, password: &str) -> Result<(), &'static str> {
+    let printable = || {
+        password
+            .chars()
+            .all(|c| c.is_ascii() && !c.is_ascii_control())
+    };
+    let hex = || password.chars().all(|c| c.is_ascii_hexdigit());
+    let valid = match method {
+        AuthMethod::None => password.is_empty(),
+        // 40 or 104 bit keys, as text or hex digits
+        AuthMethod::WEP => match password.len() {
+            5 | 13 => printable(),
+            10 | 26 => hex(),
+            _ => false,
+        },
+        _ => match password.len() {
+            8..=63 => printable(),
+            64 => hex(),
+            _ => false,
+        },
+    };
+    if valid {
+        return Ok(());
+    }
+    Err(match method {
+        AuthMethod::None => "must be empty for an open network",
+        AuthMethod::WEP => "must be 5 or 13 characters or 10 or 26 hex digits for WEP",
+        _ => "must be 8 to 63 printable ASCII characters or 64 hex digits",
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SELECTIONS: [ClientAuth; 7] = [
+        ClientAuth::Open,
+        ClientAuth::Wep,
+        ClientAuth::Wpa,
+        ClientAuth::Wpa2,
+        ClientAuth::WpaWpa2,
+        ClientAuth::Wpa3,
+        ClientAuth::Wpa2Wpa3,
+    ];
+
+    #[test]
+    fn explicit_selection() {
+        for selected in SELECTIONS {
+            let method = selected.method().unwrap();
+            for scanned in [None, Some(AuthMethod::WPA3Personal)] {
+                assert_eq!(resolve(selected, scanned, ""), method);
+                assert_eq!(resolve(selected, scanned, "password"), method);
+            }
+        }
+    }
+
+    #[test]
+    fn auto() {
+        let auto = ClientAuth::Auto;
+        assert_eq!(auto.method(), None);
+        for password in ["", "password"] {
+            assert_eq!(
+                resolve(auto, Some(AuthMethod::WPA), password),
+                AuthMethod::WPA
+            );
+            assert_eq!(
+                resolve(auto, Some(AuthMethod::None), password),
+                AuthMethod::None
+            );
+        }
+        assert_eq!(resolve(auto, None, ""), AuthMethod::None);
+        assert_eq!(resolve(auto, None, "password"), AuthMethod::WPA2Personal);
+    }
+
+    #[test]
+    fn method_round_trip() {
+        for selected in SELECTIONS {
+            assert_eq!(
+                ClientAuth::from_method(selected.method().unwrap()),
+                selected
+            );
+        }
+        for unsupported in [AuthMethod::WPA2Enterprise, AuthMethod::WAPIPersonal] {
+            assert_eq!(ClientAuth::from_method(unsupported), ClientAuth::Auto);
+        }
+    }
+
+    #[test]
+    fn wep_lengths() {
+        for password in ["abcde", "abcdefghijklm", "0123456789", &"aF".repeat(13)] {
+            assert_eq!(check_password(AuthMethod::WEP, password), Ok(()));
+        }
+        for password in [
+            "",
+            "abcd",
+            "abcdef",
+            "abcdefghijkl",
+            "012345678g",
+            &"g".repeat(26),
+        ] {
+            assert!(check_password(AuthMethod::WEP, password).is_err());
+        }
+        assert!(check_password(AuthMethod::WEP, "abc\tde").is_err());
+    }
+
+    #[test]
+    fn passphrase_lengths() {
+        for method in [
+            AuthMethod::WPA,
+            AuthMethod::WPA2Personal,
+            AuthMethod::WPA3Personal,
+        ] {
+            for password in [
+                "12345678",
+                "correct horse ~!",
+                &"a".repeat(63),
+                &"0aF".repeat(21),
+            ] {
+                assert_eq!(check_password(method, password), Ok(()));
+            }
+            assert_eq!(check_password(method, &"0aF9".repeat(16)), Ok(()));
+            for password in ["", "1234567", &"a".repeat(65), &"0g".repeat(32)] {
+                assert!(check_password(method, password).is_err());
+            }
+            assert!(check_password(method, "password\n").is_err());
+            assert!(check_password(method, "pässword").is_err());
+        }
+    }
+
+    #[test]
+    fn open_with_password() {
+        assert_eq!(check_password(AuthMethod::None, ""), Ok(()));
+        let method = resolve(ClientAuth::Open, None, "password");
+        assert_eq!(
+            check_password(method, "password"),
+            Err("must be empty for an open network")
+        );
+    }
+}
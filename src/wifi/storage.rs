@@ -1,14 +1,66 @@
-use embedded_svc::{storage::RawStorage};
+use embedded_svc::{
+    ipv4,
+    storage::RawStorage,
+    wifi::{AccessPointConfiguration, ClientConfiguration},
+};
 use esp_idf_svc::nvs;
 use esp_idf_sys as sys;
-use super::{WifiInfo, DEDAULT_IP_INFO, ApMode};
+use super::{ApMode, WifiInfo};
+use crate::{fleet::frame::FleetKey, syslog::SyslogConfig};
+
+/// Room for the serialized [`WifiInfo`] and its version, they take up to 243
+/// bytes with every field at its maximum length
+const SETTINGS_LEN: usize = 320;
+
+/// Layout of the stored [`WifiInfo`], raised whenever a field is appended to
+/// it. Version 0 are the settings stored before they got a version.
+const VERSION: u8 = 3;
 
 pub struct WifiStorage<T: nvs::NvsPartitionId> {
     nvs: nvs::EspNvs<T>,
 }
 
+/// The settings stored from the start
+#[derive(serde::Deserialize)]
+struct BaseWifiInfo {
+    ip_info: ipv4::IpInfo,
+    sta_config: Option<ClientConfiguration>,
+    ap_config: Option<AccessPointConfiguration>,
+    ap_mode: ApMode,
+}
+
+/// Decodes settings stored with layout `version`. Fields are only ever
+/// appended, so the ones added after `version` keep their defaults.
+fn decode(version: u8, buf: &[u8]) -> Result<WifiInfo, postcard::Error> {
+    match version {
+        // Fields appended by a later firmware are left out
+        VERSION.. => Ok(postcard::take_from_bytes(buf)?.0),
+        older => {
+            let (base, mut rest) = postcard::take_from_bytes::<BaseWifiInfo>(buf)?;
+            let mut info = WifiInfo {
+                ip_info: base.ip_info,
+                sta_config: base.sta_config,
+                ap_config: base.ap_config,
+                ap_mode: base.ap_mode,
+                ..Default::default()
+            };
+            if older >= 1 {
+                (info.country, rest) = postcard::take_from_bytes(rest)?;
+            }
+            if older >= 2 {
+                (info.sta_hidden, _) = postcard::take_from_bytes(rest)?;
+            }
+            Ok(info)
+        }
+    }
+}
+
 impl<P: nvs::NvsPartitionId> WifiStorage<P> {
-    const SETTINGS_KEY: &str = "settings";
+    const SETTINGS_KEY: &str = "wifi_info";
+    /// Settings stored before they got a version
+    const LEGACY_KEY: &str = "settings";
+    const SYSLOG_KEY: &str = "syslog";
+    const FLEET_KEY: &str = "fleet_key";
 
     pub fn new(nvs_partition: nvs::EspNvsPartition<P>) -> Result<Self, sys::EspError> {
         let nvs = nvs::EspNvs::new(nvs_partition, "wifi", true)?;
@@ -16,30 +68,75 @@ impl<P: nvs::NvsPartitionId> WifiStorage<P> {
     }
 
     pub fn get_info(&self) -> Result<WifiInfo, anyhow::Error> {
-        let mut buf = heapless::Vec::<_, 240>::new();
-        let Some(len) = self.nvs.len(Self::SETTINGS_KEY)? else {
-            return Ok(
-                WifiInfo { 
-                    ip_info: DEDAULT_IP_INFO, 
-                    sta_config: None, 
-                    ap_config: None, 
-                    ap_mode: ApMode::NoConnOnBoot
-                }
-            )
+        let mut buf = [0; SETTINGS_LEN];
+        let info = if let Some(stored) = self.nvs.get_raw(Self::SETTINGS_KEY, &mut buf)? {
+            let (&version, info) = stored
+                .split_first()
+                .ok_or(postcard::Error::DeserializeUnexpectedEnd)?;
+            decode(version, info)?
+        } else if let Some(stored) = self.nvs.get_raw(Self::LEGACY_KEY, &mut buf)? {
+            decode(0, stored)?
+        } else {
+            WifiInfo::default()
         };
-        buf.resize_default(len)
-            .expect("value is less than 120 bytes");
-        self.nvs.get_raw(Self::SETTINGS_KEY, &mut buf[..len])?;
-        Ok(postcard::from_bytes(&buf)?)
+        Ok(info)
     }
 
     pub fn set_info(&mut self, config: Option<&WifiInfo>) -> Result<(), anyhow::Error> {
         if let Some(config) = config {
-            let buf = postcard::to_vec::<_, 240>(&config)?;
+            let buf = postcard::to_vec::<_, SETTINGS_LEN>(&(VERSION, config))?;
             self.nvs.set_raw(Self::SETTINGS_KEY, &buf)?;
         } else {
             self.nvs.remove(Self::SETTINGS_KEY)?;
         }
+        // Replaced by the versioned settings
+        self.nvs.remove(Self::LEGACY_KEY)?;
+        Ok(())
+    }
+
+    pub fn get_syslog(&self) -> Result<SyslogConfig, anyhow::Error> {
+        let mut buf = [0; 80];
+        let Some(len) = self.nvs.len(Self::SYSLOG_KEY)? else {
+            return Ok(SyslogConfig::default())
+        };
+        self.nvs.get_raw(Self::SYSLOG_KEY, &mut buf[..len])?;
+        Ok(postcard::from_bytes(&buf[..len])?)
+    }
+
+    pub fn set_syslog(&mut self, config: &SyslogConfig) -> Result<(), anyhow::Error> {
+        let buf = postcard::to_vec::<_, 80>(config)?;
+        self.nvs.set_raw(Self::SYSLOG_KEY, &buf)?;
+        Ok(())
+    }
+
+    /// The stored fleet key, or the one set at build time with `FLEET_KEY`
+    /// as long as none was ever stored or removed
+    pub fn get_fleet_key(&self) -> Result<Option<FleetKey>, anyhow::Error> {
+        let mut buf = [0; 32];
+        let len = self.nvs.get_raw(Self::FLEET_KEY, &mut buf)?.map(|key| key.len());
+        Ok(match len {
+            Some(len) if len == buf.len() => Some(FleetKey::from_bytes(buf)),
+            // The marker of a removed key
+            Some(_) => None,
+            None => option_env!("FLEET_KEY").map(FleetKey::derive),
+        })
+    }
+
+    pub fn set_fleet_key(&mut self, key: Option<&FleetKey>) -> Result<(), anyhow::Error> {
+        if let Some(key) = key {
+            self.nvs.set_raw(Self::FLEET_KEY, key.as_bytes())?;
+        } else {
+            // Marks the key as removed, so the one set at build time does not
+            // come back
+            self.nvs.set_raw(Self::FLEET_KEY, &[0])?;
+        }
+        Ok(())
+    }
+
+    /// Forgets the stored key and its removal, the one set at build time
+    /// applies again
+    pub fn clear_fleet_key(&mut self) -> Result<(), anyhow::Error> {
+        self.nvs.remove(Self::FLEET_KEY)?;
         Ok(())
     }
 }
\ No newline at end of file
@@ -0,0 +1,142 @@
+//! WPS push button (PBC) connection, for networks whose password nobody can
+//! tell but whose router has a WPS button
+//!
+//! The driver reports the outcome through WiFi events, which are kept until
+//! the main loop picks them up with [`take_outcome`].
+
+use crate::{api::ClientSettings, wifi::ClientAuth};
+use core::{ffi::c_void, time::Duration};
+use esp_idf_sys::{self as sys, EspError};
+use log::{info, warn};
+use std::sync::Mutex;
+
+/// Walk time of the push button method, the router stops offering WPS after
+/// it as well
+pub const TIMEOUT: Duration = Duration::from_secs(120);
+
+static OUTCOME: Mutex<Option<Result<ClientSettings, &'static str>>> = Mutex::new(None);
+
+/// Starts looking for a router in push button mode. The station is
+/// disconnected while WPS runs.
+pub fn start() -> Result<(), EspError> {
+    OUTCOME.lock().unwrap().take();
+    sys::esp!(unsafe {
+        sys::esp_event_handler_register(
+            sys::WIFI_EVENT,
+            sys::ESP_EVENT_ANY_ID,
+            Some(on_event),
+            core::ptr::null_mut(),
+        )
+    })?;
+
+    // Same as `WPS_CONFIG_INIT_DEFAULT(WPS_TYPE_PBC)`
+    let mut config = sys::esp_wps_config_t {
+        wps_type: sys::wps_type_WPS_TYPE_PBC,
+        ..Default::default()
+    };
+    copy_str(&mut config.factory_info.manufacturer, "ESPRESSIF");
+    copy_str(&mut config.factory_info.model_number, "ESP32");
+    copy_str(&mut config.factory_info.model_name, "ESPRESSIF IOT");
+    copy_str(&mut config.factory_info.device_name, "ESP STATION");
+
+    let started = (|| {
+        // Fails if the station is not connected, which is fine
+        let _ = unsafe { sys::esp_wifi_disconnect() };
+        sys::esp!(unsafe { sys::esp_wifi_wps_enable(&config) })?;
+        sys::esp!(unsafe { sys::esp_wifi_wps_start(0) })
+    })();
+    if started.is_err() {
+        stop();
+    }
+    started
+}
+
+/// Ends WPS, after it finished or to cancel it.
+pub fn stop() {
+    unsafe {
+        sys::esp_wifi_wps_disable();
+        sys::esp_event_handler_unregister(sys::WIFI_EVENT, sys::ESP_EVENT_ANY_ID, Some(on_event));
+    }
+}
+
+/// The credentials received from the router, or why WPS failed
+pub fn take_outcome() -> Option<Result<ClientSettings, &'static str>> {
+    OUTCOME.lock().unwrap().take()
+}
+
+unsafe extern "C" fn on_event(
+    _: *mut c_void,
+    _: sys::esp_event_base_t,
+    event_id: i32,
+    event_data: *mut c_void,
+) {
+    let outcome = match event_id as sys::wifi_event_t {
+        sys::wifi_event_t_WIFI_EVENT_STA_WPS_ER_SUCCESS => {
+            let event = event_data as *const sys::wifi_event_sta_wps_er_success_t;
+            let credentials = if !event.is_null() && (*event).ap_cred_cnt > 0 {
+                // The router offered several networks, take the first one
+                let credential = &(*event).ap_cred[0];
+                client_settings(&credential.ssid, &credential.passphrase)
+            } else {
+                // Otherwise the driver already configured the station
+                let mut config = sys::wifi_config_t::default();
+                match sys::esp!(sys::esp_wifi_get_config(
+                    sys::wifi_interface_t_WIFI_IF_STA,
+                    &mut config
+                )) {
+                    Ok(()) => client_settings(&config.sta.ssid, &config.sta.password),
+                    Err(_) => Err("credentials could not be read"),
+                }
+            };
+            if let Ok(settings) = &credentials {
+                info!("received credentials for {} over WPS", settings.ssid);
+            }
+            credentials
+        }
+        sys::wifi_event_t_WIFI_EVENT_STA_WPS_ER_FAILED => Err("rejected by the router"),
+        sys::wifi_event_t_WIFI_EVENT_STA_WPS_ER_TIMEOUT => Err("no router in WPS mode found"),
+        sys::wifi_event_t_WIFI_EVENT_STA_WPS_ER_PBC_OVERLAP => {
+            Err("more than one router in WPS mode")
+        }
+        _ => return,
+    };
+    if let Err(reason) = outcome {
+        warn!("WPS failed: {reason}");
+    }
+    *OUTCOME.lock().unwrap() = Some(outcome);
+}
+
+fn client_settings(ssid: &[u8], password: &[u8]) -> Result<ClientSettings, &'static str> {
+    let until_nul = |bytes: &[u8]| {
+        let len = bytes
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(bytes.len());
+        core::str::from_utf8(&bytes[..len]).map_err(|_| "SSID or password is not UTF-8")
+    };
+    let mut settings = ClientSettings {
+        ssid: heapless::String::new(),
+        password: Some(heapless::String::new()),
+        password_set: false,
+        hidden: false,
+        auth_method: ClientAuth::Auto,
+        bssid: None,
+    };
+    settings
+        .ssid
+        .push_str(until_nul(ssid)?)
+        .map_err(|_| "SSID is too long")?;
+    if let Some(stored) = &mut settings.password {
+        stored
+            .push_str(until_nul(password)?)
+            .map_err(|_| "password is too long")?;
+    }
+    Ok(settings)
+}
+
+fn copy_str(target: &mut [core::ffi::c_char], value: &str) {
+    // Leave room for the terminating NUL
+    for (target, byte) in target[..target.len() - 1].iter_mut().zip(value.bytes()) {
+        *target = byte as _;
+    }
+}
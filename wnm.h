@@ -0,0 +1,2 @@
+// 802.11v BSS transition queries used by the roaming monitor
+#include "esp_wnm.h"